@@ -0,0 +1,61 @@
+# writes the known-answer vector of the adpcm tests: ima_adpcm_in.pcm
+# is the input, ima_adpcm.bin the encoded blocks and ima_adpcm_out.pcm
+# the decoded samples, all little endian. the nibbles come from the IMA reference encoder in
+# python's audioop (Jack Jansen's adpcm.c), the expected samples from
+# its decoder. audioop was removed in python 3.13, run it with 3.12 or
+# older:
+#
+#   python3.12 fixtures/ima_adpcm.py
+#
+# the nibbles are put into two 256 byte mono blocks the way wav files
+# lay them out: a header with the predictor and step index, then two
+# samples per byte, low nibble first.
+
+import audioop
+import math
+import os
+import struct
+
+BLOCK_BYTES = 256
+BLOCK_SAMPLES = (BLOCK_BYTES - 4) * 2
+
+here = os.path.dirname(os.path.abspath(__file__))
+
+# a sweep that gets loud enough to clip, with a jump in the middle
+samples = []
+for i in range(2 * BLOCK_SAMPLES):
+    phase = 2 * math.pi * (200 * i + 3 * i * i) / 8000
+    x = 40000 * math.sin(phase) * i / (2 * BLOCK_SAMPLES)
+    if 300 <= i < 340:
+        x = -30000
+    samples.append(max(-32768, min(32767, round(x))))
+
+data = b""
+pcm = []
+state = (0, 0)
+for block in range(2):
+    chunk = samples[block * BLOCK_SAMPLES:][:BLOCK_SAMPLES]
+    lin = struct.pack("<%dh" % len(chunk), *chunk)
+
+    # the header sample is played as it is, then the nibbles follow
+    valpred, index = state
+    header = struct.pack("<hBB", valpred, index, 0)
+    pcm.append(valpred)
+
+    nibbles, next_state = audioop.lin2adpcm(lin, 2, state)
+    decoded, _ = audioop.adpcm2lin(nibbles, 2, state)
+    pcm.extend(struct.unpack("<%dh" % len(chunk), decoded))
+    state = next_state
+
+    # audioop puts the first nibble in the high half of a byte
+    body = bytes((b >> 4) | (b & 0xF) << 4 for b in nibbles)
+    data += header + body
+
+def write(name, data):
+    with open(os.path.join(here, name), "wb") as f:
+        f.write(data)
+
+
+write("ima_adpcm_in.pcm", struct.pack("<%dh" % len(samples), *samples))
+write("ima_adpcm.bin", data)
+write("ima_adpcm_out.pcm", struct.pack("<%dh" % len(pcm), *pcm))
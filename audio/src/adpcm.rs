@@ -0,0 +1,517 @@
+// 4-bit IMA ADPCM, laid out in blocks like the IMA ADPCM of wav files:
+// each block starts with a header per channel holding the first sample
+// and the step index, followed by two samples per byte, low nibble
+// first. with two channels the data alternates between the channels
+// every 4 bytes.
+//
+// since every block carries its own decoder state, playback can jump
+// to any block without decoding the data before it.
+//
+// the encoder runs in build.rs, which compresses the wav assets.
+
+// the block size of the encoder
+pub const BLOCK_BYTES: usize = 256;
+const HEADER_BYTES: usize = 4;
+// the header sample plus two per data byte
+pub const BLOCK_SAMPLES: usize = block_samples(BLOCK_BYTES, 1);
+pub const MAX_CHANNELS: usize = 2;
+
+const INDEX_TABLE: [i8; 16] =
+  [-1, -1, -1, -1, 2, 4, 6, 8, -1, -1, -1, -1, 2, 4, 6, 8];
+
+#[rustfmt::skip]
+const STEP_TABLE: [i16; 89] = [
+  7, 8, 9, 10, 11, 12, 13, 14, 16, 17,
+  19, 21, 23, 25, 28, 31, 34, 37, 41, 45,
+  50, 55, 60, 66, 73, 80, 88, 97, 107, 118,
+  130, 143, 157, 173, 190, 209, 230, 253, 279, 307,
+  337, 371, 408, 449, 494, 544, 598, 658, 724, 796,
+  876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066,
+  2272, 2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358,
+  5894, 6484, 7132, 7845, 8630, 9493, 10442, 11487, 12635, 13899,
+  15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+];
+
+#[derive(Clone, Copy, Default)]
+pub struct State {
+  pub predictor: i16,
+  pub step_index: u8,
+}
+
+impl State {
+  pub fn decode(&mut self, nibble: u8) -> i16 {
+    let step = STEP_TABLE[self.step_index as usize] as i32;
+
+    let mut diff = step >> 3;
+    if nibble & 4 != 0 {
+      diff += step;
+    }
+    if nibble & 2 != 0 {
+      diff += step >> 1;
+    }
+    if nibble & 1 != 0 {
+      diff += step >> 2;
+    }
+    if nibble & 8 != 0 {
+      diff = -diff;
+    }
+
+    let predictor = (self.predictor as i32 + diff).clamp(-32768, 32767);
+    self.predictor = predictor as i16;
+    self.update_index(nibble);
+    self.predictor
+  }
+
+  pub fn encode(&mut self, sample: i16) -> u8 {
+    let step = STEP_TABLE[self.step_index as usize] as i32;
+    let mut diff = sample as i32 - self.predictor as i32;
+
+    let mut nibble = 0;
+    if diff < 0 {
+      nibble = 8;
+      diff = -diff;
+    }
+    if diff >= step {
+      nibble |= 4;
+      diff -= step;
+    }
+    if diff >= step >> 1 {
+      nibble |= 2;
+      diff -= step >> 1;
+    }
+    if diff >= step >> 2 {
+      nibble |= 1;
+    }
+
+    // run the decoder so both sides stay in sync
+    self.decode(nibble);
+    nibble
+  }
+
+  fn update_index(&mut self, nibble: u8) {
+    let index = self.step_index as i32 + INDEX_TABLE[nibble as usize] as i32;
+    self.step_index = index.clamp(0, STEP_TABLE.len() as i32 - 1) as u8;
+  }
+
+  fn read_header(header: &[u8]) -> Self {
+    Self {
+      predictor: i16::from_le_bytes([header[0], header[1]]),
+      step_index: header[2].min(STEP_TABLE.len() as u8 - 1),
+    }
+  }
+
+  fn write_header(&self, out: &mut [u8]) {
+    out[..2].copy_from_slice(&self.predictor.to_le_bytes());
+    out[2] = self.step_index;
+    out[3] = 0;
+  }
+}
+
+// the number of samples per channel in a block
+pub const fn block_samples(block_bytes: usize, channels: usize) -> usize {
+  1 + (block_bytes - HEADER_BYTES * channels) * 2 / channels
+}
+
+// the number of samples per channel in len bytes of data, the last
+// block may be short
+pub const fn sample_count(
+  len: usize,
+  block_bytes: usize,
+  channels: usize,
+) -> usize {
+  let full = len / block_bytes;
+  let rest = len % block_bytes;
+  let partial = if rest >= HEADER_BYTES * channels {
+    block_samples(rest, channels)
+  } else {
+    0
+  };
+  full * block_samples(block_bytes, channels) + partial
+}
+
+// the number of bytes needed to encode n samples
+pub const fn encoded_len(n: usize) -> usize {
+  let full = n / BLOCK_SAMPLES;
+  let rest = n % BLOCK_SAMPLES;
+  let partial = if rest == 0 {
+    0
+  } else {
+    HEADER_BYTES + rest / 2
+  };
+  full * BLOCK_BYTES + partial
+}
+
+// encode the samples into out, which must be encoded_len() long. the
+// state carries over between blocks, so the encoder keeps adapting.
+pub fn encode(samples: &[i16], out: &mut [u8]) {
+  let mut state = State::default();
+
+  for (block, out) in samples
+    .chunks(BLOCK_SAMPLES)
+    .zip(out.chunks_mut(BLOCK_BYTES))
+  {
+    state.predictor = block[0];
+    state.write_header(&mut out[..HEADER_BYTES]);
+
+    for (pair, byte) in block[1..].chunks(2).zip(out[HEADER_BYTES..].iter_mut())
+    {
+      // an odd sample at the end is padded with itself
+      let lo = state.encode(pair[0]);
+      let hi = state.encode(*pair.get(1).unwrap_or(&pair[0]));
+      *byte = lo | hi << 4;
+    }
+  }
+}
+
+// decodes samples by index, stereo is mixed down to mono. reading
+// forward is cheap, going back or skipping ahead restarts from the
+// header of the block.
+pub struct Reader<'a> {
+  data: &'a [u8],
+  block_bytes: usize,
+  channels: usize,
+  block_samples: usize,
+  len: usize,
+  // the block the state belongs to
+  block: usize,
+  // the index within the block of the last decoded sample
+  pos: usize,
+  state: [State; MAX_CHANNELS],
+}
+
+impl<'a> Reader<'a> {
+  // mono data in the block size of the encoder
+  pub const fn new(data: &'a [u8]) -> Self {
+    Self::with_layout(data, BLOCK_BYTES, 1)
+  }
+
+  // channels is 1 or 2, and each block must hold at least the headers
+  pub const fn with_layout(
+    data: &'a [u8],
+    block_bytes: usize,
+    channels: usize,
+  ) -> Self {
+    Self {
+      data,
+      block_bytes,
+      channels,
+      block_samples: block_samples(block_bytes, channels),
+      len: sample_count(data.len(), block_bytes, channels),
+      block: usize::MAX,
+      pos: 0,
+      state: [State {
+        predictor: 0,
+        step_index: 0,
+      }; MAX_CHANNELS],
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  pub fn sample_at(&mut self, idx: usize) -> i16 {
+    let block = idx / self.block_samples;
+    let offset = idx % self.block_samples;
+
+    if block != self.block || offset < self.pos {
+      self.seek_block(block);
+    }
+
+    while self.pos < offset {
+      self.pos += 1;
+      for ch in 0..self.channels {
+        let nibble = self.nibble(ch, self.pos);
+        self.state[ch].decode(nibble);
+      }
+    }
+
+    let states = &self.state[..self.channels];
+    let sum: i32 = states.iter().map(|s| s.predictor as i32).sum();
+    (sum / self.channels as i32) as i16
+  }
+
+  fn seek_block(&mut self, block: usize) {
+    let start = block * self.block_bytes;
+    for ch in 0..self.channels {
+      let header = start + ch * HEADER_BYTES;
+      self.state[ch] =
+        State::read_header(&self.data[header..header + HEADER_BYTES]);
+    }
+    self.block = block;
+    self.pos = 0;
+  }
+
+  // the nibble of the sample at offset within the current block
+  fn nibble(&self, ch: usize, offset: usize) -> u8 {
+    // the data comes in words of 8 samples, taking turns between the
+    // channels. for mono this is simply two samples per byte.
+    let n = offset - 1;
+    let word = (n / 8 * self.channels + ch) * 4;
+    let i = self.block * self.block_bytes
+      + HEADER_BYTES * self.channels
+      + word
+      + n % 8 / 2;
+    let byte = self.data.get(i).copied().unwrap_or(0);
+    if n.is_multiple_of(2) {
+      byte & 0xf
+    } else {
+      byte >> 4
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{vec, vec::Vec};
+
+  use super::*;
+  use crate::test_util::Random;
+
+  // the tables of the IMA reference implementation, adpcm.c, typed in
+  // from there rather than taken from the decoder so a wrong entry
+  // can't pass on both sides
+  const REF_INDEX_TABLE: [i32; 16] =
+    [-1, -1, -1, -1, 2, 4, 6, 8, -1, -1, -1, -1, 2, 4, 6, 8];
+
+  #[rustfmt::skip]
+  const REF_STEP_SIZE_TABLE: [i32; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17,
+    19, 21, 23, 25, 28, 31, 34, 37, 41, 45,
+    50, 55, 60, 66, 73, 80, 88, 97, 107, 118,
+    130, 143, 157, 173, 190, 209, 230, 253, 279, 307,
+    337, 371, 408, 449, 494, 544, 598, 658, 724, 796,
+    876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066,
+    2272, 2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358,
+    5894, 6484, 7132, 7845, 8630, 9493, 10442, 11487, 12635, 13899,
+    15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+  ];
+
+  // the decoder of the IMA reference implementation, written the way
+  // the recommendation describes it, and the block layout of
+  // Microsoft's IMA ADPCM wav files. decodes the whole file at once
+  // into samples per channel.
+  fn reference_decode(
+    data: &[u8],
+    block_bytes: usize,
+    channels: usize,
+  ) -> Vec<Vec<i16>> {
+    let mut out = vec![Vec::new(); channels];
+
+    for block in data.chunks(block_bytes) {
+      if block.len() < 4 * channels {
+        break;
+      }
+      let mut valpred = [0i32; 2];
+      let mut index = [0i32; 2];
+      for ch in 0..channels {
+        let header = &block[4 * ch..4 * ch + 4];
+        valpred[ch] = i16::from_le_bytes([header[0], header[1]]) as i32;
+        index[ch] = header[2] as i32;
+        out[ch].push(valpred[ch] as i16);
+      }
+
+      // 4 bytes of each channel in turn, 8 samples, low nibble first
+      let body = &block[4 * channels..];
+      for (i, word) in body.chunks(4).enumerate() {
+        let ch = i % channels;
+        for byte in word {
+          for delta in [byte & 0xf, byte >> 4] {
+            let step = REF_STEP_SIZE_TABLE[index[ch] as usize];
+            let mut vpdiff = step >> 3;
+            if delta & 4 != 0 {
+              vpdiff += step;
+            }
+            if delta & 2 != 0 {
+              vpdiff += step >> 1;
+            }
+            if delta & 1 != 0 {
+              vpdiff += step >> 2;
+            }
+            if delta & 8 != 0 {
+              valpred[ch] -= vpdiff;
+            } else {
+              valpred[ch] += vpdiff;
+            }
+            valpred[ch] = valpred[ch].clamp(-32768, 32767);
+
+            index[ch] += REF_INDEX_TABLE[delta as usize];
+            index[ch] = index[ch].clamp(0, 88);
+            out[ch].push(valpred[ch] as i16);
+          }
+        }
+      }
+    }
+
+    out
+  }
+
+  // random data with valid headers, a short block at the end
+  fn random_data(
+    blocks: usize,
+    block_bytes: usize,
+    channels: usize,
+  ) -> Vec<u8> {
+    let mut random = Random::new(0x2545_f491);
+    let len = blocks * block_bytes + 4 * channels + 4 * channels * 3;
+    let mut data: Vec<u8> = (0..len).map(|_| random.next() as u8).collect();
+    for block in data.chunks_mut(block_bytes) {
+      for header in block.chunks_mut(4).take(channels) {
+        header[2] = (random.next() % 89) as u8;
+        header[3] = 0;
+      }
+    }
+    data
+  }
+
+  fn check_against_reference(block_bytes: usize, channels: usize) {
+    let data = random_data(6, block_bytes, channels);
+    let expected = reference_decode(&data, block_bytes, channels);
+    let mixed: Vec<i16> = (0..expected[0].len())
+      .map(|i| {
+        let sum: i32 = expected.iter().map(|ch| ch[i] as i32).sum();
+        (sum / channels as i32) as i16
+      })
+      .collect();
+
+    let mut reader = Reader::with_layout(&data, block_bytes, channels);
+    assert_eq!(reader.len(), mixed.len());
+
+    // forward, as the player reads
+    for (i, expected) in mixed.iter().enumerate() {
+      assert_eq!(reader.sample_at(i), *expected, "sample {}", i);
+    }
+
+    // and jumping around, as after a seek or a rate change
+    let mut random = Random::new(7);
+    for _ in 0..2000 {
+      let i = random.next() as usize % mixed.len();
+      assert_eq!(reader.sample_at(i), mixed[i], "sample {}", i);
+    }
+  }
+
+  #[test]
+  fn mono_matches_the_reference_decoder() {
+    check_against_reference(BLOCK_BYTES, 1);
+    check_against_reference(512, 1);
+  }
+
+  #[test]
+  fn stereo_matches_the_reference_decoder() {
+    check_against_reference(1024, 2);
+    check_against_reference(BLOCK_BYTES, 2);
+  }
+
+  #[test]
+  fn the_tables_are_the_standard_ones() {
+    assert!(STEP_TABLE
+      .iter()
+      .zip(REF_STEP_SIZE_TABLE)
+      .all(|(&a, b)| a as i32 == b));
+    assert!(INDEX_TABLE
+      .iter()
+      .zip(REF_INDEX_TABLE)
+      .all(|(&a, b)| a as i32 == b));
+  }
+
+  // fixtures/ima_adpcm.py encodes a loud sweep into two blocks with
+  // the IMA reference encoder in python's audioop and decodes them
+  // with its decoder
+  const KNOWN_INPUT: &[u8] = include_bytes!("../fixtures/ima_adpcm_in.pcm");
+  const KNOWN_BLOCKS: &[u8] = include_bytes!("../fixtures/ima_adpcm.bin");
+  const KNOWN_OUTPUT: &[u8] = include_bytes!("../fixtures/ima_adpcm_out.pcm");
+
+  fn pcm(bytes: &[u8]) -> Vec<i16> {
+    bytes
+      .chunks_exact(2)
+      .map(|x| i16::from_le_bytes([x[0], x[1]]))
+      .collect()
+  }
+
+  #[test]
+  fn decodes_the_known_answer() {
+    let expected = pcm(KNOWN_OUTPUT);
+    assert_eq!(expected.len(), 2 * BLOCK_SAMPLES);
+    // it's loud enough to clip
+    assert!(expected.contains(&i16::MAX) && expected.contains(&i16::MIN));
+
+    let mut reader = Reader::new(KNOWN_BLOCKS);
+    assert_eq!(reader.len(), expected.len());
+    for (i, x) in expected.iter().enumerate() {
+      assert_eq!(reader.sample_at(i), *x, "sample {}", i);
+    }
+
+    assert_eq!(reference_decode(KNOWN_BLOCKS, BLOCK_BYTES, 1)[0], expected);
+  }
+
+  #[test]
+  fn encodes_the_known_answer() {
+    let input = pcm(KNOWN_INPUT);
+    let mut state = State::default();
+    for (block, known) in input
+      .chunks(BLOCK_SAMPLES - 1)
+      .zip(KNOWN_BLOCKS.chunks(BLOCK_BYTES))
+    {
+      let header = State::read_header(known);
+      assert_eq!(header.predictor, state.predictor);
+      assert_eq!(header.step_index, state.step_index);
+
+      for (pair, byte) in block.chunks(2).zip(&known[4..]) {
+        let low = state.encode(pair[0]);
+        let high = state.encode(pair[1]);
+        assert_eq!(low | high << 4, *byte);
+      }
+    }
+  }
+
+  #[test]
+  fn sizes_agree() {
+    assert_eq!(BLOCK_SAMPLES, 505);
+    assert_eq!(block_samples(1024, 2), 1017);
+    for n in [0, 1, 2, 504, 505, 506, 1010, 1011, 5000] {
+      let len = encoded_len(n);
+      let count = sample_count(len, BLOCK_BYTES, 1);
+      // a partial block may hold one padding sample
+      assert!(count == n || count == n + 1, "{} samples gave {}", n, count);
+    }
+  }
+
+  #[test]
+  fn encoder_round_trips() {
+    // a sweep from 100 Hz to 4 kHz at 16 kHz, with a few jumps
+    let n = 16000;
+    let samples: Vec<i16> = (0..n)
+      .map(|i| {
+        let t = i as f64 / 16000.0;
+        let phase = 2.0 * std::f64::consts::PI * (100.0 * t + 1950.0 * t * t);
+        let jump = if i % 4000 < 20 { 12000.0 } else { 0.0 };
+        (phase.sin() * 16000.0 + jump) as i16
+      })
+      .collect();
+
+    let mut data = vec![0; encoded_len(n)];
+    encode(&samples, &mut data);
+    let mut reader = Reader::new(&data);
+    assert!(reader.len() >= n);
+
+    let mut signal = 0.0;
+    let mut noise = 0.0;
+    for (i, x) in samples.iter().enumerate() {
+      let y = reader.sample_at(i) as f64;
+      signal += (*x as f64).powi(2);
+      noise += (*x as f64 - y).powi(2);
+    }
+    let snr = 10.0 * (signal / noise).log10();
+    assert!(snr > 20.0, "snr {:.1} dB", snr);
+
+    // and the decoder reads the encoder's blocks like the reference
+    let expected = reference_decode(&data, BLOCK_BYTES, 1);
+    for (i, x) in expected[0].iter().enumerate() {
+      assert_eq!(reader.sample_at(i), *x);
+    }
+  }
+}
@@ -0,0 +1,427 @@
+// microphone signal conditioning: bias removal, noise gate and
+// automatic gain control. everything is in fixed point so it can run
+// per sample without touching the fpu.
+//
+// the samples are i16. the input is the raw SAADC reading, the output
+// is centered around zero.
+
+use fixed::types::U8F8;
+
+// a peak follower. the level rises by 1/2^attack_shift and falls by
+// 1/2^release_shift of the difference each sample.
+struct Envelope {
+  // Q8
+  level: i32,
+  attack_shift: u8,
+  release_shift: u8,
+}
+
+impl Envelope {
+  const fn new(attack_shift: u8, release_shift: u8) -> Self {
+    Self {
+      level: 0,
+      attack_shift,
+      release_shift,
+    }
+  }
+
+  fn update(&mut self, sample: i16) -> u16 {
+    let x = (sample as i32).abs() << 8;
+    let shift = if x > self.level {
+      self.attack_shift
+    } else {
+      self.release_shift
+    };
+    self.level += (x - self.level) >> shift;
+    self.level()
+  }
+
+  fn level(&self) -> u16 {
+    (self.level >> 8).min(u16::MAX as i32) as u16
+  }
+}
+
+// removes the bias of the microphone signal by subtracting a slowly
+// moving average.
+pub struct DcBlocker {
+  // Q8, None until the first sample is seen
+  bias: Option<i32>,
+  shift: u8,
+}
+
+impl DcBlocker {
+  pub const fn new(shift: u8) -> Self {
+    Self { bias: None, shift }
+  }
+
+  pub fn process(&mut self, sample: i16) -> i16 {
+    let x = (sample as i32) << 8;
+    // start from the first reading, otherwise it takes seconds for
+    // the average to climb from zero to the bias
+    let bias = self.bias.get_or_insert(x);
+    *bias += (x - *bias) >> self.shift;
+    ((x - *bias) >> 8).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+  }
+}
+
+#[derive(Clone, Copy)]
+pub struct GateConfig {
+  // the gate opens when the input level rises above this
+  pub open_threshold: u16,
+  // and closes when it stays below this for hold_samples
+  pub close_threshold: u16,
+  pub hold_samples: u16,
+  // how fast the output fades in and out, in Q15 per sample
+  pub attack_step: u16,
+  pub release_step: u16,
+}
+
+impl Default for GateConfig {
+  fn default() -> Self {
+    Self {
+      open_threshold: 24,
+      close_threshold: 16,
+      // 50 ms at 8 kHz
+      hold_samples: 400,
+      // 1 ms fade in, 20 ms fade out at 8 kHz
+      attack_step: (1 << 15) / 8,
+      release_step: (1 << 15) / 160,
+    }
+  }
+}
+
+pub struct NoiseGate {
+  config: GateConfig,
+  envelope: Envelope,
+  open: bool,
+  // samples since the level fell below close_threshold
+  quiet: u16,
+  // Q15, 1 << 15 is fully open
+  gain: u16,
+}
+
+impl NoiseGate {
+  pub fn new(config: GateConfig) -> Self {
+    Self {
+      config,
+      envelope: Envelope::new(2, 8),
+      open: false,
+      quiet: 0,
+      gain: 0,
+    }
+  }
+
+  pub fn is_open(&self) -> bool {
+    self.open
+  }
+
+  // update the gate state with the input sample, returns the sample
+  // with the gate gain applied.
+  pub fn process(&mut self, sample: i16) -> i16 {
+    let level = self.envelope.update(sample);
+    let config = &self.config;
+
+    if level >= config.open_threshold {
+      self.open = true;
+      self.quiet = 0;
+    } else if level >= config.close_threshold {
+      self.quiet = 0;
+    } else if self.open {
+      self.quiet += 1;
+      if self.quiet >= config.hold_samples {
+        self.open = false;
+      }
+    }
+
+    self.gain = if self.open {
+      (self.gain as u32 + config.attack_step as u32).min(1 << 15) as u16
+    } else {
+      self.gain.saturating_sub(config.release_step)
+    };
+
+    ((sample as i32 * self.gain as i32) >> 15) as i16
+  }
+}
+
+#[derive(Clone, Copy)]
+pub struct AgcConfig {
+  // the output level the gain is adjusted towards
+  pub target_level: u16,
+  pub max_gain: U8F8,
+  // the envelope follows rising levels by 1/2^attack_shift and
+  // falling levels by 1/2^release_shift per sample. a fast attack
+  // keeps sudden loud sounds from clipping for long.
+  pub attack_shift: u8,
+  pub release_shift: u8,
+}
+
+impl Default for AgcConfig {
+  fn default() -> Self {
+    Self {
+      target_level: 4096,
+      max_gain: U8F8::const_from_int(64),
+      attack_shift: 4,
+      release_shift: 12,
+    }
+  }
+}
+
+pub struct Agc {
+  config: AgcConfig,
+  envelope: Envelope,
+  gain: U8F8,
+}
+
+impl Agc {
+  pub fn new(config: AgcConfig) -> Self {
+    Self {
+      config,
+      envelope: Envelope::new(config.attack_shift, config.release_shift),
+      gain: U8F8::ONE,
+    }
+  }
+
+  pub fn gain(&self) -> U8F8 {
+    self.gain
+  }
+
+  // track the input level without applying the gain
+  pub fn update(&mut self, sample: i16) {
+    let level = self.envelope.update(sample).max(1) as u32;
+    let gain = ((self.config.target_level as u32) << 8) / level;
+    let max_gain = self.config.max_gain.to_bits() as u32;
+    self.gain = U8F8::from_bits(gain.clamp(1, max_gain) as u16);
+  }
+
+  pub fn apply(&self, sample: i16) -> i16 {
+    let y = (sample as i32 * self.gain.to_bits() as i32) >> 8;
+    y.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+  }
+
+  pub fn process(&mut self, sample: i16) -> i16 {
+    self.update(sample);
+    self.apply(sample)
+  }
+}
+
+// the processing applied to microphone samples before the apps see
+// them. the bias is always removed, the gate and agc are optional.
+pub struct MicChain {
+  dc: DcBlocker,
+  gate: Option<NoiseGate>,
+  agc: Option<Agc>,
+}
+
+impl Default for MicChain {
+  fn default() -> Self {
+    Self::new(None, None)
+  }
+}
+
+impl MicChain {
+  pub fn new(gate: Option<GateConfig>, agc: Option<AgcConfig>) -> Self {
+    Self {
+      dc: DcBlocker::new(6),
+      gate: gate.map(NoiseGate::new),
+      agc: agc.map(Agc::new),
+    }
+  }
+
+  pub fn gate_open(&self) -> bool {
+    self.gate.as_ref().is_none_or(|g| g.is_open())
+  }
+
+  pub fn process(&mut self, samples: &mut [i16]) {
+    for sample in samples.iter_mut() {
+      let mut x = self.dc.process(*sample);
+
+      if let Some(gate) = self.gate.as_mut() {
+        x = gate.process(x);
+      }
+
+      if let Some(agc) = self.agc.as_mut() {
+        // hold the gain while the gate is closed so it doesn't creep
+        // up to max_gain during silence
+        if self.gate.as_ref().is_none_or(|g| g.is_open()) {
+          agc.update(x);
+        }
+        x = agc.apply(x);
+      }
+
+      *sample = x;
+    }
+  }
+}
+
+// mean absolute value of the samples
+pub fn level(samples: &[i16]) -> u16 {
+  if samples.is_empty() {
+    return 0;
+  }
+
+  let sum: u32 = samples.iter().map(|s| s.unsigned_abs() as u32).sum();
+  (sum / samples.len() as u32) as u16
+}
+
+#[cfg(test)]
+mod tests {
+  use std::vec::Vec;
+
+  use super::*;
+
+  // a 500 Hz square wave at 8 kHz, peak amplitude `amplitude`
+  fn square(amplitude: i16, len: usize) -> Vec<i16> {
+    (0..len)
+      .map(|i| {
+        if i / 8 % 2 == 0 {
+          amplitude
+        } else {
+          -amplitude
+        }
+      })
+      .collect()
+  }
+
+  #[test]
+  fn dc_blocker_removes_the_bias() {
+    let mut dc = DcBlocker::new(6);
+    // the saadc reading of a quiet microphone sits around 1/2 of the
+    // range
+    let out: Vec<i16> = square(300, 4000)
+      .iter()
+      .map(|x| dc.process(x + 2000))
+      .collect();
+    let tail = &out[2000..];
+    let mean = tail.iter().map(|x| *x as i32).sum::<i32>() / tail.len() as i32;
+    assert!(mean.abs() < 10, "mean {}", mean);
+    assert!((level(tail) as i32 - 300).abs() < 30);
+  }
+
+  #[test]
+  fn gate_stays_closed_on_noise() {
+    let mut gate = NoiseGate::new(GateConfig::default());
+    for x in square(12, 4000) {
+      assert_eq!(gate.process(x), 0);
+    }
+    assert!(!gate.is_open());
+  }
+
+  #[test]
+  fn gate_opens_fades_and_holds() {
+    let config = GateConfig::default();
+    let mut gate = NoiseGate::new(config);
+
+    let loud = square(1000, 64);
+    let out: Vec<i16> = loud.iter().map(|x| gate.process(*x)).collect();
+    assert!(gate.is_open());
+    // fades in over 8 samples from where it opened
+    let opened = out.iter().position(|x| *x != 0).unwrap();
+    assert!(opened < 4);
+    assert!(out[opened].abs() < 1000);
+    assert_eq!(out[opened + 8], loud[opened + 8]);
+
+    // stays open for the hold time once the level has fallen
+    let mut quiet_from = None;
+    let mut closed_at = None;
+    for i in 0..4000 {
+      gate.process(0);
+      if quiet_from.is_none() && gate.envelope.level() < config.close_threshold
+      {
+        quiet_from = Some(i);
+      }
+      if !gate.is_open() {
+        closed_at = Some(i);
+        break;
+      }
+    }
+    let held = closed_at.unwrap() - quiet_from.unwrap();
+    assert_eq!(held, config.hold_samples as usize - 1);
+
+    // and fades out over 160 samples, the first one as it closed
+    let faded = (0..200)
+      .take_while(|_| {
+        gate.process(0);
+        gate.gain > 0
+      })
+      .count();
+    assert_eq!(faded, 159);
+  }
+
+  #[test]
+  fn agc_brings_the_level_to_the_target() {
+    let config = AgcConfig::default();
+    for amplitude in [200, 1000, 12000] {
+      let mut agc = Agc::new(config);
+      let out: Vec<i16> = square(amplitude, 32000)
+        .iter()
+        .map(|x| agc.process(*x))
+        .collect();
+      let level = level(&out[24000..]) as i32;
+      let target = config.target_level as i32;
+      assert!(
+        (level - target).abs() < target / 8,
+        "{} came out at {}",
+        amplitude,
+        level
+      );
+    }
+  }
+
+  #[test]
+  fn agc_gain_is_capped() {
+    let config = AgcConfig::default();
+    let mut agc = Agc::new(config);
+    for x in square(10, 32000) {
+      agc.process(x);
+    }
+    assert_eq!(agc.gain(), config.max_gain);
+    assert_eq!(agc.apply(10), 640);
+  }
+
+  #[test]
+  fn agc_attacks_fast_and_releases_slowly() {
+    let mut agc = Agc::new(AgcConfig::default());
+    for x in square(500, 32000) {
+      agc.process(x);
+    }
+    let quiet_gain = agc.gain();
+
+    // a loud sound clips for a few samples at most
+    let out: Vec<i16> =
+      square(16000, 200).iter().map(|x| agc.process(*x)).collect();
+    let clipped = out.iter().filter(|x| x.unsigned_abs() >= 32767).count();
+    assert!(clipped < 48, "{} samples clipped", clipped);
+
+    // the gain takes longer to come back up
+    for x in square(500, 200) {
+      agc.process(x);
+    }
+    assert!(agc.gain() < quiet_gain / 2);
+  }
+
+  #[test]
+  fn chain_holds_the_gain_while_the_gate_is_closed() {
+    let mut chain =
+      MicChain::new(Some(GateConfig::default()), Some(AgcConfig::default()));
+    let mut loud: Vec<i16> =
+      square(1000, 8000).iter().map(|x| x + 2000).collect();
+    chain.process(&mut loud);
+    let gain = chain.agc.as_ref().unwrap().gain();
+
+    // the gain moves while the gate fades out, then stays
+    let mut quiet = [2000; 16000];
+    chain.process(&mut quiet);
+    assert!(!chain.gate_open());
+    let held = chain.agc.as_ref().unwrap().gain();
+    assert!(held > gain);
+    let mut quiet = [2000; 16000];
+    chain.process(&mut quiet);
+    assert_eq!(chain.agc.as_ref().unwrap().gain(), held);
+  }
+
+  #[test]
+  fn level_is_the_mean_absolute_value() {
+    assert_eq!(level(&[]), 0);
+    assert_eq!(level(&[100, -300, 200, -200]), 200);
+  }
+}
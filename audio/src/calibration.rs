@@ -0,0 +1,346 @@
+// analysis for the speaker calibration. the speaker is swept with
+// stepped tones and the microphone picks up the response. from the
+// response we find the resonance peak and derive a compensation
+// profile, which the players use to even out the loudness across
+// frequencies.
+
+use fixed::types::U8F8;
+use heapless::Vec;
+
+use super::{graph::AudioSource, osc::Osc};
+
+pub const MAX_POINTS: usize = 32;
+
+// 2^(1/6) in Q16, the sweep steps in sixth of an octave
+const SWEEP_RATIO: u64 = 73562;
+
+// the compensation never cuts below 1/8 or boosts above 2x
+const MIN_GAIN: u16 = 1 << 5;
+const MAX_GAIN: u16 = 2 << 8;
+
+// marks a profile in flash, "SPKR"
+const PROFILE_MAGIC: u32 = 0x5350_4b52;
+
+// the words needed to store a profile: magic, length, points, checksum
+pub const PROFILE_WORDS: usize = MAX_POINTS + 3;
+
+// Q15, the sweep is played at half of the full swing
+const TONE_AMPLITUDE: i32 = 1 << 14;
+
+// log spaced frequencies from f_min up to f_max
+pub fn sweep(f_min: u32, f_max: u32) -> impl Iterator<Item = u32> {
+  let mut f = (f_min as u64) << 16;
+  core::iter::from_fn(move || {
+    let freq = (f >> 16) as u32;
+    if freq > f_max {
+      return None;
+    }
+    f = (f * SWEEP_RATIO) >> 16;
+    Some(freq)
+  })
+  .take(MAX_POINTS)
+}
+
+// the tone the speaker is swept with, one frequency at a time
+pub struct SweepTone {
+  osc: Osc,
+  playing: bool,
+}
+
+impl SweepTone {
+  pub const fn new(sample_rate: u32) -> Self {
+    Self {
+      osc: Osc::new(sample_rate),
+      playing: false,
+    }
+  }
+
+  // None stops the tone
+  pub fn set_freq(&mut self, freq: Option<u32>) {
+    match freq {
+      Some(freq) => {
+        self.osc.set_freq(freq);
+        self.playing = true;
+      }
+      None => self.playing = false,
+    }
+  }
+}
+
+impl AudioSource for SweepTone {
+  fn fill(&mut self, samples: &mut [i16]) {
+    for sample in samples.iter_mut() {
+      let x = self.osc.next_sample() as i32;
+      *sample = ((x * TONE_AMPLITUDE) >> 15) as i16;
+    }
+  }
+
+  fn is_silent(&self) -> bool {
+    !self.playing
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Point {
+  pub freq: u16,
+  // the measured amplitude, in microphone sample units
+  pub magnitude: u32,
+}
+
+#[derive(Default)]
+pub struct Response {
+  points: Vec<Point, MAX_POINTS>,
+}
+
+impl Response {
+  pub fn push(&mut self, freq: u32, magnitude: u32) {
+    let point = Point {
+      freq: freq as u16,
+      magnitude,
+    };
+    self.points.push(point).ok();
+  }
+
+  pub fn points(&self) -> &[Point] {
+    &self.points
+  }
+
+  // the resonance peak
+  pub fn peak(&self) -> Option<Point> {
+    self.points.iter().copied().max_by_key(|p| p.magnitude)
+  }
+
+  // the median magnitude, used as the level of a flat response
+  pub fn median(&self) -> u32 {
+    let mut magnitudes: Vec<u32, MAX_POINTS> =
+      self.points.iter().map(|p| p.magnitude).collect();
+    magnitudes.sort_unstable();
+    magnitudes.get(magnitudes.len() / 2).copied().unwrap_or(0)
+  }
+}
+
+#[derive(Default)]
+pub struct Profile {
+  // (freq, gain) sorted by freq
+  points: Vec<(u16, U8F8), MAX_POINTS>,
+}
+
+impl Profile {
+  // the gain for each point brings its magnitude to the median
+  pub fn from_response(response: &Response) -> Self {
+    let median = response.median() as u64;
+    let points = response
+      .points()
+      .iter()
+      .map(|p| {
+        let gain = (median << 8) / p.magnitude.max(1) as u64;
+        let gain = gain.clamp(MIN_GAIN as u64, MAX_GAIN as u64) as u16;
+        (p.freq, U8F8::from_bits(gain))
+      })
+      .collect();
+
+    Self { points }
+  }
+
+  pub fn points(&self) -> &[(u16, U8F8)] {
+    &self.points
+  }
+
+  // the gain at freq, interpolated between the measured points
+  pub fn gain_at(&self, freq: u32) -> U8F8 {
+    let (Some(first), Some(last)) = (self.points.first(), self.points.last())
+    else {
+      return U8F8::ONE;
+    };
+
+    if freq <= first.0 as u32 {
+      return first.1;
+    }
+
+    for w in self.points.windows(2) {
+      let ((f0, g0), (f1, g1)) = (w[0], w[1]);
+      let (f0, f1) = (f0 as i32, f1 as i32);
+      if (freq as i32) <= f1 {
+        let (g0, g1) = (g0.to_bits() as i32, g1.to_bits() as i32);
+        let g = g0 + (g1 - g0) * (freq as i32 - f0) / (f1 - f0).max(1);
+        return U8F8::from_bits(g as u16);
+      }
+    }
+
+    last.1
+  }
+
+  pub fn to_words(&self) -> Vec<u32, PROFILE_WORDS> {
+    let mut words = Vec::new();
+    words.push(PROFILE_MAGIC).ok();
+    words.push(self.points.len() as u32).ok();
+    for &(freq, gain) in self.points.iter() {
+      words.push((freq as u32) << 16 | gain.to_bits() as u32).ok();
+    }
+    let checksum = checksum(&words);
+    words.push(checksum).ok();
+    words
+  }
+
+  // None if the words don't contain a valid profile, e.g. erased flash
+  pub fn from_words(words: &[u32]) -> Option<Self> {
+    if words.len() < 3 || words[0] != PROFILE_MAGIC {
+      return None;
+    }
+
+    let len = words[1] as usize;
+    if len > MAX_POINTS || words.len() < len + 3 {
+      return None;
+    }
+
+    if checksum(&words[..len + 2]) != words[len + 2] {
+      return None;
+    }
+
+    let points = words[2..len + 2]
+      .iter()
+      .map(|w| ((w >> 16) as u16, U8F8::from_bits(*w as u16)))
+      .collect();
+
+    Some(Self { points })
+  }
+}
+
+fn checksum(words: &[u32]) -> u32 {
+  words.iter().fold(0u32, |acc, w| acc.rotate_left(5) ^ w)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // the sweep as app_speaker_calibration prints it over serial. the
+  // magnitudes are modelled on the speaker's 2.7 kHz resonance with a
+  // room mode around 1 kHz and some noise, so the fixture can be
+  // swapped for a capture from the board without touching the tests.
+  const RESPONSE: &str = include_str!("../fixtures/speaker_response.csv");
+
+  fn response() -> Response {
+    let mut lines = RESPONSE.lines();
+    assert_eq!(lines.next(), Some("freq (Hz), magnitude"));
+
+    let mut response = Response::default();
+    for line in lines {
+      let (freq, magnitude) = line.split_once(", ").unwrap();
+      response.push(freq.parse().unwrap(), magnitude.parse().unwrap());
+    }
+    response
+  }
+
+  #[test]
+  fn sweeps_in_sixth_octaves() {
+    let freqs: Vec<u32, MAX_POINTS> = sweep(250, 6000).collect();
+    assert_eq!(freqs.len(), 28);
+    assert_eq!(freqs[..4], [250, 280, 314, 353]);
+    // an octave is six steps
+    assert_eq!(freqs[6], 500);
+    assert_eq!(freqs[12], 1000);
+    assert_eq!(*freqs.last().unwrap(), 5657);
+
+    // never more points than a response holds
+    assert_eq!(sweep(20, 20000).count(), MAX_POINTS);
+    assert_eq!(sweep(1000, 999).count(), 0);
+  }
+
+  #[test]
+  fn sweep_tone_plays_at_half_swing() {
+    let mut tone = SweepTone::new(8000);
+    assert!(tone.is_silent());
+
+    tone.set_freq(Some(1000));
+    assert!(!tone.is_silent());
+    let mut samples = [0; 64];
+    tone.fill(&mut samples);
+    let peak = samples.iter().map(|x| x.abs()).max().unwrap();
+    assert!((16000..=16384).contains(&peak), "{peak}");
+    // 8 samples per period
+    assert_eq!(samples[8], samples[0]);
+
+    tone.set_freq(None);
+    assert!(tone.is_silent());
+  }
+
+  #[test]
+  fn finds_the_resonance_and_the_median() {
+    let response = response();
+    assert_eq!(response.points().len(), 28);
+    assert_eq!(response.points()[0].freq, 250);
+
+    // the room mode at 1 kHz is a local peak, but not the highest
+    let peak = response.peak().unwrap();
+    assert_eq!((peak.freq, peak.magnitude), (2828, 178));
+    // the 15th of the 28 magnitudes in order
+    assert_eq!(response.median(), 66);
+
+    assert!(Response::default().peak().is_none());
+    assert_eq!(Response::default().median(), 0);
+  }
+
+  #[test]
+  fn profile_evens_out_the_response() {
+    let profile = Profile::from_response(&response());
+    let gain = |freq| profile.gain_at(freq).to_bits();
+
+    // 66 * 256 / magnitude, within 1/8 and 2
+    assert_eq!(profile.points().len(), 28);
+    assert_eq!(gain(2828), 94);
+    assert_eq!(gain(2520), 98);
+    assert_eq!(gain(793), 256);
+    assert_eq!(gain(250), 512);
+    assert_eq!(gain(5657), 512);
+
+    // interpolated halfway between 2520 and 2828, and held past the ends
+    assert_eq!(gain(2674), 96);
+    assert_eq!(gain(100), 512);
+    assert_eq!(gain(8000), 512);
+
+    // no measurement, no compensation
+    assert_eq!(Profile::default().gain_at(1000), U8F8::ONE);
+  }
+
+  #[test]
+  fn profile_round_trips_through_flash() {
+    let profile = Profile::from_response(&response());
+    let words = profile.to_words();
+    assert_eq!(words.len(), 28 + 3);
+
+    // a page holds more words than the profile, the rest is erased
+    let mut page = [u32::MAX; 64];
+    page[..words.len()].copy_from_slice(&words);
+    let read = Profile::from_words(&page).unwrap();
+    assert_eq!(read.points(), profile.points());
+  }
+
+  #[test]
+  fn corrupted_pages_are_rejected() {
+    let words = Profile::from_response(&response()).to_words();
+    assert!(Profile::from_words(&words).is_some());
+
+    // erased flash, and nothing at all
+    assert!(Profile::from_words(&[u32::MAX; 64]).is_none());
+    assert!(Profile::from_words(&[]).is_none());
+
+    // any flipped bit breaks the magic, the length or the checksum
+    for i in 0..words.len() {
+      for bit in [0, 7, 16, 31] {
+        let mut broken = words.clone();
+        broken[i] ^= 1 << bit;
+        let read = Profile::from_words(&broken);
+        assert!(read.is_none(), "word {i} bit {bit}");
+      }
+    }
+
+    // a page cut short of the checksum
+    assert!(Profile::from_words(&words[..words.len() - 1]).is_none());
+
+    // a length that doesn't fit in a profile
+    let mut long = words.clone();
+    long[1] = MAX_POINTS as u32 + 1;
+    assert!(Profile::from_words(&long).is_none());
+  }
+}
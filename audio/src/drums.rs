@@ -0,0 +1,322 @@
+// the drums of general midi channel 10. a hit is a sine that falls in
+// pitch, some filtered noise from an lfsr, or both, under an envelope
+// that dies out by itself. the key of a note picks the drum.
+
+use fixed::types::U8F8;
+use heapless::Vec;
+
+use super::{
+  dsp,
+  envelope::{Adsr, Envelope},
+  eq::{Biquad, Coeffs, Filter, BUTTERWORTH},
+  graph::AudioSource,
+  osc,
+};
+
+// channel 10, counted from zero
+pub const DRUM_CHANNEL: u8 = 9;
+
+// hits ringing at once, a new one cuts off the oldest
+const MAX_HITS: usize = 6;
+
+// how long the pitch of the body takes to fall, in ms
+const SWEEP_MS: u32 = 20;
+
+// a 16 bit galois lfsr, one bit of white noise per sample
+pub struct Noise {
+  state: u16,
+}
+
+impl Noise {
+  pub const fn new() -> Self {
+    Self { state: 0xace1 }
+  }
+
+  // full scale either way
+  pub fn sample(&mut self) -> i16 {
+    let bit = self.state & 1;
+    self.state >>= 1;
+    if bit != 0 {
+      self.state ^= 0xb400;
+      i16::MAX
+    } else {
+      -i16::MAX
+    }
+  }
+}
+
+impl Default for Noise {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Sound {
+  // the pitch of the body falls from the first to the second, in Hz.
+  // None for noise only.
+  body: Option<(u32, u32)>,
+  // the share of the noise in the hit, the body gets the rest
+  noise: U8F8,
+  // what the noise goes through
+  filter: Option<Filter>,
+  // until the hit dies out, in ms
+  decay: u16,
+}
+
+const fn high_pass(freq: u32) -> Option<Filter> {
+  Some(Filter::HighPass {
+    freq,
+    q: BUTTERWORTH,
+  })
+}
+
+const fn share(x: u8) -> U8F8 {
+  U8F8::from_bits(x as u16)
+}
+
+const KICK: Sound = Sound {
+  body: Some((160, 45)),
+  noise: share(0),
+  filter: None,
+  decay: 300,
+};
+
+const SNARE: Sound = Sound {
+  body: Some((220, 180)),
+  noise: share(180),
+  filter: high_pass(1500),
+  decay: 180,
+};
+
+const SIDE_STICK: Sound = Sound {
+  body: Some((900, 800)),
+  noise: share(128),
+  filter: high_pass(2000),
+  decay: 40,
+};
+
+const CLAP: Sound = Sound {
+  body: None,
+  noise: share(255),
+  filter: high_pass(1000),
+  decay: 120,
+};
+
+const CLOSED_HAT: Sound = Sound {
+  body: None,
+  noise: share(255),
+  filter: high_pass(6000),
+  decay: 45,
+};
+
+const OPEN_HAT: Sound = Sound {
+  decay: 300,
+  ..CLOSED_HAT
+};
+
+const TAMBOURINE: Sound = Sound {
+  decay: 150,
+  ..CLOSED_HAT
+};
+
+const CRASH: Sound = Sound {
+  body: None,
+  noise: share(255),
+  filter: high_pass(4000),
+  decay: 1200,
+};
+
+const RIDE: Sound = Sound {
+  filter: high_pass(5000),
+  decay: 600,
+  ..CRASH
+};
+
+const COWBELL: Sound = Sound {
+  body: Some((800, 800)),
+  noise: share(0),
+  filter: None,
+  decay: 150,
+};
+
+// toms fall a fifth below where they start
+const fn tom(freq: u32) -> Sound {
+  Sound {
+    body: Some((freq, freq * 2 / 3)),
+    noise: share(24),
+    filter: None,
+    decay: 300,
+  }
+}
+
+// the general midi percussion keys the kit can play
+fn sound(key: u8) -> Option<Sound> {
+  let sound = match key {
+    35 | 36 => KICK,
+    37 => SIDE_STICK,
+    38 | 40 => SNARE,
+    39 => CLAP,
+    42 | 44 => CLOSED_HAT,
+    46 => OPEN_HAT,
+    41 => tom(90),
+    43 => tom(110),
+    45 => tom(135),
+    47 => tom(160),
+    48 => tom(190),
+    50 => tom(225),
+    49 | 52 | 55 | 57 => CRASH,
+    51 | 53 | 59 => RIDE,
+    54 => TAMBOURINE,
+    56 => COWBELL,
+    _ => return None,
+  };
+  Some(sound)
+}
+
+// a closed or pedal hi-hat stops the open one
+fn chokes(key: u8, other: u8) -> bool {
+  matches!(key, 42 | 44) && other == 46
+}
+
+struct Hit {
+  key: u8,
+  sound: Sound,
+  phase: u32,
+  // the phase step of the body at the end of the sweep, and how much
+  // higher it starts
+  step: u32,
+  span: u32,
+  // what's left of the sweep, Q16
+  sweep: u32,
+  filter: Biquad,
+  envelope: Envelope,
+  velocity: U8F8,
+}
+
+impl Hit {
+  fn sample(&mut self, noise: i16, sweep_decay: u32) -> i16 {
+    let mut x = 0;
+
+    if self.sound.body.is_some() {
+      let body = osc::sine(self.phase) as i32;
+      let sweep = (self.span as u64 * self.sweep as u64) >> 16;
+      self.phase = self.phase.wrapping_add(self.step + sweep as u32);
+      self.sweep = (self.sweep * sweep_decay) >> 16;
+      x += (body * (256 - self.sound.noise.to_bits() as i32)) >> 8;
+    }
+
+    if self.sound.noise != U8F8::ZERO {
+      let noise = self.filter.process(noise) as i32;
+      x += (noise * self.sound.noise.to_bits() as i32) >> 8;
+    }
+
+    let x = x.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+    self.envelope.apply(dsp::apply_gain(x, self.velocity))
+  }
+}
+
+pub struct Drums {
+  sample_rate: u32,
+  noise: Noise,
+  hits: Vec<Hit, MAX_HITS>,
+  // the sweep is multiplied by this every sample, Q16
+  sweep_decay: u32,
+}
+
+impl Drums {
+  pub fn new(sample_rate: u32) -> Self {
+    let sweep_samples = (SWEEP_MS * sample_rate / 1000).max(1);
+    Self {
+      sample_rate,
+      noise: Noise::new(),
+      hits: Vec::new(),
+      sweep_decay: 65536 - 65536 / sweep_samples,
+    }
+  }
+
+  // returns whether the kit has a drum for the key. velocity is the
+  // gain after the velocity curve.
+  pub fn hit(&mut self, key: u8, velocity: U8F8) -> bool {
+    let Some(sound) = sound(key) else {
+      return false;
+    };
+
+    for hit in self.hits.iter_mut() {
+      if chokes(key, hit.key) {
+        hit.envelope.release();
+      }
+    }
+    self.hits.retain(|hit| hit.key != key);
+    if self.hits.is_full() {
+      self.hits.remove(0);
+    }
+
+    let (step, span) = match sound.body {
+      Some((from, to)) => {
+        let to = osc::phase_step(to, self.sample_rate);
+        let from = osc::phase_step(from, self.sample_rate);
+        (to, from.saturating_sub(to))
+      }
+      None => (0, 0),
+    };
+    let coeffs = match sound.filter {
+      Some(filter) => Coeffs::design(filter, self.sample_rate),
+      None => Coeffs::IDENTITY,
+    };
+    let adsr = Adsr {
+      attack: 0,
+      decay: sound.decay,
+      sustain: U8F8::ZERO,
+      // for the choke
+      release: 30,
+    };
+
+    let mut hit = Hit {
+      key,
+      sound,
+      phase: 0,
+      step,
+      span,
+      sweep: 1 << 16,
+      filter: Biquad::new(coeffs),
+      envelope: Envelope::new(adsr, self.sample_rate),
+      velocity,
+    };
+    hit.envelope.trigger();
+    self.hits.push(hit).ok();
+    true
+  }
+
+  pub fn clear(&mut self) {
+    self.hits.clear();
+  }
+
+  pub fn sample(&mut self) -> i16 {
+    let noise = self.noise.sample();
+    let sum: i32 = self
+      .hits
+      .iter_mut()
+      .map(|hit| hit.sample(noise, self.sweep_decay) as i32)
+      .sum();
+    sum.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+  }
+
+  // the hits that have died out make room
+  pub fn collect(&mut self) {
+    self.hits.retain(|hit| !hit.envelope.is_idle());
+  }
+}
+
+impl AudioSource for Drums {
+  fn fill(&mut self, samples: &mut [i16]) {
+    for sample in samples.iter_mut() {
+      *sample = self.sample();
+    }
+    self.collect();
+  }
+
+  fn is_silent(&self) -> bool {
+    self.hits.is_empty()
+  }
+}
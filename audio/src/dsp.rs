@@ -0,0 +1,101 @@
+// the fixed-point building blocks of the synthesizers, so the pwm
+// interrupt doesn't need any f32 math. samples are Q15 i16 as
+// everywhere else, and turned into duty values by the noise shaper.
+
+use fixed::types::{U16F16, U8F8};
+
+// the top octave of midi keys 120 to 131, the lower octaves are
+// halved from there
+#[rustfmt::skip]
+const TOP_OCTAVE: [U16F16; 12] = [
+  U16F16::from_bits(548_668_578), U16F16::from_bits(581_294_109),
+  U16F16::from_bits(615_859_655), U16F16::from_bits(652_480_576),
+  U16F16::from_bits(691_279_090), U16F16::from_bits(732_384_684),
+  U16F16::from_bits(775_934_544), U16F16::from_bits(822_074_013),
+  U16F16::from_bits(870_957_077), U16F16::from_bits(922_746_880),
+  U16F16::from_bits(977_616_265), U16F16::from_bits(1_035_748_353),
+];
+
+// equal temperament with A4 (key 69) at 440 Hz
+pub fn key_to_freq(key: u8) -> U16F16 {
+  let key = key.min(127);
+  let shift = 10 - key / 12;
+  let bits = TOP_OCTAVE[(key % 12) as usize].to_bits();
+  // rounded to the nearest
+  U16F16::from_bits((bits + (1 << shift >> 1)) >> shift)
+}
+
+// 2^(k/12) for the semitones of an octave, Q16
+#[rustfmt::skip]
+const SEMITONES: [u32; 13] = [
+  65536, 69433, 73562, 77936, 82570, 87480, 92682,
+  98193, 104032, 110218, 116772, 123715, 131072,
+];
+
+// the frequency ratio of an interval in cents, 1200 to an octave up.
+// linear between the semitones, which is off by less than a cent.
+pub fn cents_to_ratio(cents: i32) -> U16F16 {
+  let octaves = cents.div_euclid(1200).clamp(-16, 15);
+  let cents = cents.rem_euclid(1200) as u32;
+  let (semitone, frac) = ((cents / 100) as usize, cents % 100);
+  let (a, b) = (SEMITONES[semitone], SEMITONES[semitone + 1]);
+  let ratio = a + (b - a) * frac / 100;
+  let bits = if octaves >= 0 {
+    (ratio as u64) << octaves
+  } else {
+    (ratio >> -octaves) as u64
+  };
+  U16F16::from_bits(bits.min(u32::MAX as u64) as u32)
+}
+
+// scale a Q15 sample, saturating when it goes out of range
+pub fn apply_gain(sample: i16, gain: U8F8) -> i16 {
+  let y = (sample as i32 * gain.to_bits() as i32) >> 8;
+  y.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn cents(ratio: f64) -> f64 {
+    1200.0 * ratio.log2()
+  }
+
+  // the figure in the readme
+  #[test]
+  fn keys_are_in_equal_temperament() {
+    assert_eq!(key_to_freq(69), U16F16::from_num(440));
+    let mut worst: f64 = 0.0;
+    for key in 0..=127 {
+      let exact = 440.0 * 2f64.powf((key as f64 - 69.0) / 12.0);
+      let freq = key_to_freq(key).to_num::<f64>();
+      worst = worst.max((freq / exact - 1.0).abs());
+    }
+    // 0.0001%
+    assert!(worst < 1e-6, "{:e}", worst);
+  }
+
+  #[test]
+  fn ratios_are_within_a_cent() {
+    assert_eq!(cents_to_ratio(0), U16F16::ONE);
+    assert_eq!(cents_to_ratio(1200), U16F16::from_num(2));
+    assert_eq!(cents_to_ratio(-1200), U16F16::from_num(0.5));
+    let mut worst: f64 = 0.0;
+    for c in -4800..=4800 {
+      let ratio = cents_to_ratio(c).to_num::<f64>();
+      worst = worst.max((cents(ratio) - c as f64).abs());
+    }
+    assert!(worst < 1.0, "{} cents", worst);
+  }
+
+  #[test]
+  fn gain_saturates() {
+    assert_eq!(apply_gain(1000, U8F8::ONE), 1000);
+    assert_eq!(apply_gain(1000, U8F8::from_num(0.5)), 500);
+    assert_eq!(apply_gain(-1000, U8F8::from_num(2)), -2000);
+    assert_eq!(apply_gain(20000, U8F8::from_num(2)), i16::MAX);
+    assert_eq!(apply_gain(-20000, U8F8::from_num(2)), i16::MIN);
+    assert_eq!(apply_gain(i16::MIN, U8F8::ZERO), 0);
+  }
+}
@@ -0,0 +1,362 @@
+// dual-tone multi-frequency signaling, the beeps of a phone keypad.
+// each key is the sum of a row tone and a column tone.
+
+use heapless::Vec;
+
+use super::{goertzel::Goertzel, osc::Osc};
+
+pub const ROW_FREQS: [u32; 4] = [697, 770, 852, 941];
+pub const COL_FREQS: [u32; 4] = [1209, 1336, 1477, 1633];
+
+const KEYS: [[u8; 4]; 4] = [
+  [b'1', b'2', b'3', b'A'],
+  [b'4', b'5', b'6', b'B'],
+  [b'7', b'8', b'9', b'C'],
+  [b'*', b'0', b'#', b'D'],
+];
+
+// the number of frequencies a ToneDetector can watch
+pub const MAX_TONES: usize = 8;
+
+// how long a key is held and the pause after it when sending a code
+const TONE_MS: u32 = 100;
+const GAP_MS: u32 = 100;
+
+pub const MAX_CODE_LEN: usize = 32;
+
+// (row, col) of the key
+fn key_position(key: u8) -> Option<(usize, usize)> {
+  let key = key.to_ascii_uppercase();
+  (0..4)
+    .flat_map(|r| (0..4).map(move |c| (r, c)))
+    .find(|&(r, c)| KEYS[r][c] == key)
+}
+
+// generates the tone of a single key
+pub struct Generator {
+  row: Osc,
+  col: Osc,
+  key: Option<u8>,
+}
+
+impl Generator {
+  pub const fn new(sample_rate: u32) -> Self {
+    Self {
+      row: Osc::new(sample_rate),
+      col: Osc::new(sample_rate),
+      key: None,
+    }
+  }
+
+  // None or an unknown key is silence
+  pub fn set_key(&mut self, key: Option<u8>) {
+    self.key = None;
+    if let Some((r, c)) = key.and_then(key_position) {
+      self.row.set_freq(ROW_FREQS[r]);
+      self.col.set_freq(COL_FREQS[c]);
+      self.row.reset();
+      self.col.reset();
+      self.key = key;
+    }
+  }
+
+  // Q15, each tone at half of the full scale
+  pub fn next_sample(&mut self) -> i16 {
+    if self.key.is_none() {
+      return 0;
+    }
+    let row = self.row.next_sample() as i32;
+    let col = self.col.next_sample() as i32;
+    ((row + col) / 2) as i16
+  }
+}
+
+// plays a sequence of keys, each followed by a pause
+pub struct Sender {
+  generator: Generator,
+  sample_rate: u32,
+  code: Vec<u8, MAX_CODE_LEN>,
+  pos: usize,
+  // samples until the next tone or gap
+  remaining: u32,
+  tone_on: bool,
+}
+
+impl Sender {
+  pub const fn new(sample_rate: u32) -> Self {
+    Self {
+      generator: Generator::new(sample_rate),
+      sample_rate,
+      code: Vec::new(),
+      pos: 0,
+      remaining: 0,
+      tone_on: false,
+    }
+  }
+
+  // replaces the code being sent, extra keys are dropped
+  pub fn send(&mut self, code: &[u8]) {
+    self.code.clear();
+    let len = code.len().min(MAX_CODE_LEN);
+    self.code.extend_from_slice(&code[..len]).ok();
+    self.pos = 0;
+    self.remaining = 0;
+    self.tone_on = false;
+  }
+
+  // busy until the pause after the last key is over
+  pub fn is_busy(&self) -> bool {
+    self.pos < self.code.len() || self.remaining > 0 || self.tone_on
+  }
+
+  pub fn next_sample(&mut self) -> i16 {
+    if self.remaining == 0 {
+      self.advance();
+    }
+
+    self.remaining = self.remaining.saturating_sub(1);
+    self.generator.next_sample()
+  }
+
+  // switch between tone and gap
+  fn advance(&mut self) {
+    if !self.tone_on && self.pos < self.code.len() {
+      self.generator.set_key(Some(self.code[self.pos]));
+      self.pos += 1;
+      self.tone_on = true;
+      self.remaining = self.sample_rate * TONE_MS / 1000;
+    } else if self.tone_on {
+      self.generator.set_key(None);
+      self.tone_on = false;
+      self.remaining = self.sample_rate * GAP_MS / 1000;
+    }
+  }
+}
+
+// measures the amplitude of a set of frequencies in each block
+pub struct ToneDetector {
+  filters: Vec<Goertzel, MAX_TONES>,
+}
+
+impl ToneDetector {
+  pub fn new(freqs: &[u32], sample_rate: u32) -> Self {
+    let filters = freqs
+      .iter()
+      .take(MAX_TONES)
+      .map(|&f| Goertzel::new(f, sample_rate))
+      .collect();
+    Self { filters }
+  }
+
+  // the amplitude of each frequency in the block, in the order the
+  // frequencies were given
+  pub fn process(&mut self, block: &[i16]) -> Vec<u32, MAX_TONES> {
+    self
+      .filters
+      .iter_mut()
+      .map(|g| {
+        g.reset();
+        g.process(block);
+        g.amplitude()
+      })
+      .collect()
+  }
+}
+
+// a block of 205 samples at 8 kHz is the classic choice: the bins
+// land close to all eight frequencies and it's short enough for 40 ms
+// keys.
+pub const BLOCK_LEN: usize = 205;
+
+pub struct Decoder {
+  detector: ToneDetector,
+  // the minimum amplitude of each tone
+  threshold: u32,
+  // the key seen in the last block
+  last: Option<u8>,
+  // the key already reported, cleared after a block without it
+  reported: Option<u8>,
+}
+
+impl Decoder {
+  pub fn new(sample_rate: u32, threshold: u32) -> Self {
+    let mut freqs: Vec<u32, MAX_TONES> = Vec::new();
+    freqs.extend_from_slice(&ROW_FREQS).ok();
+    freqs.extend_from_slice(&COL_FREQS).ok();
+
+    Self {
+      detector: ToneDetector::new(&freqs, sample_rate),
+      threshold,
+      last: None,
+      reported: None,
+    }
+  }
+
+  // the key heard in the block, without debouncing
+  pub fn detect(&mut self, block: &[i16]) -> Option<u8> {
+    let amplitudes = self.detector.process(block);
+    let (rows, cols) = amplitudes.split_at(4);
+    let (r, row) = strongest(rows, self.threshold)?;
+    let (c, col) = strongest(cols, self.threshold)?;
+
+    // the two tones of a key are about equally loud. allow the speaker
+    // to favor one of them by up to 4x.
+    if row > col * 4 || col > row * 4 {
+      return None;
+    }
+
+    Some(KEYS[r][c])
+  }
+
+  // returns a key once when it's heard in two blocks in a row
+  pub fn process(&mut self, block: &[i16]) -> Option<u8> {
+    let key = self.detect(block);
+    let stable = key.is_some() && key == self.last;
+    self.last = key;
+
+    if key.is_none() {
+      self.reported = None;
+    }
+
+    if stable && self.reported != key {
+      self.reported = key;
+      return key;
+    }
+
+    None
+  }
+}
+
+// the strongest tone of the group, if it's above the threshold and
+// clearly stronger than the others in the group
+fn strongest(amplitudes: &[u32], threshold: u32) -> Option<(usize, u32)> {
+  let (i, &max) = amplitudes.iter().enumerate().max_by_key(|(_, a)| **a)?;
+  if max < threshold {
+    return None;
+  }
+
+  let dominant = amplitudes
+    .iter()
+    .enumerate()
+    .all(|(j, &a)| j == i || a * 2 < max);
+
+  dominant.then_some((i, max))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::vec::Vec;
+
+  use super::*;
+  use crate::test_util::Random;
+
+  const SAMPLE_RATE: u32 = 8000;
+  // the decoder app's threshold
+  const THRESHOLD: u32 = 600;
+  const ALL_KEYS: &[u8] = b"123A456B789C*0#D";
+
+  // what the microphone of another board hears: the samples scaled
+  // down by `attenuation` with noise on top
+  fn channel(samples: &[i16], attenuation: i32, noise: i32) -> Vec<i16> {
+    let mut rng = Random::new(0xdead_beef);
+    samples
+      .iter()
+      .map(|x| (*x as i32 / attenuation + rng.noise(noise)) as i16)
+      .collect()
+  }
+
+  fn key_tone(key: u8, len: usize) -> Vec<i16> {
+    let mut generator = Generator::new(SAMPLE_RATE);
+    generator.set_key(Some(key));
+    (0..len).map(|_| generator.next_sample()).collect()
+  }
+
+  #[test]
+  fn generates_the_row_and_column_tones() {
+    for &key in ALL_KEYS {
+      let (r, c) = key_position(key).unwrap();
+      let tone = key_tone(key, 4000);
+
+      let mut freqs = Vec::new();
+      freqs.extend_from_slice(&ROW_FREQS);
+      freqs.extend_from_slice(&COL_FREQS);
+      let amplitudes = ToneDetector::new(&freqs, SAMPLE_RATE).process(&tone);
+      for (i, amplitude) in amplitudes.iter().enumerate() {
+        if i == r || i == 4 + c {
+          // each tone at half of the full scale
+          assert!((*amplitude as i32 - 16384).abs() < 800, "{}", key);
+        } else {
+          assert!(*amplitude < 800, "{} {}", key as char, amplitude);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn unknown_keys_are_silent() {
+    assert!(key_tone(b'x', 100).iter().all(|x| *x == 0));
+    assert_eq!(key_position(b'a'), key_position(b'A'));
+  }
+
+  #[test]
+  fn detects_every_key_in_one_block() {
+    let mut decoder = Decoder::new(SAMPLE_RATE, THRESHOLD);
+    for &key in ALL_KEYS {
+      for offset in [0, 37, 101] {
+        let tone = channel(&key_tone(key, BLOCK_LEN + offset), 16, 300);
+        let heard = decoder.detect(&tone[offset..]);
+        assert_eq!(heard, Some(key), "{}", key as char);
+      }
+    }
+  }
+
+  #[test]
+  fn hears_nothing_in_noise_or_a_single_tone() {
+    let mut decoder = Decoder::new(SAMPLE_RATE, THRESHOLD);
+    let noise = channel(&[0; BLOCK_LEN], 1, 2000);
+    assert_eq!(decoder.detect(&noise), None);
+
+    let mut row = crate::osc::Osc::new(SAMPLE_RATE);
+    row.set_freq(ROW_FREQS[1]);
+    let single: Vec<i16> =
+      (0..BLOCK_LEN).map(|_| row.next_sample() / 2).collect();
+    assert_eq!(decoder.detect(&single), None);
+  }
+
+  #[test]
+  fn sender_times_the_keys() {
+    let mut sender = Sender::new(SAMPLE_RATE);
+    sender.send(b"12");
+    let mut samples = Vec::new();
+    while sender.is_busy() {
+      samples.push(sender.next_sample());
+    }
+    // two keys of 100 ms, each followed by 100 ms of silence
+    assert_eq!(samples.len(), 4 * 800);
+    assert!(samples[800..1600].iter().all(|x| *x == 0));
+    assert!(samples[2400..].iter().all(|x| *x == 0));
+    assert!(samples[..800].iter().any(|x| x.abs() > 8000));
+  }
+
+  // one board beeps a code, the other one hears it through the air
+  #[test]
+  fn decodes_a_code_end_to_end() {
+    let code = b"0123456789*#ABCD";
+    let mut sender = Sender::new(SAMPLE_RATE);
+    sender.send(code);
+    let mut samples = Vec::new();
+    while sender.is_busy() {
+      samples.push(sender.next_sample());
+    }
+
+    for (attenuation, noise) in [(1, 0), (8, 500), (20, 400)] {
+      let heard = channel(&samples, attenuation, noise);
+      let mut decoder = Decoder::new(SAMPLE_RATE, THRESHOLD);
+      let keys: Vec<u8> = heard
+        .chunks_exact(BLOCK_LEN)
+        .filter_map(|block| decoder.process(block))
+        .collect();
+      assert_eq!(keys, code, "1/{} with noise {}", attenuation, noise);
+    }
+  }
+}
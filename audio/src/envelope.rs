@@ -0,0 +1,392 @@
+// adsr amplitude envelopes for the notes of the synthesizers, and the
+// curves that turn a note's velocity into a gain.
+//
+// every segment is a straight line that takes a set number of
+// samples. a new segment starts from wherever the level is, so
+// retriggering or releasing a note halfway never jumps.
+
+use fixed::types::U8F8;
+
+// the level is Q30, ONE is the peak of the attack
+const LEVEL_BITS: u32 = 30;
+const ONE: u32 = 1 << LEVEL_BITS;
+
+// no segment is shorter than this, in ms. a level that changes
+// faster than that clicks.
+const MIN_RAMP_MS: u32 = 2;
+
+// the shape of an envelope. the times are in ms, sustain is the level
+// held while the note is down, out of U8F8::ONE.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Adsr {
+  pub attack: u16,
+  pub decay: u16,
+  pub sustain: U8F8,
+  pub release: u16,
+}
+
+impl Adsr {
+  // on while held, like the synth used to be
+  pub const ORGAN: Adsr = Adsr {
+    attack: 5,
+    decay: 0,
+    sustain: U8F8::ONE,
+    release: 30,
+  };
+
+  // a strike that fades while held
+  pub const PIANO: Adsr = Adsr {
+    attack: 3,
+    decay: 900,
+    sustain: U8F8::from_bits(1 << 6),
+    release: 150,
+  };
+
+  // dies out even if held
+  pub const PLUCK: Adsr = Adsr {
+    attack: 2,
+    decay: 400,
+    sustain: U8F8::ZERO,
+    release: 80,
+  };
+
+  // swells in and lingers
+  pub const PAD: Adsr = Adsr {
+    attack: 250,
+    decay: 300,
+    sustain: U8F8::from_bits(3 << 6),
+    release: 500,
+  };
+}
+
+impl Default for Adsr {
+  fn default() -> Self {
+    Self::ORGAN
+  }
+}
+
+// how hard a key is hit, 1 to 127, becomes how loud the note is
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum VelocityCurve {
+  // every note at full volume
+  Fixed,
+  Linear,
+  // quiet notes quieter, (v/127)^2
+  #[default]
+  Soft,
+  // quiet notes louder, 1 - (1 - v/127)^2
+  Hard,
+}
+
+impl VelocityCurve {
+  pub fn gain(&self, velocity: u8) -> U8F8 {
+    // velocity out of 127, Q8
+    let v = velocity.min(127) as u32 * 256 / 127;
+    let gain = match self {
+      VelocityCurve::Fixed => 256,
+      VelocityCurve::Linear => v,
+      VelocityCurve::Soft => (v * v) >> 8,
+      VelocityCurve::Hard => 256 - (((256 - v) * (256 - v)) >> 8),
+    };
+    // a note on is never silent
+    U8F8::from_bits(gain.max(1) as u16)
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Stage {
+  Idle,
+  Attack,
+  Decay,
+  Sustain,
+  Release,
+}
+
+// an adsr at a sample rate, with the segments counted in samples
+#[derive(Clone, Copy, Debug)]
+pub struct Envelope {
+  attack: u32,
+  decay: u32,
+  sustain: u32,
+  release: u32,
+  stage: Stage,
+  level: u32,
+  // the level moves by this much every sample, either way
+  step: u32,
+  // samples left in the segment
+  remaining: u32,
+}
+
+impl Envelope {
+  pub fn new(adsr: Adsr, sample_rate: u32) -> Self {
+    let samples = |ms: u16| {
+      let ms = (ms as u32).max(MIN_RAMP_MS);
+      (ms as u64 * sample_rate as u64 / 1000).max(1) as u32
+    };
+    let sustain = adsr.sustain.min(U8F8::ONE).to_bits() as u32;
+
+    Self {
+      attack: samples(adsr.attack),
+      decay: samples(adsr.decay),
+      sustain: sustain << (LEVEL_BITS - 8),
+      release: samples(adsr.release),
+      stage: Stage::Idle,
+      level: 0,
+      step: 0,
+      remaining: 0,
+    }
+  }
+
+  pub fn stage(&self) -> Stage {
+    self.stage
+  }
+
+  pub fn is_idle(&self) -> bool {
+    self.stage == Stage::Idle
+  }
+
+  pub fn is_released(&self) -> bool {
+    matches!(self.stage, Stage::Release | Stage::Idle)
+  }
+
+  // Q15, where the level is at
+  pub fn level(&self) -> u16 {
+    (self.level >> (LEVEL_BITS - 15)) as u16
+  }
+
+  // the note starts, or starts again from the current level
+  pub fn trigger(&mut self) {
+    self.enter(Stage::Attack);
+  }
+
+  // the note is let go
+  pub fn release(&mut self) {
+    if !self.is_released() {
+      self.enter(Stage::Release);
+    }
+  }
+
+  // straight to silence, for a voice that's needed elsewhere
+  pub fn kill(&mut self) {
+    self.level = 0;
+    self.enter(Stage::Idle);
+  }
+
+  fn enter(&mut self, stage: Stage) {
+    let (target, samples) = match stage {
+      Stage::Attack => (ONE, self.attack),
+      Stage::Decay => (self.sustain, self.decay),
+      Stage::Release => (0, self.release),
+      Stage::Sustain | Stage::Idle => (self.level, 0),
+    };
+    self.stage = stage;
+    self.remaining = samples;
+    self.step = self
+      .level
+      .abs_diff(target)
+      .checked_div(samples)
+      .unwrap_or(0);
+  }
+
+  // the level of the current sample in Q15, then moves on to the
+  // next one
+  pub fn advance(&mut self) -> u16 {
+    let level = self.level();
+
+    if self.remaining > 0 {
+      self.remaining -= 1;
+      match self.stage {
+        Stage::Attack => self.level += self.step,
+        _ => self.level -= self.step,
+      }
+    }
+
+    // the last sample of a segment lands on the target, whatever the
+    // rounding of the step
+    if self.remaining == 0 {
+      match self.stage {
+        Stage::Attack => {
+          self.level = ONE;
+          self.enter(Stage::Decay);
+        }
+        // nothing left to hold
+        Stage::Decay if self.sustain == 0 => {
+          self.level = 0;
+          self.enter(Stage::Idle);
+        }
+        Stage::Decay => {
+          self.level = self.sustain;
+          self.enter(Stage::Sustain);
+        }
+        Stage::Release => {
+          self.level = 0;
+          self.enter(Stage::Idle);
+        }
+        Stage::Sustain | Stage::Idle => {}
+      }
+    }
+
+    level
+  }
+
+  // scale a Q15 sample by the level, then move on
+  pub fn apply(&mut self, sample: i16) -> i16 {
+    ((sample as i32 * self.advance() as i32) >> 15) as i16
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::vec::Vec;
+
+  // one sample per ms, so the segments are as long as their times
+  const RATE: u32 = 1000;
+  const FULL: u16 = 1 << 15;
+
+  const ADSR: Adsr = Adsr {
+    attack: 10,
+    decay: 20,
+    sustain: U8F8::from_bits(1 << 7),
+    release: 30,
+  };
+
+  fn run(envelope: &mut Envelope, samples: usize) -> Vec<u16> {
+    (0..samples).map(|_| envelope.advance()).collect()
+  }
+
+  fn rises(levels: &[u16]) -> bool {
+    levels.windows(2).all(|w| w[0] < w[1])
+  }
+
+  fn falls(levels: &[u16]) -> bool {
+    levels.windows(2).all(|w| w[0] > w[1])
+  }
+
+  #[test]
+  fn segments_take_their_time() {
+    let mut envelope = Envelope::new(ADSR, RATE);
+    assert!(envelope.is_idle());
+    envelope.trigger();
+
+    let attack = run(&mut envelope, 10);
+    assert_eq!(attack[0], 0);
+    assert!(rises(&attack));
+    assert_eq!(envelope.stage(), Stage::Decay);
+    assert_eq!(envelope.level(), FULL);
+
+    let decay = run(&mut envelope, 20);
+    assert_eq!(decay[0], FULL);
+    assert!(falls(&decay));
+    assert_eq!(envelope.stage(), Stage::Sustain);
+    assert_eq!(envelope.level(), FULL / 2);
+
+    // held as long as the key is down
+    assert!(run(&mut envelope, 1000).iter().all(|&l| l == FULL / 2));
+
+    envelope.release();
+    let release = run(&mut envelope, 30);
+    assert_eq!(release[0], FULL / 2);
+    assert!(falls(&release));
+    assert!(envelope.is_idle());
+    assert_eq!(envelope.level(), 0);
+  }
+
+  // the steps of a segment are even, the last one takes up the
+  // rounding
+  #[test]
+  fn segments_are_straight() {
+    let mut envelope = Envelope::new(ADSR, 44100);
+    envelope.trigger();
+    let attack = run(&mut envelope, 441);
+    let steps: Vec<i32> = attack
+      .windows(2)
+      .map(|w| w[1] as i32 - w[0] as i32)
+      .collect();
+    let (min, max) = (steps.iter().min(), steps.iter().max());
+    assert!(max.unwrap() - min.unwrap() <= 1, "{:?} {:?}", min, max);
+    assert_eq!(envelope.level(), FULL);
+  }
+
+  #[test]
+  fn segments_are_at_least_2ms() {
+    let adsr = Adsr {
+      attack: 0,
+      decay: 0,
+      sustain: U8F8::ONE,
+      release: 1,
+    };
+    let mut envelope = Envelope::new(adsr, RATE);
+    envelope.trigger();
+    assert_eq!(run(&mut envelope, 2), [0, FULL / 2]);
+    envelope.release();
+    run(&mut envelope, 2);
+    assert!(envelope.is_idle());
+  }
+
+  // retriggering or releasing halfway doesn't jump
+  #[test]
+  fn segments_start_where_the_level_is() {
+    let mut envelope = Envelope::new(ADSR, RATE);
+    envelope.trigger();
+    run(&mut envelope, 5);
+    let level = envelope.level();
+    envelope.release();
+    let release = run(&mut envelope, 10);
+    assert_eq!(release[0], level);
+    assert!(falls(&release));
+
+    let level = envelope.level();
+    envelope.trigger();
+    assert_eq!(envelope.stage(), Stage::Attack);
+    let attack = run(&mut envelope, 10);
+    assert_eq!(attack[0], level);
+    assert!(rises(&attack));
+    assert_eq!(envelope.level(), FULL);
+  }
+
+  #[test]
+  fn no_sustain_goes_idle() {
+    let mut envelope = Envelope::new(Adsr::PLUCK, RATE);
+    envelope.trigger();
+    run(&mut envelope, 2 + 400);
+    assert!(envelope.is_idle());
+    assert_eq!(envelope.level(), 0);
+  }
+
+  #[test]
+  fn kill_and_apply() {
+    let mut envelope = Envelope::new(ADSR, RATE);
+    envelope.trigger();
+    run(&mut envelope, 10);
+    assert_eq!(envelope.apply(-1234), -1234);
+    envelope.kill();
+    assert!(envelope.is_idle());
+    assert_eq!(envelope.apply(i16::MAX), 0);
+    // a release of an idle envelope does nothing
+    envelope.release();
+    assert!(envelope.is_idle());
+  }
+
+  #[test]
+  fn velocity_curves() {
+    let gain = |curve: VelocityCurve, v| curve.gain(v).to_bits();
+    for curve in [
+      VelocityCurve::Fixed,
+      VelocityCurve::Linear,
+      VelocityCurve::Soft,
+      VelocityCurve::Hard,
+    ] {
+      assert_eq!(gain(curve, 127), 256);
+      assert_eq!(gain(curve, 255), 256);
+      assert!(gain(curve, 0) >= 1);
+      let gains: Vec<u16> = (0..=127).map(|v| gain(curve, v)).collect();
+      assert!(gains.windows(2).all(|w| w[0] <= w[1]));
+    }
+    assert_eq!(gain(VelocityCurve::Fixed, 1), 256);
+    // about a half, a quarter and three quarters
+    assert_eq!(gain(VelocityCurve::Linear, 64), 129);
+    assert_eq!(gain(VelocityCurve::Soft, 64), 65);
+    assert_eq!(gain(VelocityCurve::Hard, 64), 193);
+  }
+}
@@ -0,0 +1,506 @@
+// equalization with a chain of biquad filters, to even out the
+// response of the speaker before the samples become duty values.
+//
+// the coefficients are designed with the formulas from the audio eq
+// cookbook (https://www.w3.org/TR/audio-eq-cookbook/), in integer
+// math with the sine table from osc, so no floats are needed here
+// either.
+
+use fixed::types::U8F8;
+use heapless::Vec;
+
+use super::{
+  goertzel::isqrt,
+  osc::{cosine, phase_step, sine},
+};
+
+pub const MAX_STAGES: usize = 4;
+
+// the coefficients are Q24, leaving room for boosts of over 40dB
+const COEFF_BITS: u32 = 24;
+// the design is done in Q30
+const DESIGN_BITS: u32 = 30;
+const ONE: i64 = 1 << DESIGN_BITS;
+
+// the gains are linear amplitudes, U8F8::ONE leaves the level as is
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+  LowPass { freq: u32, q: U8F8 },
+  HighPass { freq: u32, q: U8F8 },
+  // removes a narrow band around freq, narrower with a higher q
+  Notch { freq: u32, q: U8F8 },
+  // boosts or cuts a band around freq
+  Peaking { freq: u32, q: U8F8, gain: U8F8 },
+  // boosts or cuts everything below or above freq
+  LowShelf { freq: u32, q: U8F8, gain: U8F8 },
+  HighShelf { freq: u32, q: U8F8, gain: U8F8 },
+}
+
+// the 1/sqrt(2) q of a butterworth filter
+pub const BUTTERWORTH: U8F8 = U8F8::from_bits(181);
+
+// the MLT-8530 on the micro:bit rings at about 2.7kHz, and has little
+// to give below 500Hz. cut the resonance down by 12dB and lift the
+// lows a little, but don't bother the speaker with what it can't play.
+pub const MLT_8530: &[Filter] = &[
+  Filter::Peaking {
+    freq: 2700,
+    q: U8F8::from_bits(2 << 8),
+    gain: U8F8::from_bits(1 << 6),
+  },
+  Filter::LowShelf {
+    freq: 1000,
+    q: BUTTERWORTH,
+    gain: U8F8::from_bits(362),
+  },
+  Filter::HighPass {
+    freq: 200,
+    q: BUTTERWORTH,
+  },
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Coeffs {
+  b0: i32,
+  b1: i32,
+  b2: i32,
+  a1: i32,
+  a2: i32,
+}
+
+impl Coeffs {
+  // passes the samples through unchanged
+  pub const IDENTITY: Coeffs = Coeffs {
+    b0: 1 << COEFF_BITS,
+    b1: 0,
+    b2: 0,
+    a1: 0,
+    a2: 0,
+  };
+
+  pub fn design(filter: Filter, sample_rate: u32) -> Self {
+    let (freq, q) = match filter {
+      Filter::LowPass { freq, q }
+      | Filter::HighPass { freq, q }
+      | Filter::Notch { freq, q }
+      | Filter::Peaking { freq, q, .. }
+      | Filter::LowShelf { freq, q, .. }
+      | Filter::HighShelf { freq, q, .. } => (freq, q),
+    };
+    // above nyquist the formulas fold back, a zero q divides by zero
+    if freq == 0 || 2 * freq >= sample_rate || q == U8F8::ZERO {
+      return Self::IDENTITY;
+    }
+
+    let w0 = phase_step(freq, sample_rate);
+    let cos = (cosine(w0) as i64) << (DESIGN_BITS - 15);
+    let sin = (sine(w0) as i64) << (DESIGN_BITS - 15);
+    // sin / (2q), q is U8F8
+    let alpha = (sin << 7) / q.to_bits() as i64;
+
+    let [b0, b1, b2, a0, a1, a2] = match filter {
+      Filter::LowPass { .. } => {
+        let b1 = ONE - cos;
+        [b1 / 2, b1, b1 / 2, ONE + alpha, -2 * cos, ONE - alpha]
+      }
+      Filter::HighPass { .. } => {
+        let b1 = -(ONE + cos);
+        [-b1 / 2, b1, -b1 / 2, ONE + alpha, -2 * cos, ONE - alpha]
+      }
+      Filter::Notch { .. } => {
+        [ONE, -2 * cos, ONE, ONE + alpha, -2 * cos, ONE - alpha]
+      }
+      Filter::Peaking { gain, .. } => {
+        let a = amplitude(gain);
+        let alpha_a = mul(alpha, a);
+        let alpha_div_a = div(alpha, a);
+        [
+          ONE + alpha_a,
+          -2 * cos,
+          ONE - alpha_a,
+          ONE + alpha_div_a,
+          -2 * cos,
+          ONE - alpha_div_a,
+        ]
+      }
+      Filter::LowShelf { gain, .. } => {
+        let a = amplitude(gain);
+        // 2 sqrt(a) alpha
+        let s = 2 * mul(sqrt(a), alpha);
+        let (ap, am) = (a + ONE, a - ONE);
+        [
+          mul(a, ap - mul(am, cos) + s),
+          2 * mul(a, am - mul(ap, cos)),
+          mul(a, ap - mul(am, cos) - s),
+          ap + mul(am, cos) + s,
+          -2 * (am + mul(ap, cos)),
+          ap + mul(am, cos) - s,
+        ]
+      }
+      Filter::HighShelf { gain, .. } => {
+        let a = amplitude(gain);
+        let s = 2 * mul(sqrt(a), alpha);
+        let (ap, am) = (a + ONE, a - ONE);
+        [
+          mul(a, ap + mul(am, cos) + s),
+          -2 * mul(a, am + mul(ap, cos)),
+          mul(a, ap + mul(am, cos) - s),
+          ap - mul(am, cos) + s,
+          2 * (am - mul(ap, cos)),
+          ap - mul(am, cos) - s,
+        ]
+      }
+    };
+
+    // normalized so a0 is 1
+    let norm = |x: i64| {
+      let x = div(x, a0) >> (DESIGN_BITS - COEFF_BITS);
+      x.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+    };
+    Self {
+      b0: norm(b0),
+      b1: norm(b1),
+      b2: norm(b2),
+      a1: norm(a1),
+      a2: norm(a2),
+    }
+  }
+}
+
+// Q30 product and quotient, the design only runs at setup so the
+// wide math doesn't matter
+fn mul(x: i64, y: i64) -> i64 {
+  ((x as i128 * y as i128) >> DESIGN_BITS) as i64
+}
+
+fn div(x: i64, y: i64) -> i64 {
+  (((x as i128) << DESIGN_BITS) / y as i128) as i64
+}
+
+// Q30 square root. gains of 16 and up don't fit in 64 bits shifted
+// by the whole 30, those give up a few of the lowest bits.
+fn sqrt(x: i64) -> i64 {
+  let x = x.max(0) as u64;
+  let shift = DESIGN_BITS.min(x.leading_zeros() & !1);
+  (isqrt(x << shift) << ((DESIGN_BITS - shift) / 2)) as i64
+}
+
+// the cookbook's A, the square root of the gain, in Q30
+fn amplitude(gain: U8F8) -> i64 {
+  let gain = (gain.to_bits().max(1) as i64) << (DESIGN_BITS - 8);
+  sqrt(gain)
+}
+
+// a direct form I biquad. the rounding error of each output is carried
+// over to the next one, so it doesn't build up in the feedback path.
+#[derive(Clone, Copy, Debug)]
+pub struct Biquad {
+  coeffs: Coeffs,
+  x1: i32,
+  x2: i32,
+  y1: i32,
+  y2: i32,
+  error: i64,
+}
+
+impl Biquad {
+  pub const fn new(coeffs: Coeffs) -> Self {
+    Self {
+      coeffs,
+      x1: 0,
+      x2: 0,
+      y1: 0,
+      y2: 0,
+      error: 0,
+    }
+  }
+
+  pub fn reset(&mut self) {
+    *self = Self::new(self.coeffs);
+  }
+
+  // Q15 in and out, saturating
+  pub fn process(&mut self, x: i16) -> i16 {
+    let c = &self.coeffs;
+    let x = x as i32;
+    let acc = c.b0 as i64 * x as i64
+      + c.b1 as i64 * self.x1 as i64
+      + c.b2 as i64 * self.x2 as i64
+      - c.a1 as i64 * self.y1 as i64
+      - c.a2 as i64 * self.y2 as i64
+      + self.error;
+
+    let y = acc >> COEFF_BITS;
+    self.error = acc - (y << COEFF_BITS);
+    // keep the state bounded if the filter is driven into clipping
+    let y = y.clamp(i16::MIN as i64 * 2, i16::MAX as i64 * 2) as i32;
+
+    self.x2 = self.x1;
+    self.x1 = x;
+    self.y2 = self.y1;
+    self.y1 = y;
+    y.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+  }
+}
+
+// the filters one after another. an empty chain leaves the samples
+// alone.
+#[derive(Clone, Default)]
+pub struct Equalizer {
+  stages: Vec<Biquad, MAX_STAGES>,
+}
+
+impl Equalizer {
+  pub const fn new() -> Self {
+    Self { stages: Vec::new() }
+  }
+
+  // filters beyond MAX_STAGES are left out
+  pub fn with_filters(filters: &[Filter], sample_rate: u32) -> Self {
+    let mut eq = Self::new();
+    for filter in filters {
+      eq.push(*filter, sample_rate).ok();
+    }
+    eq
+  }
+
+  // gives the filter back when the chain is full
+  pub fn push(
+    &mut self,
+    filter: Filter,
+    sample_rate: u32,
+  ) -> Result<(), Filter> {
+    let stage = Biquad::new(Coeffs::design(filter, sample_rate));
+    self.stages.push(stage).map_err(|_| filter)
+  }
+
+  pub fn clear(&mut self) {
+    self.stages.clear();
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.stages.is_empty()
+  }
+
+  pub fn reset(&mut self) {
+    self.stages.iter_mut().for_each(Biquad::reset);
+  }
+
+  pub fn process(&mut self, sample: i16) -> i16 {
+    self
+      .stages
+      .iter_mut()
+      .fold(sample, |sample, stage| stage.process(sample))
+  }
+
+  pub fn process_buffer(&mut self, samples: &mut [i16]) {
+    for sample in samples.iter_mut() {
+      *sample = self.process(*sample);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::f64::consts::TAU;
+
+  fn filters() -> [Filter; 6] {
+    let q = BUTTERWORTH;
+    let gain = U8F8::from_num(4);
+    let cut = U8F8::from_num(0.25);
+    [
+      Filter::LowPass { freq: 1000, q },
+      Filter::HighPass { freq: 500, q },
+      Filter::Notch {
+        freq: 2700,
+        q: U8F8::from_num(2),
+      },
+      Filter::Peaking {
+        freq: 2700,
+        q: U8F8::from_num(2),
+        gain: cut,
+      },
+      Filter::LowShelf {
+        freq: 1000,
+        q,
+        gain,
+      },
+      Filter::HighShelf {
+        freq: 2000,
+        q,
+        gain: cut,
+      },
+    ]
+  }
+
+  // the cookbook formulas in floats
+  fn reference(filter: Filter, sample_rate: u32) -> [f64; 6] {
+    let (freq, q, gain) = match filter {
+      Filter::LowPass { freq, q }
+      | Filter::HighPass { freq, q }
+      | Filter::Notch { freq, q } => (freq, q, U8F8::ONE),
+      Filter::Peaking { freq, q, gain }
+      | Filter::LowShelf { freq, q, gain }
+      | Filter::HighShelf { freq, q, gain } => (freq, q, gain),
+    };
+    let w0 = TAU * freq as f64 / sample_rate as f64;
+    let (sin, cos) = w0.sin_cos();
+    let alpha = sin / (2.0 * q.to_num::<f64>());
+    let a = gain.to_num::<f64>().sqrt();
+    let s = 2.0 * a.sqrt() * alpha;
+    match filter {
+      Filter::LowPass { .. } => {
+        let b1 = 1.0 - cos;
+        [b1 / 2.0, b1, b1 / 2.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha]
+      }
+      Filter::HighPass { .. } => {
+        let b1 = 1.0 + cos;
+        [
+          b1 / 2.0,
+          -b1,
+          b1 / 2.0,
+          1.0 + alpha,
+          -2.0 * cos,
+          1.0 - alpha,
+        ]
+      }
+      Filter::Notch { .. } => {
+        [1.0, -2.0 * cos, 1.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha]
+      }
+      Filter::Peaking { .. } => [
+        1.0 + alpha * a,
+        -2.0 * cos,
+        1.0 - alpha * a,
+        1.0 + alpha / a,
+        -2.0 * cos,
+        1.0 - alpha / a,
+      ],
+      Filter::LowShelf { .. } => [
+        a * ((a + 1.0) - (a - 1.0) * cos + s),
+        2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+        a * ((a + 1.0) - (a - 1.0) * cos - s),
+        (a + 1.0) + (a - 1.0) * cos + s,
+        -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+        (a + 1.0) + (a - 1.0) * cos - s,
+      ],
+      Filter::HighShelf { .. } => [
+        a * ((a + 1.0) + (a - 1.0) * cos + s),
+        -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+        a * ((a + 1.0) + (a - 1.0) * cos - s),
+        (a + 1.0) - (a - 1.0) * cos + s,
+        2.0 * ((a - 1.0) - (a + 1.0) * cos),
+        (a + 1.0) - (a - 1.0) * cos - s,
+      ],
+    }
+  }
+
+  // |H| at freq, in dB
+  fn reference_db(coeffs: [f64; 6], freq: f64, sample_rate: u32) -> f64 {
+    let [b0, b1, b2, a0, a1, a2] = coeffs;
+    let w = TAU * freq / sample_rate as f64;
+    let eval = |c0: f64, c1: f64, c2: f64| {
+      let re = c0 + c1 * w.cos() + c2 * (2.0 * w).cos();
+      let im = -c1 * w.sin() - c2 * (2.0 * w).sin();
+      re.hypot(im)
+    };
+    20.0 * (eval(b0, b1, b2) / eval(a0, a1, a2)).log10()
+  }
+
+  // runs a sine through the filter and measures what comes out once
+  // it has settled, in dB
+  fn measure_db(eq: &mut Equalizer, freq: f64, sample_rate: u32) -> f64 {
+    let amplitude = 4000.0;
+    let settle = sample_rate as usize / 5;
+    let len = sample_rate as usize / 5;
+    let (mut re, mut im) = (0.0, 0.0);
+    for i in 0..settle + len {
+      let t = TAU * freq * i as f64 / sample_rate as f64;
+      let y = eq.process((amplitude * t.sin()).round() as i16) as f64;
+      if i >= settle {
+        re += y * t.cos();
+        im += y * t.sin();
+      }
+    }
+    let out = 2.0 * re.hypot(im) / len as f64;
+    20.0 * (out / amplitude).log10()
+  }
+
+  fn freqs() -> impl Iterator<Item = f64> {
+    // a third of an octave apart, from 100Hz to 6kHz
+    (0..)
+      .map(|i| 100.0 * 2f64.powf(i as f64 / 3.0))
+      .take_while(|&f| f <= 6000.0)
+  }
+
+  // the figures in the readme. the higher the rate, the closer the
+  // cosine of a low cutoff gets to 1, and the more the error of the
+  // sine table shows.
+  #[test]
+  fn response_matches_cookbook() {
+    for (sample_rate, limit) in [(16000, 0.15), (31250, 0.15), (44000, 0.25)] {
+      let mut worst: f64 = 0.0;
+      for filter in filters() {
+        let coeffs = reference(filter, sample_rate);
+        for freq in freqs() {
+          let expected = reference_db(coeffs, freq, sample_rate);
+          // deep in a notch there's nothing left to compare
+          if expected < -40.0 {
+            continue;
+          }
+          let mut eq = Equalizer::with_filters(&[filter], sample_rate);
+          let db = measure_db(&mut eq, freq, sample_rate);
+          worst = worst.max((db - expected).abs());
+        }
+      }
+      assert!(worst < limit, "{}Hz: {}dB", sample_rate, worst);
+    }
+  }
+
+  #[test]
+  fn out_of_range_filters_pass_through() {
+    let q = BUTTERWORTH;
+    for filter in [
+      Filter::LowPass { freq: 0, q },
+      Filter::LowPass { freq: 8000, q },
+      Filter::HighPass {
+        freq: 1000,
+        q: U8F8::ZERO,
+      },
+    ] {
+      assert_eq!(Coeffs::design(filter, 16000), Coeffs::IDENTITY);
+    }
+  }
+
+  #[test]
+  fn chain_holds_four_filters() {
+    let mut eq = Equalizer::new();
+    assert!(eq.is_empty());
+    assert_eq!(eq.process(1234), 1234);
+    let filter = Filter::Notch {
+      freq: 1000,
+      q: BUTTERWORTH,
+    };
+    for _ in 0..MAX_STAGES {
+      assert_eq!(eq.push(filter, 16000), Ok(()));
+    }
+    assert_eq!(eq.push(filter, 16000), Err(filter));
+  }
+
+  // a boost driven into clipping saturates and recovers, the state
+  // doesn't wrap around
+  #[test]
+  fn clipping_saturates() {
+    let boost = Filter::LowShelf {
+      freq: 1000,
+      q: BUTTERWORTH,
+      gain: U8F8::from_num(16),
+    };
+    let mut eq = Equalizer::with_filters(&[boost], 16000);
+    let out: std::vec::Vec<i16> =
+      (0..4000).map(|_| eq.process(i16::MAX)).collect();
+    assert!(out[100..].iter().all(|&y| y == i16::MAX));
+    let after = (0..4000).map(|_| eq.process(0)).last();
+    assert_eq!(after, Some(0));
+  }
+}
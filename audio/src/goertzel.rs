@@ -0,0 +1,144 @@
+// the goertzel algorithm measures the magnitude of a single frequency
+// in a block of samples. it's much cheaper than a full fft when only a
+// few frequencies are of interest.
+
+use super::osc::{cosine, phase_step};
+
+pub struct Goertzel {
+  // 2 * cos(2 * pi * freq / sample_rate) in Q14
+  coeff: i64,
+  s1: i64,
+  s2: i64,
+  n: u32,
+}
+
+impl Goertzel {
+  pub fn new(freq_hz: u32, sample_rate: u32) -> Self {
+    // cos in Q15 is the same as 2 * cos in Q14
+    let coeff = cosine(phase_step(freq_hz, sample_rate)) as i64;
+    Self {
+      coeff,
+      s1: 0,
+      s2: 0,
+      n: 0,
+    }
+  }
+
+  pub fn reset(&mut self) {
+    self.s1 = 0;
+    self.s2 = 0;
+    self.n = 0;
+  }
+
+  pub fn process(&mut self, samples: &[i16]) {
+    for &x in samples {
+      let s0 = x as i64 + ((self.coeff * self.s1) >> 14) - self.s2;
+      self.s2 = self.s1;
+      self.s1 = s0;
+    }
+    self.n += samples.len() as u32;
+  }
+
+  // squared magnitude of the frequency bin
+  pub fn power(&self) -> u64 {
+    let (s1, s2) = (self.s1, self.s2);
+    let p = s1 * s1 + s2 * s2 - ((self.coeff * s1) >> 14) * s2;
+    p.max(0) as u64
+  }
+
+  // the amplitude of the tone in sample units, a full scale sine
+  // gives about 32767
+  pub fn amplitude(&self) -> u32 {
+    if self.n == 0 {
+      return 0;
+    }
+    (2 * isqrt(self.power()) / self.n as u64) as u32
+  }
+}
+
+pub fn isqrt(x: u64) -> u64 {
+  if x < 2 {
+    return x;
+  }
+
+  // newton's method, starting above the root
+  let mut r = 1u64 << ((64 - x.leading_zeros()) / 2 + 1);
+  loop {
+    let next = (r + x / r) / 2;
+    if next >= r {
+      return r;
+    }
+    r = next;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::vec::Vec;
+
+  use super::*;
+  use crate::osc::Osc;
+
+  const SAMPLE_RATE: u32 = 8000;
+
+  fn tone(freq: u32, amplitude: i32, len: usize) -> Vec<i16> {
+    let mut osc = Osc::new(SAMPLE_RATE);
+    osc.set_freq(freq);
+    (0..len)
+      .map(|_| ((osc.next_sample() as i32 * amplitude) >> 15) as i16)
+      .collect()
+  }
+
+  fn measure(freq: u32, samples: &[i16]) -> u32 {
+    let mut goertzel = Goertzel::new(freq, SAMPLE_RATE);
+    goertzel.process(samples);
+    goertzel.amplitude()
+  }
+
+  #[test]
+  fn measures_the_amplitude_of_its_frequency() {
+    for freq in [400, 697, 1000, 1633, 3000] {
+      for amplitude in [1000, 8000, 32767] {
+        // a whole number of periods
+        let samples = tone(freq, amplitude, 800);
+        let measured = measure(freq, &samples) as i32;
+        assert!(
+          (measured - amplitude).abs() < amplitude / 50 + 2,
+          "{} Hz at {} measured {}",
+          freq,
+          amplitude,
+          measured
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn ignores_other_frequencies() {
+    let samples = tone(1000, 32767, 800);
+    for freq in [500, 900, 1100, 2000] {
+      assert!(measure(freq, &samples) < 300, "{} Hz", freq);
+    }
+  }
+
+  #[test]
+  fn blocks_add_up() {
+    let samples = tone(697, 16000, 800);
+    let mut goertzel = Goertzel::new(697, SAMPLE_RATE);
+    for block in samples.chunks(37) {
+      goertzel.process(block);
+    }
+    assert_eq!(goertzel.amplitude(), measure(697, &samples));
+
+    goertzel.reset();
+    assert_eq!(goertzel.amplitude(), 0);
+  }
+
+  #[test]
+  fn isqrt_rounds_down() {
+    for x in (0..100_000).chain([u32::MAX as u64, u64::MAX]) {
+      let r = isqrt(x) as u128;
+      assert!(r * r <= x as u128 && (r + 1) * (r + 1) > x as u128, "{}", x);
+    }
+  }
+}
@@ -0,0 +1,401 @@
+// a small audio graph: sources of Q15 samples, effects that change
+// them, a mixer that adds sources together and the pwm sink at the
+// end that turns the result into duty values.
+//
+// the mixer borrows its inputs, so it's cheap to put together for
+// every buffer in the pwm interrupt. the sources keep their state and
+// the app keeps the gains.
+
+use fixed::types::U8F8;
+use heapless::Vec;
+
+use super::{
+  dsp, dtmf,
+  eq::Equalizer,
+  modem,
+  noise_shaping::NoiseShaper,
+  resample::{Interpolation, Resampler},
+  wav,
+};
+
+// the samples are worked on this many at a time, in buffers on the
+// stack
+const CHUNK: usize = 32;
+
+pub trait AudioSource {
+  // Q15 samples at the rate the source was set up for
+  fn fill(&mut self, samples: &mut [i16]);
+
+  // nothing is playing, so the sink can let the speaker rest
+  fn is_silent(&self) -> bool {
+    false
+  }
+}
+
+impl<S: AudioSource + ?Sized> AudioSource for &mut S {
+  fn fill(&mut self, samples: &mut [i16]) {
+    (**self).fill(samples)
+  }
+
+  fn is_silent(&self) -> bool {
+    (**self).is_silent()
+  }
+}
+
+pub trait Effect {
+  fn process(&mut self, samples: &mut [i16]);
+}
+
+impl Effect for Equalizer {
+  fn process(&mut self, samples: &mut [i16]) {
+    self.process_buffer(samples);
+  }
+}
+
+pub struct Gain(pub U8F8);
+
+impl Effect for Gain {
+  fn process(&mut self, samples: &mut [i16]) {
+    for sample in samples.iter_mut() {
+      *sample = dsp::apply_gain(*sample, self.0);
+    }
+  }
+}
+
+// a source followed by an effect
+pub struct Effected<S, E> {
+  pub source: S,
+  pub effect: E,
+}
+
+impl<S: AudioSource, E: Effect> AudioSource for Effected<S, E> {
+  fn fill(&mut self, samples: &mut [i16]) {
+    self.source.fill(samples);
+    self.effect.process(samples);
+  }
+
+  fn is_silent(&self) -> bool {
+    self.source.is_silent()
+  }
+}
+
+struct Input<'a> {
+  source: &'a mut dyn AudioSource,
+  gain: U8F8,
+}
+
+// adds up to N sources, each scaled by its gain. the sum saturates, so
+// the gains should add up to about one.
+pub struct Mixer<'a, const N: usize> {
+  inputs: Vec<Input<'a>, N>,
+}
+
+impl<'a, const N: usize> Mixer<'a, N> {
+  pub const fn new() -> Self {
+    Self { inputs: Vec::new() }
+  }
+
+  // inputs beyond N are left out
+  pub fn input(mut self, source: &'a mut dyn AudioSource, gain: U8F8) -> Self {
+    self.inputs.push(Input { source, gain }).ok();
+    self
+  }
+}
+
+impl<const N: usize> Default for Mixer<'_, N> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<const N: usize> AudioSource for Mixer<'_, N> {
+  fn fill(&mut self, samples: &mut [i16]) {
+    let mut input = [0i16; CHUNK];
+    let mut sum = [0i32; CHUNK];
+
+    for chunk in samples.chunks_mut(CHUNK) {
+      let sum = &mut sum[..chunk.len()];
+      sum.fill(0);
+
+      for Input { source, gain } in self.inputs.iter_mut() {
+        if source.is_silent() {
+          continue;
+        }
+        let input = &mut input[..chunk.len()];
+        source.fill(input);
+        for (acc, x) in sum.iter_mut().zip(input.iter()) {
+          *acc += (*x as i32 * gain.to_bits() as i32) >> 8;
+        }
+      }
+
+      for (out, acc) in chunk.iter_mut().zip(sum.iter()) {
+        *out = (*acc).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+      }
+    }
+  }
+
+  fn is_silent(&self) -> bool {
+    self.inputs.iter().all(|input| input.source.is_silent())
+  }
+}
+
+// a wav clip played once, resampled to the rate of the graph
+pub struct ClipSource<'a> {
+  reader: wav::Reader<'a>,
+  resampler: Resampler,
+}
+
+impl<'a> ClipSource<'a> {
+  pub fn new(
+    wav: &'a [u8],
+    target_rate: u32,
+    interpolation: Interpolation,
+  ) -> Result<Self, wav::WavError> {
+    let wav = wav::Wav::parse(wav)?;
+    let mut reader = wav.reader();
+    let mut resampler = Resampler::new(interpolation);
+    resampler.set_rates(wav.spec.sample_rate, target_rate);
+    resampler.seek(&mut reader, 0);
+    Ok(Self { reader, resampler })
+  }
+
+  // back to the start
+  pub fn rewind(&mut self) {
+    self.resampler.seek(&mut self.reader, 0);
+  }
+
+  pub fn is_finished(&self) -> bool {
+    self.resampler.is_finished(self.reader.len())
+  }
+}
+
+impl AudioSource for ClipSource<'_> {
+  fn fill(&mut self, samples: &mut [i16]) {
+    for sample in samples.iter_mut() {
+      *sample = self.resampler.next_sample(&mut self.reader);
+    }
+  }
+
+  fn is_silent(&self) -> bool {
+    self.is_finished()
+  }
+}
+
+impl AudioSource for dtmf::Sender {
+  fn fill(&mut self, samples: &mut [i16]) {
+    for sample in samples.iter_mut() {
+      *sample = self.next_sample();
+    }
+  }
+}
+
+impl AudioSource for modem::Modulator {
+  fn fill(&mut self, samples: &mut [i16]) {
+    for sample in samples.iter_mut() {
+      *sample = self.next_sample();
+    }
+  }
+
+  // between frames
+  fn is_silent(&self) -> bool {
+    !self.is_busy()
+  }
+}
+
+// the end of the graph: turns samples into duty values for the pwm,
+// one per sample. spreading them out for the speaker drive is up to
+// the app.
+pub struct PwmSink {
+  countertop: u16,
+  // the duty value while the source is silent
+  silence: u16,
+  shaper: NoiseShaper,
+}
+
+impl PwmSink {
+  pub const fn new(countertop: u16, silence: u16, shaper: NoiseShaper) -> Self {
+    Self {
+      countertop,
+      silence,
+      shaper,
+    }
+  }
+
+  pub fn set_countertop(&mut self, countertop: u16, silence: u16) {
+    self.countertop = countertop;
+    self.silence = silence;
+  }
+
+  pub fn countertop(&self) -> u16 {
+    self.countertop
+  }
+
+  pub fn shaper(&mut self) -> &mut NoiseShaper {
+    &mut self.shaper
+  }
+
+  pub fn fill<S: AudioSource + ?Sized>(
+    &mut self,
+    source: &mut S,
+    buffer: &mut [u16],
+  ) {
+    if source.is_silent() {
+      buffer.fill(self.silence);
+      return;
+    }
+
+    let mut samples = [0i16; CHUNK];
+    for chunk in buffer.chunks_mut(CHUNK) {
+      let samples = &mut samples[..chunk.len()];
+      source.fill(samples);
+      for (cell, sample) in chunk.iter_mut().zip(samples.iter()) {
+        *cell = self.shaper.duty(*sample, self.countertop);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::noise_shaping::Order;
+  use std::vec;
+
+  // the same sample over and over, counting the samples it made
+  struct Constant {
+    value: i16,
+    silent: bool,
+    filled: usize,
+  }
+
+  impl Constant {
+    fn new(value: i16) -> Self {
+      Self {
+        value,
+        silent: false,
+        filled: 0,
+      }
+    }
+  }
+
+  impl AudioSource for Constant {
+    fn fill(&mut self, samples: &mut [i16]) {
+      samples.fill(self.value);
+      self.filled += samples.len();
+    }
+
+    fn is_silent(&self) -> bool {
+      self.silent
+    }
+  }
+
+  fn mix<const N: usize>(mut mixer: Mixer<'_, N>, len: usize) -> vec::Vec<i16> {
+    let mut out = vec![0; len];
+    mixer.fill(&mut out);
+    out
+  }
+
+  #[test]
+  fn inputs_are_scaled_and_added() {
+    let (mut a, mut b) = (Constant::new(1000), Constant::new(-3000));
+    let mixer = Mixer::<2>::new()
+      .input(&mut a, U8F8::from_num(0.5))
+      .input(&mut b, U8F8::from_num(0.25));
+    // more than a chunk, and not a whole number of them
+    let out = mix(mixer, 3 * CHUNK + 5);
+    assert!(out.iter().all(|&x| x == 500 - 750));
+    assert_eq!(a.filled, 3 * CHUNK + 5);
+  }
+
+  #[test]
+  fn sum_saturates() {
+    let (mut a, mut b) = (Constant::new(30000), Constant::new(30000));
+    let mixer = Mixer::<2>::new()
+      .input(&mut a, U8F8::ONE)
+      .input(&mut b, U8F8::ONE);
+    assert!(mix(mixer, 10).iter().all(|&x| x == i16::MAX));
+
+    let (mut a, mut b) = (Constant::new(-30000), Constant::new(-30000));
+    let mixer = Mixer::<2>::new()
+      .input(&mut a, U8F8::ONE)
+      .input(&mut b, U8F8::ONE);
+    assert!(mix(mixer, 10).iter().all(|&x| x == i16::MIN));
+  }
+
+  // a silent input isn't asked for samples, so it doesn't move on
+  #[test]
+  fn silent_inputs_are_skipped() {
+    let (mut a, mut b) = (Constant::new(1000), Constant::new(2000));
+    b.silent = true;
+    let mixer = Mixer::<2>::new()
+      .input(&mut a, U8F8::ONE)
+      .input(&mut b, U8F8::ONE);
+    assert!(!mixer.is_silent());
+    assert!(mix(mixer, 10).iter().all(|&x| x == 1000));
+    assert_eq!(b.filled, 0);
+
+    a.silent = true;
+    let mixer = Mixer::<2>::new()
+      .input(&mut a, U8F8::ONE)
+      .input(&mut b, U8F8::ONE);
+    assert!(mixer.is_silent());
+    assert!(Mixer::<1>::new().is_silent());
+  }
+
+  #[test]
+  fn inputs_beyond_n_are_left_out() {
+    let (mut a, mut b) = (Constant::new(1000), Constant::new(2000));
+    let mixer = Mixer::<1>::new()
+      .input(&mut a, U8F8::ONE)
+      .input(&mut b, U8F8::ONE);
+    assert!(mix(mixer, 10).iter().all(|&x| x == 1000));
+    assert_eq!(b.filled, 0);
+  }
+
+  #[test]
+  fn effects_follow_the_source() {
+    let mut source = Effected {
+      source: Constant::new(1000),
+      effect: Gain(U8F8::from_num(3)),
+    };
+    let mut out = [0; 4];
+    source.fill(&mut out);
+    assert_eq!(out, [3000; 4]);
+  }
+
+  #[test]
+  fn sink_rests_while_silent() {
+    let mut sink = PwmSink::new(100, 50, NoiseShaper::new(Order::None));
+    let mut source = Constant::new(i16::MAX);
+    let mut buffer = [0; 8];
+    sink.fill(&mut source, &mut buffer);
+    assert!(buffer.iter().all(|&d| d == 100));
+
+    source.silent = true;
+    sink.fill(&mut source, &mut buffer);
+    assert_eq!(buffer, [50; 8]);
+  }
+
+  #[test]
+  fn modulator_rests_between_frames() {
+    let rate = 8000;
+    let mut sink = PwmSink::new(100, 50, NoiseShaper::new(Order::None));
+    let mut modulator = modem::Modulator::new(rate);
+    let mut buffer = [0; 64];
+    sink.fill(&mut modulator, &mut buffer);
+    assert_eq!(buffer, [50; 64]);
+
+    modulator.send(&modem::Frame::ack(1));
+    let mut duties = vec::Vec::new();
+    while modulator.is_busy() {
+      sink.fill(&mut modulator, &mut buffer);
+      duties.extend_from_slice(&buffer);
+    }
+    // the tones swing over most of the range
+    assert!(duties.iter().any(|&d| d <= 5));
+    assert!(duties.iter().any(|&d| d >= 95));
+
+    sink.fill(&mut modulator, &mut buffer);
+    assert_eq!(buffer, [50; 64]);
+  }
+}
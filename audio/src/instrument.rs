@@ -0,0 +1,231 @@
+// the instruments of the midi synth: a single cycle wavetable, an
+// envelope and a vibrato each. the tables are worked out at compile
+// time from a few harmonics and live in flash with the rest of the
+// bank.
+//
+// general midi numbers its 128 programs in families of eight (pianos,
+// organs, strings and so on). the bank has one instrument per family,
+// which is enough to tell the parts of a song apart.
+
+use fixed::types::U8F8;
+
+use super::{envelope::Adsr, osc::SINE_TABLE};
+
+pub const TABLE_SIZE: usize = 256;
+const HARMONICS: usize = 8;
+
+// one period of a waveform, Q15
+pub struct Wavetable([i16; TABLE_SIZE]);
+
+impl Wavetable {
+  // the harmonics are the relative levels of the first eight partials,
+  // and the table is scaled to full range
+  const fn additive(harmonics: [u8; HARMONICS]) -> Self {
+    let mut sums = [0i32; TABLE_SIZE];
+    let mut peak = 1;
+    let mut i = 0;
+    while i < TABLE_SIZE {
+      let mut h = 0;
+      while h < HARMONICS {
+        let x = SINE_TABLE[i * (h + 1) % TABLE_SIZE] as i32;
+        sums[i] += x * harmonics[h] as i32;
+        h += 1;
+      }
+      if sums[i].abs() > peak {
+        peak = sums[i].abs();
+      }
+      i += 1;
+    }
+
+    let mut table = [0i16; TABLE_SIZE];
+    let mut i = 0;
+    while i < TABLE_SIZE {
+      table[i] = (sums[i] as i64 * i16::MAX as i64 / peak as i64) as i16;
+      i += 1;
+    }
+    Self(table)
+  }
+
+  // Q15 sample at the phase, linear between the entries
+  pub fn sample(&self, phase: u32) -> i16 {
+    let i = (phase >> 24) as usize;
+    let frac = ((phase >> 8) & 0xffff) as i32;
+    let a = self.0[i] as i32;
+    let b = self.0[(i + 1) % TABLE_SIZE] as i32;
+    (a + (((b - a) * frac) >> 16)) as i16
+  }
+}
+
+pub static SINE: Wavetable = Wavetable::additive([255, 0, 0, 0, 0, 0, 0, 0]);
+// the partials of a struck string fall off quickly
+pub static PIANO_TABLE: Wavetable =
+  Wavetable::additive([255, 110, 60, 30, 20, 10, 6, 4]);
+// drawbars at 8', 4', 2' and 1'
+pub static ORGAN_TABLE: Wavetable =
+  Wavetable::additive([255, 160, 0, 100, 0, 0, 0, 60]);
+// a sawtooth cut off after the eighth harmonic, for bowed strings and
+// brass
+pub static SAW: Wavetable =
+  Wavetable::additive([255, 128, 85, 64, 51, 43, 36, 32]);
+// the odd harmonics only, hollow like a clarinet
+pub static SQUARE: Wavetable =
+  Wavetable::additive([255, 0, 85, 0, 51, 0, 36, 0]);
+// a sine with a little breath
+pub static FLUTE_TABLE: Wavetable =
+  Wavetable::additive([255, 40, 12, 0, 0, 0, 0, 0]);
+
+// a slow wobble of the pitch
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Vibrato {
+  // in Hz
+  pub rate: U8F8,
+  // how far the pitch goes either way, in cents
+  pub depth: u16,
+  // from the start of the note, in ms
+  pub delay: u16,
+}
+
+impl Vibrato {
+  pub const NONE: Vibrato = Vibrato {
+    rate: U8F8::ZERO,
+    depth: 0,
+    delay: 0,
+  };
+
+  const fn new(rate: u8, depth: u16, delay: u16) -> Self {
+    Self {
+      rate: U8F8::from_bits((rate as u16) << 8),
+      depth,
+      delay,
+    }
+  }
+}
+
+pub struct Instrument {
+  pub name: &'static str,
+  pub table: &'static Wavetable,
+  pub envelope: Adsr,
+  pub vibrato: Vibrato,
+}
+
+pub static PIANO: Instrument = Instrument {
+  name: "piano",
+  table: &PIANO_TABLE,
+  envelope: Adsr::PIANO,
+  vibrato: Vibrato::NONE,
+};
+
+pub static MALLET: Instrument = Instrument {
+  name: "mallet",
+  table: &SINE,
+  envelope: Adsr::PLUCK,
+  vibrato: Vibrato::NONE,
+};
+
+pub static ORGAN: Instrument = Instrument {
+  name: "organ",
+  table: &ORGAN_TABLE,
+  envelope: Adsr::ORGAN,
+  vibrato: Vibrato::NONE,
+};
+
+pub static GUITAR: Instrument = Instrument {
+  name: "guitar",
+  table: &SAW,
+  envelope: Adsr::PLUCK,
+  vibrato: Vibrato::NONE,
+};
+
+pub static BASS: Instrument = Instrument {
+  name: "bass",
+  table: &PIANO_TABLE,
+  envelope: Adsr::PLUCK,
+  vibrato: Vibrato::NONE,
+};
+
+pub static STRINGS: Instrument = Instrument {
+  name: "strings",
+  table: &SAW,
+  envelope: Adsr {
+    attack: 40,
+    decay: 200,
+    sustain: U8F8::from_bits(7 << 5),
+    release: 120,
+  },
+  vibrato: Vibrato::new(5, 15, 250),
+};
+
+pub static BRASS: Instrument = Instrument {
+  name: "brass",
+  table: &SAW,
+  envelope: Adsr {
+    attack: 30,
+    decay: 100,
+    sustain: U8F8::from_bits(3 << 6),
+    release: 60,
+  },
+  vibrato: Vibrato::new(5, 10, 400),
+};
+
+pub static REED: Instrument = Instrument {
+  name: "reed",
+  table: &SQUARE,
+  envelope: Adsr {
+    attack: 20,
+    decay: 0,
+    sustain: U8F8::ONE,
+    release: 50,
+  },
+  vibrato: Vibrato::new(5, 10, 300),
+};
+
+pub static FLUTE: Instrument = Instrument {
+  name: "flute",
+  table: &FLUTE_TABLE,
+  envelope: Adsr {
+    attack: 40,
+    decay: 0,
+    sustain: U8F8::ONE,
+    release: 80,
+  },
+  vibrato: Vibrato::new(5, 20, 200),
+};
+
+pub static LEAD: Instrument = Instrument {
+  name: "lead",
+  table: &SQUARE,
+  envelope: Adsr::ORGAN,
+  vibrato: Vibrato::new(6, 20, 150),
+};
+
+pub static PAD: Instrument = Instrument {
+  name: "pad",
+  table: &SAW,
+  envelope: Adsr::PAD,
+  vibrato: Vibrato::new(4, 8, 0),
+};
+
+// the instrument of each family of eight programs
+static FAMILIES: [&Instrument; 16] = [
+  &PIANO,   // piano
+  &MALLET,  // chromatic percussion
+  &ORGAN,   // organ
+  &GUITAR,  // guitar
+  &BASS,    // bass
+  &STRINGS, // strings
+  &STRINGS, // ensemble
+  &BRASS,   // brass
+  &REED,    // reed
+  &FLUTE,   // pipe
+  &LEAD,    // synth lead
+  &PAD,     // synth pad
+  &PAD,     // synth effects
+  &GUITAR,  // ethnic
+  &MALLET,  // percussive
+  &MALLET,  // sound effects
+];
+
+// the instrument for a general midi program, 0 to 127
+pub fn for_program(program: u8) -> &'static Instrument {
+  FAMILIES[(program.min(127) / 8) as usize]
+}
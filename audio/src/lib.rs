@@ -0,0 +1,33 @@
+#![cfg_attr(not(test), no_std)]
+
+// hardware independent audio code shared by the apps. nothing in here
+// touches the peripherals directly, so it also builds and runs its
+// tests on the host.
+
+pub mod adpcm;
+pub mod agc;
+pub mod calibration;
+pub mod dsp;
+pub mod drums;
+pub mod dtmf;
+pub mod envelope;
+pub mod eq;
+pub mod goertzel;
+pub mod graph;
+pub mod instrument;
+#[cfg(feature = "midi")]
+pub mod midi;
+pub mod modem;
+pub mod noise_shaping;
+pub mod osc;
+pub mod pcm;
+pub mod player;
+pub mod render;
+pub mod resample;
+pub mod stats;
+pub mod synth;
+pub mod tempo;
+#[cfg(test)]
+mod test_util;
+pub mod tone;
+pub mod wav;
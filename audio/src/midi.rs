@@ -0,0 +1,548 @@
+// reads the events of a standard midi file in the order they are due,
+// following all its tracks at once. the file is checked when it's
+// loaded, so nothing in it can make the player panic later on.
+
+use heapless::Vec;
+use midly::{
+  EventIter, Format, MetaMessage, MidiMessage, Timing, TrackEvent,
+  TrackEventKind,
+};
+
+use super::tempo::Division;
+
+pub const MAX_TRACKS: usize = 8;
+
+#[derive(Clone, Copy, Debug)]
+pub enum LoadError {
+  // midly can't read it
+  Parse(midly::ErrorKind),
+  // format 2, the tracks are separate songs that can't be played
+  // together
+  Sequential,
+  // more tracks with events than MAX_TRACKS
+  TooManyTracks(usize),
+  // no events to play
+  Empty,
+}
+
+impl LoadError {
+  // a number to show on the led matrix
+  pub fn code(&self) -> u8 {
+    match self {
+      LoadError::Parse(_) => 1,
+      LoadError::Sequential => 2,
+      LoadError::TooManyTracks(_) => 3,
+      LoadError::Empty => 4,
+    }
+  }
+}
+
+impl From<midly::Error> for LoadError {
+  fn from(e: midly::Error) -> Self {
+    LoadError::Parse(e.kind())
+  }
+}
+
+pub enum MidiEvent {
+  NoteOn(u8, u8),
+  NoteOff(u8),
+  ProgramChange(u8),
+  // controller, value
+  Controller(u8, u8),
+  // -8192 to 8191
+  PitchBend(i16),
+}
+
+pub enum NextMidiEvent {
+  // channel, event
+  Event(u8, MidiEvent),
+  // microseconds per quarter note from now on
+  Tempo(u32),
+  Finished,
+  Pending,
+}
+
+pub struct Midi {
+  tracks: Vec<EventIter<'static>, MAX_TRACKS>,
+  next_event: [Option<TrackEvent<'static>>; MAX_TRACKS],
+  ticks: [u32; MAX_TRACKS],
+  next_track: Option<(usize, u32)>,
+  pub division: Division,
+  // system exclusive messages in the file, which are skipped. the song
+  // still plays, but may sound different from what it was written for.
+  pub sysex_skipped: usize,
+}
+
+impl Midi {
+  pub fn load(bytes: &'static [u8]) -> Result<Self, LoadError> {
+    check_division(bytes)?;
+    let (header, midly_tracks) = midly::parse(bytes)?;
+    if header.format == Format::Sequential {
+      return Err(LoadError::Sequential);
+    }
+
+    let division = match header.timing {
+      Timing::Metrical(n) => Division::TicksPerQuarter(n.as_int()),
+      Timing::Timecode(fps, n) => Division::Timecode {
+        fps: fps.as_int(),
+        ticks_per_frame: n,
+      },
+    };
+
+    let mut tracks = Vec::new();
+    let mut found = 0;
+    let mut sysex_skipped = 0;
+    for track in midly_tracks {
+      let track = track?;
+      // zero length tracks, or ones with nothing for the player such
+      // as only a name, don't need a slot
+      if check_track(track.clone(), &mut sysex_skipped)? {
+        found += 1;
+        tracks.push(track).ok();
+      }
+    }
+    if found > MAX_TRACKS {
+      return Err(LoadError::TooManyTracks(found));
+    }
+    if tracks.is_empty() {
+      return Err(LoadError::Empty);
+    }
+
+    let mut this = Self {
+      tracks,
+      next_event: [None; MAX_TRACKS],
+      ticks: [0; MAX_TRACKS],
+      next_track: None,
+      division,
+      sysex_skipped,
+    };
+    for i in 0..this.tracks.len() {
+      this.next_event[i] = this.read_event(i);
+    }
+    this.update_next_track();
+    Ok(this)
+  }
+
+  pub fn tracks(&self) -> usize {
+    self.tracks.len()
+  }
+
+  // the events were all read once by check_track, so they can't fail
+  // now. the end of track is the last event of a track, whatever comes
+  // after it.
+  fn read_event(&mut self, track: usize) -> Option<TrackEvent<'static>> {
+    self.tracks[track].next().and_then(Result::ok)
+  }
+
+  fn update_next_track(&mut self) {
+    let mut earliest_tick = u32::MAX;
+    let mut earliest_track = None;
+
+    for i in 0..self.tracks.len() {
+      if let Some(event) = self.next_event[i].as_ref() {
+        let delta = event.delta.as_int();
+        let event_tick = self.ticks[i].saturating_add(delta);
+        if event_tick <= earliest_tick {
+          earliest_tick = event_tick;
+          earliest_track = Some(i);
+        }
+      }
+    }
+
+    self.next_track = earliest_track.map(|i| (i, earliest_tick));
+  }
+
+  fn next_event(&mut self) -> Option<TrackEvent<'static>> {
+    let (i, tick) = self.next_track.take()?;
+    let event = self.next_event[i].take()?;
+    self.next_event[i] = match event.kind {
+      TrackEventKind::Meta(MetaMessage::EndOfTrack) => None,
+      _ => self.read_event(i),
+    };
+    self.ticks[i] = tick;
+    self.update_next_track();
+    Some(event)
+  }
+
+  pub fn next_midi_event(&mut self, tick: u32) -> NextMidiEvent {
+    loop {
+      let Some((_next_track, next_tick)) = self.next_track else {
+        return NextMidiEvent::Finished;
+      };
+
+      if next_tick > tick {
+        return NextMidiEvent::Pending;
+      }
+
+      let Some(event) = self.next_event() else {
+        return NextMidiEvent::Finished;
+      };
+
+      if let TrackEventKind::Meta(MetaMessage::Tempo(tempo)) = event.kind {
+        return NextMidiEvent::Tempo(tempo.as_int());
+      }
+
+      if let TrackEventKind::Midi { message, channel } = event.kind {
+        use MidiEvent::{
+          Controller, NoteOff, NoteOn, PitchBend, ProgramChange,
+        };
+        let event = match message {
+          MidiMessage::NoteOn { key, vel } if vel.as_int() == 0 => {
+            NoteOff(key.as_int())
+          }
+          MidiMessage::NoteOn { key, vel } => {
+            NoteOn(key.as_int(), vel.as_int())
+          }
+
+          MidiMessage::NoteOff { key, .. } => NoteOff(key.as_int()),
+          MidiMessage::ProgramChange { program } => {
+            ProgramChange(program.as_int())
+          }
+          MidiMessage::Controller { controller, value } => {
+            Controller(controller.as_int(), value.as_int())
+          }
+          MidiMessage::PitchBend { bend } => PitchBend(bend.as_int()),
+          _ => continue,
+        };
+
+        return NextMidiEvent::Event(channel.as_int(), event);
+      }
+    }
+  }
+}
+
+// midly negates the smpte format of the division as an i8, which
+// overflows for 0x80 and panics in a debug build. only the division of
+// the header chunk midly reads is checked, bytes that look like one
+// inside a track are just data.
+fn check_division(bytes: &[u8]) -> Result<(), LoadError> {
+  let overflows = header_chunk(bytes)
+    .is_some_and(|header| header.len() >= 6 && header[4] == 0x80);
+  if overflows {
+    let e = midly::ErrorKind::Invalid("invalid smpte fps");
+    return Err(LoadError::Parse(e));
+  }
+  Ok(())
+}
+
+// finds the header chunk the way midly does: in the data chunk of an
+// RMID file, after skipping any unknown chunks. None if midly fails
+// before it gets to the division.
+fn header_chunk(bytes: &[u8]) -> Option<&[u8]> {
+  let mut smf = match bytes.get(..4)? {
+    b"RIFF" => {
+      let (_, riff) = riff_chunks(bytes).next()?;
+      let riff = riff.strip_prefix(b"RMID")?;
+      riff_chunks(riff).find(|(id, _)| id == b"data")?.1
+    }
+    b"MThd" => bytes,
+    _ => return None,
+  };
+
+  // smf chunk lengths are big endian
+  while smf.len() >= 8 {
+    let len = u32::from_be_bytes([smf[4], smf[5], smf[6], smf[7]]);
+    let (id, body, rest) = split_chunk(smf, len);
+    match id {
+      b"MThd" => return Some(body),
+      b"MTrk" => return None,
+      _ => smf = rest,
+    }
+  }
+  None
+}
+
+// the chunks of a riff file, with little endian lengths and bodies
+// padded to an even length
+fn riff_chunks(mut bytes: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+  core::iter::from_fn(move || {
+    if bytes.len() < 8 {
+      return None;
+    }
+    let len = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+    let (id, body, rest) = split_chunk(bytes, len);
+    bytes = rest.get(len as usize % 2..).unwrap_or(&[]);
+    Some((id, body))
+  })
+}
+
+// the id, body and what follows a chunk of at least 8 bytes. a body
+// that runs past the end of the file is cut short, like midly does.
+fn split_chunk(bytes: &[u8], len: u32) -> (&[u8], &[u8], &[u8]) {
+  let body = &bytes[8..];
+  let (body, rest) = body.split_at((len as usize).min(body.len()));
+  (&bytes[..4], body, rest)
+}
+
+// reads the whole track up to its end, returns whether there's
+// anything to play in it. system exclusive messages are skipped like
+// the build script does and counted in sysex_skipped, most songs only
+// have them for the GM or GS reset at the start, which the synth
+// doesn't need.
+fn check_track(
+  events: EventIter<'static>,
+  sysex_skipped: &mut usize,
+) -> Result<bool, LoadError> {
+  let mut has_events = false;
+  for event in events {
+    match event?.kind {
+      TrackEventKind::Meta(MetaMessage::EndOfTrack) => break,
+      TrackEventKind::Midi { .. }
+      | TrackEventKind::Meta(MetaMessage::Tempo(_)) => has_events = true,
+      TrackEventKind::SysEx(_) => *sysex_skipped += 1,
+      _ => {}
+    }
+  }
+  Ok(has_events)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{boxed::Box, vec, vec::Vec};
+
+  use super::*;
+  use crate::test_util::Random;
+
+  const END_OF_TRACK: &[u8] = &[0x00, 0xff, 0x2f, 0x00];
+  // the GM system on message
+  const GM_RESET: &[u8] = &[0x00, 0xf0, 0x05, 0x7e, 0x7f, 0x09, 0x01, 0xf7];
+  const NOTE: &[u8] = &[0x00, 0x90, 60, 100, 0x60, 0x80, 60, 0];
+
+  fn smf(format: u16, tracks: &[&[u8]]) -> &'static [u8] {
+    let mut bytes = b"MThd\0\0\0\x06".to_vec();
+    bytes.extend_from_slice(&format.to_be_bytes());
+    bytes.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(&96u16.to_be_bytes());
+    for track in tracks {
+      bytes.extend_from_slice(b"MTrk");
+      bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+      bytes.extend_from_slice(track);
+    }
+    Box::leak(bytes.into_boxed_slice())
+  }
+
+  fn track(parts: &[&[u8]]) -> Vec<u8> {
+    parts.concat()
+  }
+
+  // every event of the song, as the player would see it
+  fn play(midi: &mut Midi) -> Vec<NextMidiEvent> {
+    let mut events = Vec::new();
+    loop {
+      match midi.next_midi_event(u32::MAX) {
+        NextMidiEvent::Finished => return events,
+        event => events.push(event),
+      }
+    }
+  }
+
+  fn load_error(bytes: &'static [u8]) -> u8 {
+    match Midi::load(bytes) {
+      Ok(_) => 0,
+      Err(e) => e.code(),
+    }
+  }
+
+  #[test]
+  fn plays_a_note() {
+    let mut midi =
+      Midi::load(smf(0, &[&track(&[NOTE, END_OF_TRACK])])).unwrap();
+    let events = play(&mut midi);
+    assert!(matches!(
+      events[..],
+      [
+        NextMidiEvent::Event(0, MidiEvent::NoteOn(60, 100)),
+        NextMidiEvent::Event(0, MidiEvent::NoteOff(60)),
+      ]
+    ));
+  }
+
+  #[test]
+  fn rejects_format_2() {
+    let song = track(&[NOTE, END_OF_TRACK]);
+    assert!(matches!(
+      Midi::load(smf(2, &[&song, &song])),
+      Err(LoadError::Sequential)
+    ));
+  }
+
+  #[test]
+  fn rejects_more_than_8_tracks() {
+    let song = track(&[NOTE, END_OF_TRACK]);
+    let tracks = vec![&song[..]; MAX_TRACKS + 1];
+    assert!(matches!(
+      Midi::load(smf(1, &tracks)),
+      Err(LoadError::TooManyTracks(9))
+    ));
+    assert_eq!(load_error(smf(1, &tracks[..MAX_TRACKS])), 0);
+  }
+
+  #[test]
+  fn tracks_without_events_take_no_slot() {
+    let song = track(&[NOTE, END_OF_TRACK]);
+    let name = track(&[&[0x00, 0xff, 0x03, 0x01, b'x'], END_OF_TRACK]);
+    let mut tracks = vec![&[][..], &name[..]];
+    tracks.extend(vec![&song[..]; MAX_TRACKS]);
+    let midi = Midi::load(smf(1, &tracks)).unwrap();
+    assert_eq!(midi.tracks(), MAX_TRACKS);
+
+    assert!(matches!(
+      Midi::load(smf(1, &[&[], &name])),
+      Err(LoadError::Empty)
+    ));
+  }
+
+  #[test]
+  fn a_missing_end_of_track_ends_at_the_chunk() {
+    let mut midi = Midi::load(smf(0, &[NOTE])).unwrap();
+    assert_eq!(play(&mut midi).len(), 2);
+  }
+
+  #[test]
+  fn ignores_events_after_the_end_of_track() {
+    let song = track(&[NOTE, END_OF_TRACK, NOTE]);
+    let mut midi = Midi::load(smf(0, &[&song])).unwrap();
+    assert_eq!(play(&mut midi).len(), 2);
+  }
+
+  #[test]
+  fn skips_and_counts_system_exclusive_messages() {
+    let song = track(&[GM_RESET, NOTE, END_OF_TRACK]);
+    let mut midi = Midi::load(smf(0, &[&song])).unwrap();
+    assert_eq!(midi.sysex_skipped, 1);
+    assert_eq!(play(&mut midi).len(), 2);
+
+    // also in a track with nothing to play, but not after the end
+    let setup = track(&[GM_RESET, GM_RESET, END_OF_TRACK, GM_RESET]);
+    let midi = Midi::load(smf(1, &[&setup, &song])).unwrap();
+    assert_eq!(midi.tracks(), 1);
+    assert_eq!(midi.sysex_skipped, 3);
+
+    let plain = track(&[NOTE, END_OF_TRACK]);
+    assert_eq!(Midi::load(smf(0, &[&plain])).unwrap().sysex_skipped, 0);
+  }
+
+  #[test]
+  fn tracks_play_together() {
+    let first = track(&[NOTE, END_OF_TRACK]);
+    let second =
+      track(&[&[0x30, 0x91, 64, 90, 0x60, 0x91, 64, 0], END_OF_TRACK]);
+    let mut midi = Midi::load(smf(1, &[&first, &second])).unwrap();
+
+    let mut keys = Vec::new();
+    for tick in 0..0xa0 {
+      while let NextMidiEvent::Event(_, event) = midi.next_midi_event(tick) {
+        match event {
+          MidiEvent::NoteOn(key, _) => keys.push((tick, key, true)),
+          MidiEvent::NoteOff(key) => keys.push((tick, key, false)),
+          _ => {}
+        }
+      }
+    }
+    assert_eq!(
+      keys,
+      [
+        (0x00, 60, true),
+        (0x30, 64, true),
+        (0x60, 60, false),
+        (0x90, 64, false)
+      ]
+    );
+  }
+
+  fn leak(bytes: Vec<u8>) -> &'static [u8] {
+    Box::leak(bytes.into_boxed_slice())
+  }
+
+  // the song in the data chunk of a riff file, with an odd length info
+  // chunk before it
+  fn rmid(smf: &[u8]) -> &'static [u8] {
+    fn chunk(out: &mut Vec<u8>, id: &[u8], body: &[u8]) {
+      out.extend_from_slice(id);
+      out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+      out.extend_from_slice(body);
+      if body.len() % 2 == 1 {
+        out.push(0);
+      }
+    }
+
+    let mut body = b"RMID".to_vec();
+    chunk(&mut body, b"LIST", b"INFOINAM\x01\0\0\0x");
+    chunk(&mut body, b"data", smf);
+    let mut file = Vec::new();
+    chunk(&mut file, b"RIFF", &body);
+    leak(file)
+  }
+
+  #[test]
+  fn rejects_the_smpte_format_midly_cant_negate() {
+    let song = track(&[NOTE, END_OF_TRACK]);
+    let mut bytes = smf(0, &[&song]).to_vec();
+    bytes[12] = 0x80;
+    assert_eq!(load_error(leak(bytes.clone())), 1);
+
+    // also behind an unknown chunk, and inside a riff file
+    let mut unknown = b"MTxx\0\0\0\x02ab".to_vec();
+    unknown.extend_from_slice(&bytes);
+    assert_eq!(load_error(leak(unknown)), 1);
+    assert_eq!(load_error(rmid(&bytes)), 1);
+    assert_eq!(load_error(rmid(smf(0, &[&song]))), 0);
+  }
+
+  #[test]
+  fn a_header_inside_a_track_is_just_data() {
+    // a text event holding what looks like a header with that division
+    let mut text = vec![0x00, 0xff, 0x01, 14];
+    text.extend_from_slice(b"MThd\0\0\0\x06\0\0\0\x01\x80\x18");
+    let song = track(&[&text, NOTE, END_OF_TRACK]);
+
+    let mut midi = Midi::load(smf(0, &[&song])).unwrap();
+    assert_eq!(play(&mut midi).len(), 2);
+    assert!(Midi::load(rmid(smf(1, &[&song, &song]))).is_ok());
+  }
+
+  // loads random and mangled files, and plays the ones that load. none
+  // of them may panic.
+  #[test]
+  fn survives_random_files() {
+    let song = track(&[
+      GM_RESET,
+      &[0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20],
+      &[
+        0x00, 0xc0, 0x05, 0x00, 0xb0, 0x07, 0x64, 0x00, 0xe0, 0x00, 0x50,
+      ],
+      NOTE,
+      END_OF_TRACK,
+    ]);
+    let valid = smf(1, &[&song, &song]).to_vec();
+    let mut random = Random::new(0x1234_5678);
+
+    for round in 0..200_000 {
+      let mut bytes = valid.clone();
+      if round % 8 == 1 {
+        bytes = rmid(&bytes).to_vec();
+      }
+      if round % 4 == 0 {
+        let len = random.next() as usize % 96;
+        bytes.truncate(14);
+        bytes.extend((0..len).map(|_| random.next() as u8));
+      } else {
+        for _ in 0..1 + random.next() % 4 {
+          let at = random.next() as usize % bytes.len();
+          match random.next() % 3 {
+            0 => bytes[at] = random.next() as u8,
+            1 => bytes.truncate(at),
+            _ => bytes.insert(at, random.next() as u8),
+          }
+          if bytes.is_empty() {
+            break;
+          }
+        }
+      }
+
+      if let Ok(mut midi) = Midi::load(leak(bytes)) {
+        play(&mut midi);
+      }
+    }
+  }
+}
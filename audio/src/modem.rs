@@ -0,0 +1,615 @@
+// an acoustic modem: binary fsk between the speaker of one board and
+// the microphone of another.
+//
+// a frame on air is: preamble, sync word, header, length, payload and
+// a crc-16. the header holds the ack flag and the sequence number of
+// the stop-and-wait arq.
+
+use heapless::Vec;
+
+use super::{goertzel::Goertzel, osc::Osc};
+
+// frequencies for bit 0 and 1. they are 8 times the baud rate apart,
+// so a bit long window tells them apart cleanly.
+pub const SPACE_FREQ: u32 = 2000;
+pub const MARK_FREQ: u32 = 2400;
+pub const BAUD: u32 = 50;
+
+pub const MAX_PAYLOAD: usize = 32;
+// header, length, payload, crc
+pub const MAX_FRAME: usize = MAX_PAYLOAD + 4;
+
+const PREAMBLE: [u8; 2] = [0x55, 0x55];
+const SYNC: u16 = 0x2dd4;
+const ACK_FLAG: u8 = 0x80;
+
+// the demodulator decides every quarter of a bit and searches for the
+// sync word in all four phases
+const SUBSTEPS: usize = 4;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+  pub ack: bool,
+  // 0..128
+  pub seq: u8,
+  pub payload: Vec<u8, MAX_PAYLOAD>,
+}
+
+impl Frame {
+  pub fn data(seq: u8, payload: &[u8]) -> Self {
+    let len = payload.len().min(MAX_PAYLOAD);
+    Self {
+      ack: false,
+      seq: seq & !ACK_FLAG,
+      payload: Vec::from_slice(&payload[..len]).unwrap(),
+    }
+  }
+
+  pub fn ack(seq: u8) -> Self {
+    Self {
+      ack: true,
+      seq: seq & !ACK_FLAG,
+      payload: Vec::new(),
+    }
+  }
+
+  // header, length, payload and crc
+  pub fn encode(&self) -> Vec<u8, MAX_FRAME> {
+    let mut bytes = Vec::new();
+    let header = self.seq | if self.ack { ACK_FLAG } else { 0 };
+    bytes.push(header).ok();
+    bytes.push(self.payload.len() as u8).ok();
+    bytes.extend_from_slice(&self.payload).ok();
+    let crc = crc16(&bytes);
+    bytes.extend_from_slice(&crc.to_be_bytes()).ok();
+    bytes
+  }
+
+  pub fn decode(bytes: &[u8]) -> Result<Self, FrameError> {
+    if bytes.len() < 4 {
+      return Err(FrameError::Truncated);
+    }
+
+    let len = bytes[1] as usize;
+    if len > MAX_PAYLOAD {
+      return Err(FrameError::TooLong);
+    }
+    if bytes.len() < len + 4 {
+      return Err(FrameError::Truncated);
+    }
+
+    let (body, crc) = bytes[..len + 4].split_at(len + 2);
+    if crc16(body) != u16::from_be_bytes([crc[0], crc[1]]) {
+      return Err(FrameError::Crc);
+    }
+
+    Ok(Self {
+      ack: body[0] & ACK_FLAG != 0,
+      seq: body[0] & !ACK_FLAG,
+      payload: Vec::from_slice(&body[2..]).unwrap(),
+    })
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameError {
+  Truncated,
+  TooLong,
+  Crc,
+}
+
+// crc-16/ccitt-false
+pub fn crc16(bytes: &[u8]) -> u16 {
+  let mut crc = 0xffffu16;
+  for &b in bytes {
+    crc ^= (b as u16) << 8;
+    for _ in 0..8 {
+      crc = if crc & 0x8000 != 0 {
+        (crc << 1) ^ 0x1021
+      } else {
+        crc << 1
+      };
+    }
+  }
+  crc
+}
+
+// turns frames into a continuous phase fsk signal
+pub struct Modulator {
+  osc: Osc,
+  samples_per_bit: u32,
+  // preamble, sync and frame
+  bytes: Vec<u8, { MAX_FRAME + 5 }>,
+  // the bit being played
+  bit: usize,
+  remaining: u32,
+}
+
+impl Modulator {
+  pub fn new(sample_rate: u32) -> Self {
+    Self {
+      osc: Osc::new(sample_rate),
+      samples_per_bit: sample_rate / BAUD,
+      bytes: Vec::new(),
+      bit: 0,
+      remaining: 0,
+    }
+  }
+
+  pub fn send(&mut self, frame: &Frame) {
+    self.bytes.clear();
+    self.bytes.extend_from_slice(&PREAMBLE).ok();
+    self.bytes.extend_from_slice(&SYNC.to_be_bytes()).ok();
+    self.bytes.extend_from_slice(&frame.encode()).ok();
+    // a trailing byte, so the last bit of the crc isn't cut short when
+    // the speaker stops
+    self.bytes.push(0).ok();
+    self.bit = 0;
+    self.remaining = 0;
+  }
+
+  pub fn is_busy(&self) -> bool {
+    self.bit < self.bytes.len() * 8 || self.remaining > 0
+  }
+
+  // Q15 sample, silence when there is nothing to send
+  pub fn next_sample(&mut self) -> i16 {
+    if self.remaining == 0 {
+      if self.bit >= self.bytes.len() * 8 {
+        return 0;
+      }
+
+      let byte = self.bytes[self.bit / 8];
+      let bit = byte >> (7 - self.bit % 8) & 1;
+      self
+        .osc
+        .set_freq(if bit == 1 { MARK_FREQ } else { SPACE_FREQ });
+      self.bit += 1;
+      self.remaining = self.samples_per_bit;
+    }
+
+    self.remaining -= 1;
+    self.osc.next_sample()
+  }
+}
+
+enum RxState {
+  // looking for the sync word in any of the phases
+  Hunting,
+  // the sync word showed up in `count` neighboring phases starting
+  // from `first`. the middle one is the best aligned with the bits.
+  Syncing {
+    first: usize,
+    count: usize,
+  },
+  // reading bytes at the phase the sync word was found in
+  Receiving {
+    phase: usize,
+    bytes: Vec<u8, MAX_FRAME>,
+    bits: u8,
+    byte: u8,
+  },
+}
+
+// turns microphone samples back into frames
+pub struct Demodulator {
+  space: Goertzel,
+  mark: Goertzel,
+  // the number of samples in a quarter bit
+  substep_len: usize,
+  // the samples of the current quarter bit seen so far
+  filled: usize,
+  // mark minus space energy of the last quarter bits
+  history: [i64; SUBSTEPS],
+  substep: usize,
+  // the bits received in each phase, newest in the lowest bit
+  shift: [u16; SUBSTEPS],
+  state: RxState,
+}
+
+impl Demodulator {
+  pub fn new(sample_rate: u32) -> Self {
+    Self {
+      space: Goertzel::new(SPACE_FREQ, sample_rate),
+      mark: Goertzel::new(MARK_FREQ, sample_rate),
+      substep_len: (sample_rate / BAUD) as usize / SUBSTEPS,
+      filled: 0,
+      history: [0; SUBSTEPS],
+      substep: 0,
+      shift: [0; SUBSTEPS],
+      state: RxState::Hunting,
+    }
+  }
+
+  pub fn reset(&mut self) {
+    self.space.reset();
+    self.mark.reset();
+    self.filled = 0;
+    self.history = [0; SUBSTEPS];
+    self.shift = [0; SUBSTEPS];
+    self.state = RxState::Hunting;
+  }
+
+  // feed samples, returns a frame as soon as one is complete
+  pub fn process(
+    &mut self,
+    mut samples: &[i16],
+  ) -> Option<Result<Frame, FrameError>> {
+    let mut result = None;
+
+    while !samples.is_empty() {
+      let n = (self.substep_len - self.filled).min(samples.len());
+      self.space.process(&samples[..n]);
+      self.mark.process(&samples[..n]);
+      self.filled += n;
+      samples = &samples[n..];
+
+      if self.filled == self.substep_len {
+        // the last frame of the block wins, two frames in one block
+        // can't happen at this baud rate
+        if let Some(r) = self.end_substep() {
+          result = Some(r);
+        }
+      }
+    }
+
+    result
+  }
+
+  fn end_substep(&mut self) -> Option<Result<Frame, FrameError>> {
+    let diff = self.mark.power() as i64 - self.space.power() as i64;
+    self.space.reset();
+    self.mark.reset();
+    self.filled = 0;
+
+    let phase = self.substep % SUBSTEPS;
+    self.history[phase] = diff;
+    self.substep = self.substep.wrapping_add(1);
+
+    // the last four quarters make up a bit ending at this phase
+    let bit = (self.history.iter().sum::<i64>() > 0) as u8;
+
+    match &mut self.state {
+      RxState::Hunting => {
+        self.shift[phase] = self.shift[phase] << 1 | bit as u16;
+        if self.shift[phase] == SYNC {
+          self.state = RxState::Syncing {
+            first: phase,
+            count: 1,
+          };
+        }
+        None
+      }
+      RxState::Syncing { first, count } => {
+        self.shift[phase] = self.shift[phase] << 1 | bit as u16;
+        let synced = self.shift[phase] == SYNC;
+        if synced {
+          *count += 1;
+        }
+
+        if !synced || *count == SUBSTEPS {
+          let phase = (*first + (*count - 1) / 2) % SUBSTEPS;
+          self.state = RxState::Receiving {
+            phase,
+            bytes: Vec::new(),
+            bits: 0,
+            byte: 0,
+          };
+        }
+        None
+      }
+      RxState::Receiving {
+        phase: rx_phase,
+        bytes,
+        bits,
+        byte,
+      } => {
+        if *rx_phase != phase {
+          return None;
+        }
+
+        *byte = *byte << 1 | bit;
+        *bits += 1;
+        if *bits < 8 {
+          return None;
+        }
+
+        bytes.push(*byte).ok();
+        *bits = 0;
+        *byte = 0;
+
+        let done = match bytes.get(1) {
+          Some(&len) if len as usize > MAX_PAYLOAD => true,
+          Some(&len) => bytes.len() >= len as usize + 4,
+          None => false,
+        };
+
+        if !done {
+          return None;
+        }
+
+        let result = Frame::decode(bytes);
+        self.shift = [0; SUBSTEPS];
+        self.state = RxState::Hunting;
+        Some(result)
+      }
+    }
+  }
+}
+
+// how many times a frame is sent before giving up
+const MAX_ATTEMPTS: u8 = 4;
+
+#[derive(Debug, PartialEq)]
+pub enum ArqEvent {
+  // transmit this frame
+  Transmit(Frame),
+  // a new payload arrived
+  Received(Vec<u8, MAX_PAYLOAD>),
+  // the other side got our frame
+  Delivered,
+  // no ack after MAX_ATTEMPTS
+  Failed,
+}
+
+// stop-and-wait arq. the caller transmits the frames it's asked to
+// and reports the frames it receives and the time passing.
+pub struct Arq {
+  // ms to wait for an ack before sending again
+  timeout_ms: u32,
+  next_seq: u8,
+  pending: Option<Frame>,
+  attempts: u8,
+  waited_ms: u32,
+  // the seq of the last payload handed to the app, to drop repeats
+  last_received: Option<u8>,
+}
+
+impl Arq {
+  pub fn new(timeout_ms: u32) -> Self {
+    Self {
+      timeout_ms,
+      next_seq: 0,
+      pending: None,
+      attempts: 0,
+      waited_ms: 0,
+      last_received: None,
+    }
+  }
+
+  pub fn is_busy(&self) -> bool {
+    self.pending.is_some()
+  }
+
+  // None when the previous payload isn't delivered yet
+  pub fn send(&mut self, payload: &[u8]) -> Option<ArqEvent> {
+    if self.pending.is_some() {
+      return None;
+    }
+
+    let frame = Frame::data(self.next_seq, payload);
+    self.next_seq = (self.next_seq + 1) & !ACK_FLAG;
+    self.pending = Some(frame.clone());
+    self.attempts = 1;
+    self.waited_ms = 0;
+    Some(ArqEvent::Transmit(frame))
+  }
+
+  pub fn on_frame(&mut self, frame: Frame) -> Vec<ArqEvent, 2> {
+    let mut events = Vec::new();
+
+    if frame.ack {
+      if self.pending.as_ref().map(|f| f.seq) == Some(frame.seq) {
+        self.pending = None;
+        events.push(ArqEvent::Delivered).ok();
+      }
+      return events;
+    }
+
+    // always ack, our previous ack may have been lost
+    events.push(ArqEvent::Transmit(Frame::ack(frame.seq))).ok();
+
+    if self.last_received != Some(frame.seq) {
+      self.last_received = Some(frame.seq);
+      events.push(ArqEvent::Received(frame.payload)).ok();
+    }
+
+    events
+  }
+
+  pub fn tick(&mut self, elapsed_ms: u32) -> Option<ArqEvent> {
+    let frame = self.pending.as_ref()?;
+
+    self.waited_ms += elapsed_ms;
+    if self.waited_ms < self.timeout_ms {
+      return None;
+    }
+
+    if self.attempts >= MAX_ATTEMPTS {
+      self.pending = None;
+      return Some(ArqEvent::Failed);
+    }
+
+    self.attempts += 1;
+    self.waited_ms = 0;
+    Some(ArqEvent::Transmit(frame.clone()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::vec::Vec;
+
+  use super::*;
+  use crate::test_util::Random;
+
+  // the microphone's rate, and roughly the speaker's
+  const MIC_RATE: u32 = 8000;
+  const SPEAKER_RATE: u32 = 44117;
+  const BLOCK_LEN: usize = 64;
+
+  // the frame as played by the speaker at `rate`
+  fn modulate(frame: &Frame, rate: u32) -> Vec<i16> {
+    let mut modulator = Modulator::new(rate);
+    modulator.send(frame);
+    let mut samples = Vec::new();
+    while modulator.is_busy() {
+      samples.push(modulator.next_sample());
+    }
+    samples
+  }
+
+  // what the microphone hears: the signal resampled to its rate after
+  // `delay` samples of silence, scaled down by `attenuation` with noise
+  // on top
+  fn air(
+    samples: &[i16],
+    rate: u32,
+    delay: usize,
+    attenuation: i32,
+    noise: i32,
+  ) -> Vec<i16> {
+    let mut rng = Random::new(0x1234_5678 ^ delay as u32);
+    let len = samples.len() as u64 * MIC_RATE as u64 / rate as u64;
+    let resampled = (0..len as usize)
+      .map(|i| samples[(i as u64 * rate as u64 / MIC_RATE as u64) as usize]);
+    std::iter::repeat_n(0, delay)
+      .chain(resampled)
+      .chain(std::iter::repeat_n(0, 400))
+      .map(|x| (x as i32 / attenuation + rng.noise(noise)) as i16)
+      .collect()
+  }
+
+  fn demodulate(samples: &[i16]) -> Vec<Result<Frame, FrameError>> {
+    let mut demodulator = Demodulator::new(MIC_RATE);
+    samples
+      .chunks(BLOCK_LEN)
+      .filter_map(|block| demodulator.process(block))
+      .collect()
+  }
+
+  #[test]
+  fn crc_matches_the_check_value() {
+    assert_eq!(crc16(b"123456789"), 0x29b1);
+  }
+
+  #[test]
+  fn frames_round_trip() {
+    for frame in [
+      Frame::data(5, b"hello"),
+      Frame::data(127, &[0xff; MAX_PAYLOAD]),
+      Frame::data(0, &[]),
+      Frame::ack(42),
+    ] {
+      assert_eq!(Frame::decode(&frame.encode()), Ok(frame));
+    }
+  }
+
+  #[test]
+  fn broken_frames_are_rejected() {
+    let bytes = Frame::data(1, b"abc").encode();
+    assert_eq!(Frame::decode(&bytes[..3]), Err(FrameError::Truncated));
+    assert_eq!(Frame::decode(&bytes[..6]), Err(FrameError::Truncated));
+
+    let mut corrupt = bytes.clone();
+    corrupt[3] ^= 0x10;
+    assert_eq!(Frame::decode(&corrupt), Err(FrameError::Crc));
+
+    let mut long = bytes;
+    long[1] = MAX_PAYLOAD as u8 + 1;
+    assert_eq!(Frame::decode(&long), Err(FrameError::TooLong));
+  }
+
+  #[test]
+  fn modulates_at_the_baud_rate() {
+    let frame = Frame::data(3, b"hi");
+    let samples = modulate(&frame, MIC_RATE);
+    // preamble, sync, frame and the trailing byte
+    let bits = (2 + 2 + frame.encode().len() + 1) * 8;
+    assert_eq!(samples.len(), bits * (MIC_RATE / BAUD) as usize);
+  }
+
+  #[test]
+  fn end_to_end_over_a_noisy_channel() {
+    let frame = Frame::data(9, b"the quick brown fox");
+    let sent = modulate(&frame, SPEAKER_RATE);
+
+    // the delays land at different points within a bit
+    for delay in [0, 13, 57, 101, 160] {
+      for (attenuation, noise) in [(1, 0), (4, 2000), (16, 1000), (32, 500)] {
+        let heard = air(&sent, SPEAKER_RATE, delay, attenuation, noise);
+        assert_eq!(
+          demodulate(&heard),
+          [Ok(frame.clone())],
+          "delay {}, 1/{} with noise {}",
+          delay,
+          attenuation,
+          noise
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn noise_alone_is_not_a_frame() {
+    let heard = air(&[0; 8000], MIC_RATE, 0, 1, 8000);
+    assert!(demodulate(&heard).iter().all(|r| r.is_err()));
+  }
+
+  #[test]
+  fn frames_back_to_back() {
+    let mut sent = modulate(&Frame::data(1, b"one"), MIC_RATE);
+    sent.extend(modulate(&Frame::ack(7), MIC_RATE));
+    let heard = air(&sent, MIC_RATE, 31, 4, 1000);
+    assert_eq!(
+      demodulate(&heard),
+      [Ok(Frame::data(1, b"one")), Ok(Frame::ack(7))]
+    );
+  }
+
+  fn transmitted(event: Option<ArqEvent>) -> Frame {
+    match event {
+      Some(ArqEvent::Transmit(frame)) => frame,
+      other => panic!("expected a frame, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn arq_delivers_once_and_acks_repeats() {
+    let mut sender = Arq::new(1000);
+    let mut receiver = Arq::new(1000);
+
+    let frame = transmitted(sender.send(b"ping"));
+    assert!(sender.send(b"pong").is_none());
+
+    // the first copy is lost, the second one makes it
+    assert_eq!(sender.tick(999), None);
+    assert_eq!(transmitted(sender.tick(1)), frame);
+    let events = receiver.on_frame(frame.clone());
+    assert_eq!(events[0], ArqEvent::Transmit(Frame::ack(frame.seq)));
+    assert_eq!(events[1], ArqEvent::Received(frame.payload.clone()));
+
+    // the ack is lost too, the repeat is acked but not handed on
+    let frame = transmitted(sender.tick(1000));
+    let events = receiver.on_frame(frame.clone());
+    assert_eq!(events[..], [ArqEvent::Transmit(Frame::ack(frame.seq))]);
+
+    let ack = Frame::ack(frame.seq);
+    assert_eq!(sender.on_frame(ack)[..], [ArqEvent::Delivered]);
+    assert!(!sender.is_busy());
+    assert_eq!(transmitted(sender.send(b"pong")).seq, frame.seq + 1);
+  }
+
+  #[test]
+  fn arq_gives_up() {
+    let mut arq = Arq::new(1000);
+    let frame = transmitted(arq.send(b"hello"));
+    // a stale ack doesn't count
+    assert!(arq.on_frame(Frame::ack(frame.seq + 1)).is_empty());
+    for _ in 1..MAX_ATTEMPTS {
+      assert_eq!(transmitted(arq.tick(1000)), frame);
+    }
+    assert_eq!(arq.tick(1000), Some(ArqEvent::Failed));
+    assert!(!arq.is_busy());
+  }
+}
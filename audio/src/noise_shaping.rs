@@ -0,0 +1,185 @@
+// the last step before the pwm: turning samples into duty values.
+//
+// the countertop leaves only a few bits of resolution, and rounding
+// each sample to a duty value adds noise evenly across the spectrum.
+// with error feedback the rounding error of each sample is subtracted
+// from the following ones, which moves the noise up to high
+// frequencies where the speaker and our ears don't pick it up. this is
+// the idea behind sigma-delta converters.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Order {
+  // plain rounding
+  None,
+  // the noise rises 6 dB per octave
+  First,
+  // the noise rises 12 dB per octave, even less of it is left at low
+  // frequencies but there's more of it in total
+  Second,
+}
+
+// the error is kept in 1/65536 of a duty step. when the signal clips
+// the error grows without bound, so it's limited to a couple of steps.
+const ERROR_LIMIT: i64 = 2 << 16;
+
+pub struct NoiseShaper {
+  order: Order,
+  // the errors of the last two duty values
+  e1: i64,
+  e2: i64,
+}
+
+impl NoiseShaper {
+  pub const fn new(order: Order) -> Self {
+    Self {
+      order,
+      e1: 0,
+      e2: 0,
+    }
+  }
+
+  pub fn set_order(&mut self, order: Order) {
+    self.order = order;
+    self.reset();
+  }
+
+  pub fn order(&self) -> Order {
+    self.order
+  }
+
+  pub fn reset(&mut self) {
+    self.e1 = 0;
+    self.e2 = 0;
+  }
+
+  // Q15 [-1, 1] to [0, countertop]
+  pub fn duty(&mut self, sample: i16, countertop: u16) -> u16 {
+    let top = countertop as i64;
+    let x = (sample as i64 + (1 << 15)) * top;
+
+    // the noise transfer function is (1 - z^-1)^order
+    let feedback = match self.order {
+      Order::None => 0,
+      Order::First => self.e1,
+      Order::Second => 2 * self.e1 - self.e2,
+    };
+    let wanted = x - feedback;
+    let duty = ((wanted + (1 << 15)) >> 16).clamp(0, top);
+
+    self.e2 = self.e1;
+    self.e1 = ((duty << 16) - wanted).clamp(-ERROR_LIMIT, ERROR_LIMIT);
+    duty as u16
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{f64::consts::PI, vec::Vec};
+
+  use super::*;
+
+  // the tone generator's pwm
+  const COUNTERTOP: u16 = 90;
+  const RATE: f64 = 44444.0;
+  const LEN: usize = 4444;
+
+  // the error of each duty value against the sample it stands for,
+  // with a 1 kHz tone at half of the full scale, relative to full scale
+  fn error(order: Order) -> Vec<f64> {
+    let mut shaper = NoiseShaper::new(order);
+    (0..LEN)
+      .map(|i| {
+        let x = 0.5 * (2.0 * PI * 1000.0 * i as f64 / RATE).sin();
+        let duty = shaper.duty((x * 32767.0) as i16, COUNTERTOP);
+        duty as f64 / COUNTERTOP as f64 * 2.0 - 1.0 - x
+      })
+      .collect()
+  }
+
+  // the power of the error between two frequencies in dB below a full
+  // scale sine, in 10 Hz bins
+  fn band_power(error: &[f64], from: f64, to: f64) -> f64 {
+    let n = error.len() as f64;
+    let mut power = 0.0;
+    let mut freq = from;
+    while freq < to {
+      let (mut re, mut im) = (0.0, 0.0);
+      for (i, e) in error.iter().enumerate() {
+        let angle = 2.0 * PI * freq * i as f64 / RATE;
+        re += e * angle.cos();
+        im += e * angle.sin();
+      }
+      // one sided, so twice the bin
+      power += 2.0 * (re * re + im * im) / (n * n);
+      freq += RATE / n;
+    }
+    10.0 * (power / 0.5).log10()
+  }
+
+  // the power of all of the error, in dB below a full scale sine
+  fn total_power(error: &[f64]) -> f64 {
+    let power = error.iter().map(|e| e * e).sum::<f64>() / error.len() as f64;
+    10.0 * (power / 0.5).log10()
+  }
+
+  // the figures in the readme
+  #[test]
+  fn moves_the_noise_out_of_the_audible_band() {
+    let none = error(Order::None);
+    let first = error(Order::First);
+    let second = error(Order::Second);
+
+    let audible = |error: &[f64]| band_power(error, 10.0, 4000.0);
+    let (none, first, second) = (
+      (audible(&none), total_power(&none)),
+      (audible(&first), total_power(&first)),
+      (audible(&second), total_power(&second)),
+    );
+    assert!((-48.0..-46.0).contains(&none.0), "{:.1} dB", none.0);
+    assert!((-59.0..-57.0).contains(&first.0), "{:.1} dB", first.0);
+    assert!((-67.0..-65.0).contains(&second.0), "{:.1} dB", second.0);
+
+    // there is more noise in total, it's just moved up
+    assert!(none.1 < first.1 && first.1 < second.1);
+  }
+
+  #[test]
+  fn maps_the_full_range() {
+    let mut shaper = NoiseShaper::new(Order::None);
+    assert_eq!(shaper.duty(i16::MIN, COUNTERTOP), 0);
+    assert_eq!(shaper.duty(0, COUNTERTOP), COUNTERTOP / 2);
+    assert_eq!(shaper.duty(i16::MAX, COUNTERTOP), COUNTERTOP);
+  }
+
+  #[test]
+  fn averages_to_the_sample() {
+    // a constant between two duty steps comes out as a mix of both
+    for order in [Order::First, Order::Second] {
+      let mut shaper = NoiseShaper::new(order);
+      let sample = 1234;
+      let sum: u32 = (0..9000)
+        .map(|_| shaper.duty(sample, COUNTERTOP) as u32)
+        .sum();
+      let mean = sum as f64 / 9000.0;
+      let wanted = (sample as f64 + 32768.0) * COUNTERTOP as f64 / 65536.0;
+      assert!((mean - wanted).abs() < 0.01, "{:?} {}", order, mean);
+    }
+  }
+
+  #[test]
+  fn the_error_stays_bounded_when_clipping() {
+    // a full scale square wave, the feedback asks for more than the
+    // pwm can do
+    let mut shaper = NoiseShaper::new(Order::Second);
+    for i in 0..1000 {
+      let x = if i / 20 % 2 == 0 { i16::MAX } else { i16::MIN };
+      shaper.duty(x, COUNTERTOP);
+      assert!(shaper.e1.abs() <= ERROR_LIMIT);
+    }
+
+    // and it recovers right away instead of paying the error back
+    shaper.duty(0, COUNTERTOP);
+    let duty = shaper.duty(0, COUNTERTOP) as i32;
+    assert!((duty - COUNTERTOP as i32 / 2).abs() <= 2, "{}", duty);
+  }
+}
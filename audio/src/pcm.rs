@@ -0,0 +1,82 @@
+use fixed::types::U8F8;
+
+use super::{
+  adpcm, dsp, eq::Equalizer, noise_shaping::NoiseShaper, resample::Resampler,
+  wav,
+};
+
+// random access to the samples of a clip
+pub trait SampleSource {
+  fn len(&self) -> usize;
+
+  fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  fn sample_at(&mut self, idx: usize) -> i16;
+}
+
+// unsigned 8-bit pcm
+impl SampleSource for &[u8] {
+  fn len(&self) -> usize {
+    <[u8]>::len(self)
+  }
+
+  fn sample_at(&mut self, idx: usize) -> i16 {
+    (self[idx] as i16 - 128) << 8
+  }
+}
+
+impl SampleSource for adpcm::Reader<'_> {
+  fn len(&self) -> usize {
+    adpcm::Reader::len(self)
+  }
+
+  fn sample_at(&mut self, idx: usize) -> i16 {
+    adpcm::Reader::sample_at(self, idx)
+  }
+}
+
+impl SampleSource for wav::Reader<'_> {
+  fn len(&self) -> usize {
+    wav::Reader::len(self)
+  }
+
+  fn sample_at(&mut self, idx: usize) -> i16 {
+    wav::Reader::sample_at(self, idx)
+  }
+}
+
+// playback parameters for decoding pcm data into pwm duty values
+pub struct PcmParams {
+  // the sample rate of the audio data
+  pub data_sample_rate: u32,
+  // the rate the pwm consumes the buffer at
+  pub target_sample_rate: u32,
+  // the duty value corresponding to the full positive swing
+  pub countertop: u16,
+  pub gain: U8F8,
+}
+
+// resample the data into duty values, returns the index of the sample
+// being played. it reaches data.len() when the data is played out,
+// after that the buffer is filled with silence.
+pub fn fill_samples<S: SampleSource>(
+  buffer: &mut [u16],
+  data: &mut S,
+  resampler: &mut Resampler,
+  equalizer: &mut Equalizer,
+  shaper: &mut NoiseShaper,
+  params: &PcmParams,
+) -> usize {
+  resampler.set_rates(params.data_sample_rate, params.target_sample_rate);
+
+  for cell in buffer.iter_mut() {
+    let sample = resampler.next_sample(data);
+    let sample = dsp::apply_gain(sample, params.gain);
+    let sample = equalizer.process(sample);
+    *cell = shaper.duty(sample, params.countertop);
+  }
+
+  resampler.position()
+}
@@ -0,0 +1,333 @@
+// plays a list of embedded wav clips: switching tracks, pausing and
+// seeking. the player is a source for the pwm sink, which fills the
+// buffers from the pwm interrupt. everything else is called by the app
+// in between.
+
+use fixed::types::U8F8;
+
+use super::{
+  dsp,
+  eq::Equalizer,
+  graph::AudioSource,
+  resample::{Interpolation, Resampler},
+  wav::{self, Wav, WavError},
+};
+
+pub struct Clip {
+  pub name: &'static str,
+  // the whole wav file
+  pub wav: &'static [u8],
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PlayerEvent {
+  // the track played out, the buffers are silent from now on
+  // or couldn't be played at all
+  EndOfClip(usize),
+}
+
+pub struct Player {
+  clips: &'static [Clip],
+  track: usize,
+  reader: Option<wav::Reader<'static>>,
+  sample_rate: u32,
+  // the rate the samples are played at
+  target_sample_rate: u32,
+  gain: U8F8,
+  resampler: Resampler,
+  equalizer: Equalizer,
+  // designed by the app, swapped in before the next buffer is filled
+  next_equalizer: Option<Equalizer>,
+  paused: bool,
+  ended: bool,
+  // waits for the app to take it
+  event: Option<PlayerEvent>,
+  // the clips that failed to play in a row
+  failed: usize,
+}
+
+impl Player {
+  pub const fn new(
+    clips: &'static [Clip],
+    target_sample_rate: u32,
+    interpolation: Interpolation,
+  ) -> Self {
+    Self {
+      clips,
+      track: 0,
+      reader: None,
+      sample_rate: 0,
+      target_sample_rate,
+      gain: U8F8::ONE,
+      resampler: Resampler::new(interpolation),
+      equalizer: Equalizer::new(),
+      next_equalizer: None,
+      paused: false,
+      ended: false,
+      event: None,
+      failed: 0,
+    }
+  }
+
+  pub fn clips(&self) -> &'static [Clip] {
+    self.clips
+  }
+
+  pub fn track(&self) -> usize {
+    self.track
+  }
+
+  // start playing the track from the beginning. a clip that can't be
+  // played ends right away, so the app moves on to the next one like
+  // it does at the end of a clip. once every clip has failed in a row
+  // the player stays quiet.
+  pub fn play(&mut self, track: usize) -> Result<(), WavError> {
+    let track = track % self.clips.len();
+    self.track = track;
+    self.reader = None;
+    self.ended = false;
+    self.event = None;
+
+    let wav = match Wav::parse(self.clips[track].wav) {
+      Ok(wav) => wav,
+      Err(e) => {
+        self.ended = true;
+        self.failed += 1;
+        if self.failed < self.clips.len() {
+          self.event = Some(PlayerEvent::EndOfClip(track));
+        }
+        return Err(e);
+      }
+    };
+    self.failed = 0;
+    let mut reader = wav.reader();
+    self.resampler.seek(&mut reader, 0);
+    self.sample_rate = wav.spec.sample_rate;
+    self.reader = Some(reader);
+    Ok(())
+  }
+
+  // the next and previous tracks wrap around the list
+  pub fn next_track(&mut self) -> Result<(), WavError> {
+    self.play(self.track + 1)
+  }
+
+  pub fn prev_track(&mut self) -> Result<(), WavError> {
+    self.play(self.track + self.clips.len() - 1)
+  }
+
+  // the filters to run the samples through, designed for the rate the
+  // buffers are filled at. the design takes too long for the
+  // interrupt, so the app does it whenever the rate changes.
+  pub fn set_equalizer(&mut self, equalizer: Equalizer) {
+    self.next_equalizer = Some(equalizer);
+  }
+
+  // the equalizer has to be designed again for the new rate
+  pub fn set_target_sample_rate(&mut self, target_sample_rate: u32) {
+    self.target_sample_rate = target_sample_rate;
+  }
+
+  pub fn set_gain(&mut self, gain: U8F8) {
+    self.gain = gain;
+  }
+
+  // the track that played out since the last call, if any
+  pub fn take_event(&mut self) -> Option<PlayerEvent> {
+    self.event.take()
+  }
+
+  pub fn toggle_pause(&mut self) {
+    self.paused = !self.paused;
+  }
+
+  pub fn is_paused(&self) -> bool {
+    self.paused
+  }
+
+  pub fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  pub fn position_ms(&self) -> u32 {
+    let len = self.reader.as_ref().map_or(0, |r| r.len());
+    // the resampler runs a little past the end into the silence
+    self.samples_to_ms(self.resampler.position().min(len))
+  }
+
+  pub fn duration_ms(&self) -> u32 {
+    self.samples_to_ms(self.reader.as_ref().map_or(0, |r| r.len()))
+  }
+
+  // jump to ms from the start of the track, clamped to its end
+  pub fn seek_ms(&mut self, ms: u32) {
+    let Some(reader) = self.reader.as_mut() else {
+      return;
+    };
+
+    let idx = (ms as u64 * self.sample_rate as u64 / 1000) as usize;
+    self.resampler.seek(reader, idx.min(reader.len()));
+    self.ended = false;
+  }
+
+  // jump forward or back by ms
+  pub fn skip_ms(&mut self, ms: i32) {
+    let pos = self.position_ms() as i32 + ms;
+    self.seek_ms(pos.max(0) as u32);
+  }
+
+  fn samples_to_ms(&self, samples: usize) -> u32 {
+    if self.sample_rate == 0 {
+      return 0;
+    }
+    (samples as u64 * 1000 / self.sample_rate as u64) as u32
+  }
+}
+
+impl AudioSource for Player {
+  fn fill(&mut self, samples: &mut [i16]) {
+    if let Some(equalizer) = self.next_equalizer.take() {
+      self.equalizer = equalizer;
+    }
+    let reader = match self.reader.as_mut() {
+      Some(reader) if !self.paused && !self.ended => reader,
+      _ => {
+        samples.fill(0);
+        return;
+      }
+    };
+
+    let resampler = &mut self.resampler;
+    resampler.set_rates(self.sample_rate, self.target_sample_rate);
+    for sample in samples.iter_mut() {
+      let x = dsp::apply_gain(resampler.next_sample(reader), self.gain);
+      *sample = self.equalizer.process(x);
+    }
+
+    if resampler.is_finished(reader.len()) {
+      self.ended = true;
+      self.event = Some(PlayerEvent::EndOfClip(self.track));
+    }
+  }
+
+  fn is_silent(&self) -> bool {
+    self.reader.is_none() || self.paused || self.ended
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::wav::{Format, Spec, MAX_HEADER_LEN};
+  use std::{boxed::Box, vec, vec::Vec};
+
+  const RATE: u32 = 8000;
+
+  // a 16-bit wav of len samples at 1000
+  fn clip(len: usize) -> Clip {
+    let spec = Spec {
+      format: Format::I16,
+      channels: 1,
+      sample_rate: RATE,
+    };
+    let mut wav = vec![0; MAX_HEADER_LEN];
+    let header_len = spec.write_header(2 * len as u32, &mut wav);
+    wav.truncate(header_len);
+    for _ in 0..len {
+      wav.extend_from_slice(&1000i16.to_le_bytes());
+    }
+    Clip {
+      name: "clip",
+      wav: Box::leak(wav.into_boxed_slice()),
+    }
+  }
+
+  fn player(lens: &[usize]) -> Player {
+    let clips: Vec<Clip> = lens.iter().map(|&len| clip(len)).collect();
+    let clips = Box::leak(clips.into_boxed_slice());
+    Player::new(clips, RATE, Interpolation::Nearest)
+  }
+
+  fn fill(player: &mut Player, len: usize) -> Vec<i16> {
+    let mut samples = vec![0; len];
+    player.fill(&mut samples);
+    samples
+  }
+
+  #[test]
+  fn plays_the_clip_with_gain() {
+    let mut player = player(&[RATE as usize]);
+    assert!(player.is_silent());
+    player.play(0).unwrap();
+    player.set_gain(U8F8::from_num(0.5));
+    assert!(!player.is_silent());
+    assert!(fill(&mut player, 100)[10..].iter().all(|&x| x == 500));
+    assert_eq!(player.position_ms(), 100 * 1000 / RATE);
+    assert_eq!(player.duration_ms(), 1000);
+  }
+
+  #[test]
+  fn end_of_clip_is_raised_once() {
+    let mut player = player(&[100, 100]);
+    player.play(1).unwrap();
+    fill(&mut player, 50);
+    assert_eq!(player.take_event(), None);
+    fill(&mut player, 100);
+    assert_eq!(player.take_event(), Some(PlayerEvent::EndOfClip(1)));
+    assert!(player.is_silent());
+    assert!(fill(&mut player, 100).iter().all(|&x| x == 0));
+    assert_eq!(player.take_event(), None);
+
+    // wraps around to the first
+    player.next_track().unwrap();
+    assert_eq!(player.track(), 0);
+    assert!(!player.is_silent());
+  }
+
+  #[test]
+  fn pause_and_seek() {
+    let mut player = player(&[RATE as usize]);
+    player.play(0).unwrap();
+    player.toggle_pause();
+    assert!(player.is_silent());
+    assert!(fill(&mut player, 100).iter().all(|&x| x == 0));
+    assert_eq!(player.position_ms(), 0);
+    player.toggle_pause();
+
+    player.seek_ms(500);
+    assert_eq!(player.position_ms(), 500);
+    player.skip_ms(-1000);
+    assert_eq!(player.position_ms(), 0);
+    player.seek_ms(5000);
+    assert_eq!(player.position_ms(), 1000);
+  }
+
+  // a broken clip is skipped like one that played out
+  #[test]
+  fn broken_clips_end_right_away() {
+    let broken = || Clip {
+      name: "broken",
+      wav: b"RIFF\x04\0\0\0WAVE",
+    };
+    let clips =
+      Box::leak(vec![clip(100), broken(), broken()].into_boxed_slice());
+    let mut player = Player::new(clips, RATE, Interpolation::Nearest);
+
+    assert!(player.play(1).is_err());
+    assert!(player.is_silent());
+    assert_eq!(player.take_event(), Some(PlayerEvent::EndOfClip(1)));
+    assert!(player.next_track().is_err());
+    assert_eq!(player.take_event(), Some(PlayerEvent::EndOfClip(2)));
+    player.next_track().unwrap();
+    assert_eq!(player.track(), 0);
+    assert_eq!(player.take_event(), None);
+
+    // nothing to move on to
+    let clips = Box::leak(vec![broken(), broken()].into_boxed_slice());
+    let mut player = Player::new(clips, RATE, Interpolation::Nearest);
+    assert!(player.play(0).is_err());
+    assert_eq!(player.take_event(), Some(PlayerEvent::EndOfClip(0)));
+    assert!(player.next_track().is_err());
+    assert_eq!(player.take_event(), None);
+  }
+}
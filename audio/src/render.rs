@@ -0,0 +1,293 @@
+// a virtual pwm to play the synthesizers into off the board. it takes
+// the duty values the apps would hand to the pwm sequencer and turns
+// them back into samples, which can be written out as a wav file or
+// compared against a render that is known to sound right.
+//
+// nothing here allocates, the samples go into a buffer from the
+// caller.
+
+use super::wav::{self, Format, Spec, Wav, WavError};
+
+// bit 15 of a duty value flips the polarity of the pwm output
+const INVERTED: u16 = 1 << 15;
+
+// a render that doesn't match the golden one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mismatch {
+  Wav(WavError),
+  // the golden file has to be 16-bit pcm at the same rate
+  Spec(Spec),
+  Length {
+    expected: usize,
+    actual: usize,
+  },
+  // the first sample that is off by more than the tolerance
+  Sample {
+    index: usize,
+    expected: i16,
+    actual: i16,
+  },
+}
+
+// the inverse of NoiseShaper::duty, without the shaping noise
+pub fn duty_to_sample(duty: u16, countertop: u16) -> i16 {
+  let top = countertop.max(1) as i32;
+  let duty = if duty & INVERTED != 0 {
+    top - (duty & !INVERTED) as i32
+  } else {
+    duty as i32
+  };
+
+  let x = (duty << 16) / top - (1 << 15);
+  x.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
+
+pub struct VirtualPwm<'a> {
+  countertop: u16,
+  // 2 for a bridged speaker. only the first value of each sample, the
+  // positive side, is recorded.
+  values_per_sample: usize,
+  samples: &'a mut [i16],
+  len: usize,
+}
+
+impl<'a> VirtualPwm<'a> {
+  pub fn new(
+    countertop: u16,
+    values_per_sample: usize,
+    samples: &'a mut [i16],
+  ) -> Self {
+    Self {
+      countertop,
+      values_per_sample: values_per_sample.max(1),
+      samples,
+      len: 0,
+    }
+  }
+
+  pub fn samples(&self) -> &[i16] {
+    &self.samples[..self.len]
+  }
+
+  pub fn is_full(&self) -> bool {
+    self.len == self.samples.len()
+  }
+
+  pub fn clear(&mut self) {
+    self.len = 0;
+  }
+
+  // play a pwm sequence. returns the number of samples recorded, less
+  // than in the sequence once the buffer is full.
+  pub fn record(&mut self, sequence: &[u16]) -> usize {
+    let start = self.len;
+    for duty in sequence.iter().step_by(self.values_per_sample) {
+      if self.is_full() {
+        break;
+      }
+      self.samples[self.len] = duty_to_sample(*duty, self.countertop);
+      self.len += 1;
+    }
+    self.len - start
+  }
+
+  // what the sequencer does on the board: fill the sequence, play it,
+  // and again, until the buffer is full
+  pub fn render(
+    &mut self,
+    sequence: &mut [u16],
+    mut fill: impl FnMut(&mut [u16]),
+  ) {
+    while !self.is_full() {
+      fill(sequence);
+      if self.record(sequence) == 0 {
+        break;
+      }
+    }
+  }
+
+  pub fn spec(&self, sample_rate: u32) -> Spec {
+    Spec {
+      format: Format::I16,
+      channels: 1,
+      sample_rate,
+    }
+  }
+
+  // the bytes write_wav needs
+  pub fn wav_len(&self) -> usize {
+    wav::MAX_HEADER_LEN + self.len * 2
+  }
+
+  // the recorded samples as a 16-bit mono wav file. out must be at
+  // least wav_len() long. returns the length of the file.
+  pub fn write_wav(&self, sample_rate: u32, out: &mut [u8]) -> usize {
+    let data_len = self.len * 2;
+    let header_len = self.spec(sample_rate).write_header(data_len as u32, out);
+
+    let data = &mut out[header_len..header_len + data_len];
+    for (bytes, sample) in data.chunks_exact_mut(2).zip(self.samples()) {
+      bytes.copy_from_slice(&sample.to_le_bytes());
+    }
+    header_len + data_len
+  }
+
+  // compare against a wav file written by write_wav. the tolerance
+  // leaves room for rounding changes that can't be heard.
+  pub fn compare(
+    &self,
+    golden: &[u8],
+    sample_rate: u32,
+    tolerance: u16,
+  ) -> Result<(), Mismatch> {
+    let golden = Wav::parse(golden).map_err(Mismatch::Wav)?;
+    if golden.spec != self.spec(sample_rate) {
+      return Err(Mismatch::Spec(golden.spec));
+    }
+
+    let mut reader = golden.reader();
+    if reader.len() != self.len {
+      return Err(Mismatch::Length {
+        expected: reader.len(),
+        actual: self.len,
+      });
+    }
+
+    for (index, actual) in self.samples().iter().enumerate() {
+      let expected = reader.sample_at(index);
+      if (expected as i32 - *actual as i32).unsigned_abs() > tolerance as u32 {
+        return Err(Mismatch::Sample {
+          index,
+          expected,
+          actual: *actual,
+        });
+      }
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{env, fs, path::PathBuf, vec};
+
+  use super::*;
+  use crate::{
+    graph::{AudioSource, PwmSink},
+    noise_shaping::{NoiseShaper, Order},
+    synth::Synth,
+    tone::Tone,
+  };
+
+  // the midi player's rate and the countertop it gets from it
+  const SAMPLE_RATE: u32 = 16387;
+  const COUNTERTOP: u16 = 1023;
+  const SEQUENCE: usize = 16;
+  // a couple of duty steps
+  const TOLERANCE: u16 = 160;
+
+  fn golden_path(name: &str) -> PathBuf {
+    let dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    PathBuf::from(dir).join("golden").join(name)
+  }
+
+  // plays the source into a virtual pwm for a second, calling `at`
+  // every 1/8 s to change it, and checks the render against
+  // golden/<name>. run with UPDATE_GOLDEN=1 to write the golden file
+  // instead, after listening to the new render.
+  fn check_render<S: AudioSource>(
+    name: &str,
+    source: &mut S,
+    mut at: impl FnMut(&mut S, usize),
+  ) {
+    let eighth = SAMPLE_RATE as usize / 8;
+    let mut samples = vec![0; eighth * 8];
+    let mut pwm = VirtualPwm::new(COUNTERTOP, 1, &mut samples);
+    let shaper = NoiseShaper::new(Order::Second);
+    let mut sink = PwmSink::new(COUNTERTOP, COUNTERTOP / 2, shaper);
+    let mut sequence = [0; SEQUENCE];
+
+    for step in 0..8 {
+      at(source, step);
+      while pwm.samples().len() < (step + 1) * eighth {
+        sink.fill(source, &mut sequence);
+        pwm.record(&sequence);
+      }
+    }
+
+    let path = golden_path(name);
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+      let mut wav = vec![0; pwm.wav_len()];
+      let len = pwm.write_wav(SAMPLE_RATE, &mut wav);
+      fs::write(&path, &wav[..len]).unwrap();
+      return;
+    }
+
+    let golden = fs::read(&path).unwrap();
+    assert_eq!(pwm.compare(&golden, SAMPLE_RATE, TOLERANCE), Ok(()));
+  }
+
+  #[test]
+  fn duty_to_sample_inverts_the_duty() {
+    assert_eq!(duty_to_sample(0, COUNTERTOP), i16::MIN);
+    assert_eq!(duty_to_sample(COUNTERTOP, COUNTERTOP), i16::MAX);
+    assert!(duty_to_sample(COUNTERTOP / 2, COUNTERTOP).abs() < 64);
+    assert_eq!(duty_to_sample(INVERTED | COUNTERTOP, COUNTERTOP), i16::MIN);
+  }
+
+  #[test]
+  fn compare_finds_the_first_difference() {
+    let mut samples = [0; 64];
+    let mut pwm = VirtualPwm::new(COUNTERTOP, 1, &mut samples);
+    pwm.render(&mut [0; SEQUENCE], |seq| seq.fill(COUNTERTOP / 2));
+    let mut wav = [0; 256];
+    let len = pwm.write_wav(SAMPLE_RATE, &mut wav);
+    assert_eq!(pwm.compare(&wav[..len], SAMPLE_RATE, 0), Ok(()));
+
+    // the high byte of sample 10
+    let header_len = len - 2 * pwm.samples().len();
+    wav[header_len + 2 * 10 + 1] ^= 0x40;
+    assert!(matches!(
+      pwm.compare(&wav[..len], SAMPLE_RATE, 100),
+      Err(Mismatch::Sample { index: 10, .. })
+    ));
+    assert!(matches!(
+      pwm.compare(&wav[..len], 8000, 100),
+      Err(Mismatch::Spec(_))
+    ));
+  }
+
+  #[test]
+  fn tone_matches_golden() {
+    let mut tone = Tone::new(SAMPLE_RATE, None);
+    tone.set_volume(100);
+    check_render("tone.wav", &mut tone, |tone, step| match step {
+      3 => tone.set_note(72),
+      6 => tone.release(),
+      _ => {}
+    });
+  }
+
+  #[test]
+  fn synth_matches_golden() {
+    let mut synth = Synth::new(SAMPLE_RATE, 8, None);
+    synth.program_change(1, 48);
+    check_render("synth.wav", &mut synth, |synth, step| match step {
+      0 => {
+        synth.note_on(0, 60, 100);
+        synth.note_on(9, 36, 110);
+      }
+      1 => {
+        synth.note_on(0, 64, 90);
+        synth.note_on(9, 42, 80);
+      }
+      2 => synth.note_on(1, 67, 100),
+      3 => synth.pitch_bend(1, 4096),
+      4 => synth.note_on(9, 38, 120),
+      5 => synth.control_change(0, 7, 60),
+      6 => synth.all_notes_off(),
+      _ => {}
+    });
+  }
+}
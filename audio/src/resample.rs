@@ -0,0 +1,354 @@
+// converts the sample rate of a clip to the rate the pwm plays at.
+//
+// the position in the source is an index plus a Q16 fraction. the
+// resampler keeps the last few source samples around, so the
+// fraction carries over from one buffer to the next and the source
+// is only ever read forward.
+
+use super::pcm::SampleSource;
+
+const ONE: u32 = 1 << 16;
+
+// the source samples the sinc kernel looks at. the output is between
+// the middle two.
+const TAPS: usize = 8;
+const PHASES: usize = 32;
+
+// windowed sinc (blackman, 8 taps) with the cutoff at 0.45 of the
+// source rate, for 33 fractional positions from 0 to 1. each row sums
+// to 1.0 in Q15. the cutoff is fixed, so downsampling aliases; the
+// players only ever run the pwm faster than the data.
+#[rustfmt::skip]
+const SINC_TABLE: [[i16; TAPS]; PHASES + 1] = [
+  [187, -1042, 2493, 29492, 2493, -1042, 187, 0],
+  [160, -865, 1723, 29446, 3315, -1226, 215, 0],
+  [135, -697, 1006, 29310, 4187, -1416, 244, -1],
+  [112, -538, 344, 29082, 5105, -1610, 274, -1],
+  [91, -390, -263, 28767, 6067, -1806, 304, -2],
+  [72, -252, -813, 28364, 7069, -2003, 335, -4],
+  [55, -126, -1307, 27876, 8107, -2197, 365, -5],
+  [39, -12, -1746, 27312, 9176, -2388, 394, -7],
+  [26, 90, -2130, 26668, 10272, -2571, 422, -9],
+  [15, 181, -2461, 25951, 11390, -2744, 447, -11],
+  [5, 260, -2739, 25166, 12524, -2905, 470, -13],
+  [-2, 327, -2967, 24318, 13668, -3051, 490, -15],
+  [-9, 383, -3147, 23414, 14817, -3178, 505, -17],
+  [-13, 429, -3281, 22455, 15964, -3283, 515, -18],
+  [-17, 464, -3372, 21454, 17103, -3363, 519, -20],
+  [-19, 490, -3423, 20410, 18228, -3415, 517, -20],
+  [-20, 508, -3436, 19331, 19333, -3436, 508, -20],
+  [-20, 517, -3415, 18228, 20410, -3423, 490, -19],
+  [-20, 519, -3363, 17103, 21454, -3372, 464, -17],
+  [-18, 515, -3283, 15964, 22455, -3281, 429, -13],
+  [-17, 505, -3178, 14817, 23414, -3147, 383, -9],
+  [-15, 490, -3051, 13668, 24318, -2967, 327, -2],
+  [-13, 470, -2905, 12524, 25166, -2739, 260, 5],
+  [-11, 447, -2744, 11390, 25951, -2461, 181, 15],
+  [-9, 422, -2571, 10272, 26668, -2130, 90, 26],
+  [-7, 394, -2388, 9176, 27312, -1746, -12, 39],
+  [-5, 365, -2197, 8107, 27876, -1307, -126, 55],
+  [-4, 335, -2003, 7069, 28364, -813, -252, 72],
+  [-2, 304, -1806, 6067, 28767, -263, -390, 91],
+  [-1, 274, -1610, 5105, 29082, 344, -538, 112],
+  [-1, 244, -1416, 4187, 29310, 1006, -697, 135],
+  [0, 215, -1226, 3315, 29446, 1723, -865, 160],
+  [0, 187, -1042, 2493, 29492, 2493, -1042, 187],
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Interpolation {
+  // repeat the closest earlier sample
+  Nearest,
+  // a straight line between neighbouring samples
+  Linear,
+  // band-limited interpolation, the least aliasing
+  Sinc,
+}
+
+pub struct Resampler {
+  interpolation: Interpolation,
+  // source samples per output sample, Q16
+  step: u32,
+  // the position between history[TAPS / 2 - 1] and the sample after
+  // it, Q16
+  frac: u32,
+  // the oldest source sample first
+  history: [i16; TAPS],
+  // the index of the next source sample to read
+  next: usize,
+}
+
+impl Resampler {
+  pub const fn new(interpolation: Interpolation) -> Self {
+    Self {
+      interpolation,
+      step: ONE,
+      frac: 0,
+      history: [0; TAPS],
+      next: 0,
+    }
+  }
+
+  pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+    self.interpolation = interpolation;
+  }
+
+  pub fn interpolation(&self) -> Interpolation {
+    self.interpolation
+  }
+
+  // the phase is kept, so the rates can change during playback
+  pub fn set_rates(&mut self, source_rate: u32, target_rate: u32) {
+    self.step = (((source_rate as u64) << 16) / target_rate as u64) as u32;
+  }
+
+  // the index of the source sample being played
+  pub fn position(&self) -> usize {
+    self.next.saturating_sub(TAPS / 2 + 1)
+  }
+
+  // whether the whole source of len samples has been played
+  pub fn is_finished(&self, len: usize) -> bool {
+    self.position() >= len
+  }
+
+  // continue playing from source sample idx. the samples before it are
+  // taken as silence.
+  pub fn seek<S: SampleSource>(&mut self, source: &mut S, idx: usize) {
+    self.history = [0; TAPS];
+    self.frac = 0;
+    self.next = idx;
+    for _ in 0..TAPS / 2 + 1 {
+      self.pull(source);
+    }
+  }
+
+  // the next output sample, silence after the end of the source
+  pub fn next_sample<S: SampleSource>(&mut self, source: &mut S) -> i16 {
+    let sample = self.interpolate();
+
+    self.frac += self.step;
+    while self.frac >= ONE {
+      self.frac -= ONE;
+      self.pull(source);
+    }
+
+    sample
+  }
+
+  fn pull<S: SampleSource>(&mut self, source: &mut S) {
+    let sample = if self.next < source.len() {
+      source.sample_at(self.next)
+    } else {
+      0
+    };
+    self.next += 1;
+
+    self.history.copy_within(1.., 0);
+    self.history[TAPS - 1] = sample;
+  }
+
+  fn interpolate(&self) -> i16 {
+    let a = self.history[TAPS / 2 - 1] as i32;
+    let b = self.history[TAPS / 2] as i32;
+
+    match self.interpolation {
+      Interpolation::Nearest => a as i16,
+      Interpolation::Linear => {
+        (a + (((b - a) * (self.frac >> 1) as i32) >> 15)) as i16
+      }
+      Interpolation::Sinc => self.sinc(),
+    }
+  }
+
+  fn sinc(&self) -> i16 {
+    // blend the two closest rows of the table
+    let pos = self.frac as usize * PHASES;
+    let (row, t) = (pos >> 16, (pos & 0xffff) as i32);
+    let lo = &SINC_TABLE[row];
+    let hi = &SINC_TABLE[row + 1];
+
+    // the taps add up to less than 1.5, so this fits in an i32
+    let mut acc = 0i32;
+    for (k, &x) in self.history.iter().enumerate() {
+      let (lo, hi) = (lo[k] as i32, hi[k] as i32);
+      let coeff = lo + (((hi - lo) * t) >> 16);
+      acc += x as i32 * coeff;
+    }
+
+    (acc >> 15).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{f64::consts::PI, vec::Vec};
+
+  use super::*;
+
+  const SOURCE_RATE: u32 = 8000;
+  const TARGET_RATE: u32 = 32000;
+  const TONE: f64 = 1000.0;
+
+  struct Samples(Vec<i16>);
+
+  impl SampleSource for Samples {
+    fn len(&self) -> usize {
+      self.0.len()
+    }
+
+    fn sample_at(&mut self, idx: usize) -> i16 {
+      self.0[idx]
+    }
+  }
+
+  fn tone(freq: f64, rate: u32, len: usize) -> Samples {
+    let samples = (0..len)
+      .map(|i| {
+        (16000.0 * (2.0 * PI * freq * i as f64 / rate as f64).sin()) as i16
+      })
+      .collect();
+    Samples(samples)
+  }
+
+  fn resample(
+    source: &mut Samples,
+    interpolation: Interpolation,
+    len: usize,
+  ) -> Vec<i16> {
+    let mut resampler = Resampler::new(interpolation);
+    resampler.set_rates(SOURCE_RATE, TARGET_RATE);
+    resampler.seek(source, 0);
+    (0..len).map(|_| resampler.next_sample(source)).collect()
+  }
+
+  // the amplitude of a frequency, through a hann window
+  fn amplitude(samples: &[i16], freq: f64, rate: u32) -> f64 {
+    let n = samples.len() as f64;
+    let (mut re, mut im) = (0.0, 0.0);
+    for (i, x) in samples.iter().enumerate() {
+      let window = 0.5 - 0.5 * (2.0 * PI * i as f64 / n).cos();
+      let angle = 2.0 * PI * freq * i as f64 / rate as f64;
+      re += *x as f64 * window * angle.cos();
+      im += *x as f64 * window * angle.sin();
+    }
+    (re * re + im * im).sqrt()
+  }
+
+  // how far the strongest mirror image of the tone is below it, in dB.
+  // the images are at multiples of the source rate, plus and minus the
+  // tone.
+  fn image_rejection(interpolation: Interpolation) -> f64 {
+    let mut source = tone(TONE, SOURCE_RATE, 4000);
+    // skip the start, where the filter fills up
+    let out = resample(&mut source, interpolation, 12000);
+    let out = &out[1000..9192];
+
+    let signal = amplitude(out, TONE, TARGET_RATE);
+    let mut strongest: f64 = 0.0;
+    for k in 1..=(TARGET_RATE / SOURCE_RATE / 2) {
+      let image = (k * SOURCE_RATE) as f64;
+      for freq in [image - TONE, image + TONE] {
+        if freq < TARGET_RATE as f64 / 2.0 {
+          strongest = strongest.max(amplitude(out, freq, TARGET_RATE));
+        }
+      }
+    }
+    20.0 * (signal / strongest).log10()
+  }
+
+  // the figures in the readme
+  #[test]
+  fn measures_the_mirror_images() {
+    let nearest = image_rejection(Interpolation::Nearest);
+    let linear = image_rejection(Interpolation::Linear);
+    let sinc = image_rejection(Interpolation::Sinc);
+    assert!((15.0..17.0).contains(&nearest), "nearest {:.1} dB", nearest);
+    assert!((31.0..34.0).contains(&linear), "linear {:.1} dB", linear);
+    assert!((77.0..81.0).contains(&sinc), "sinc {:.1} dB", sinc);
+  }
+
+  #[test]
+  fn passes_the_tone_through() {
+    // holding a sample and drawing lines between them take a bit off
+    // the tone, by sinc(f / fs) and its square
+    let x = PI * TONE / SOURCE_RATE as f64;
+    let hold = x.sin() / x;
+    for (interpolation, gain) in [
+      (Interpolation::Nearest, hold),
+      (Interpolation::Linear, hold * hold),
+      (Interpolation::Sinc, 1.0),
+    ] {
+      let mut source = tone(TONE, SOURCE_RATE, 4000);
+      let out = resample(&mut source, interpolation, 12000);
+      let level = amplitude(&out[1000..9192], TONE, TARGET_RATE);
+      // a full window of a 16000 amplitude tone
+      let expected = gain * 16000.0 * 8192.0 / 4.0;
+      let error = (level / expected - 1.0).abs();
+      assert!(error < 0.01, "{:?} is off by {:.3}", interpolation, error);
+    }
+  }
+
+  #[test]
+  fn linear_follows_the_line() {
+    let mut source = Samples((0..100).map(|i| i * 100).collect());
+    let out = resample(&mut source, Interpolation::Linear, 200);
+    for (i, y) in out.iter().enumerate().take(120) {
+      // the output starts at the first sample, a quarter sample a step
+      assert_eq!(*y as usize, i * 25, "{}", i);
+    }
+  }
+
+  #[test]
+  fn carries_over_between_buffers() {
+    let mut source = tone(TONE, SOURCE_RATE, 4000);
+    let whole = resample(&mut source, Interpolation::Sinc, 3000);
+
+    let mut resampler = Resampler::new(Interpolation::Sinc);
+    resampler.set_rates(SOURCE_RATE, TARGET_RATE);
+    resampler.seek(&mut source, 0);
+    let mut pieces = Vec::new();
+    for len in [1, 7, 64, 333, 1000].iter().cycle() {
+      if pieces.len() >= whole.len() {
+        break;
+      }
+      let len = (*len).min(whole.len() - pieces.len());
+      pieces.extend((0..len).map(|_| resampler.next_sample(&mut source)));
+    }
+    assert_eq!(pieces, whole);
+  }
+
+  #[test]
+  fn keeps_the_phase_when_the_rate_changes() {
+    let mut source = Samples((0..1000).map(|i| i * 30).collect());
+    let mut resampler = Resampler::new(Interpolation::Linear);
+    resampler.set_rates(SOURCE_RATE, TARGET_RATE);
+    resampler.seek(&mut source, 0);
+    for _ in 0..10 {
+      resampler.next_sample(&mut source);
+    }
+    let before = resampler.next_sample(&mut source);
+    resampler.set_rates(SOURCE_RATE, TARGET_RATE * 2);
+    let after = resampler.next_sample(&mut source);
+    // a quarter sample of 30, where the new rate takes over
+    assert_eq!(after - before, 7);
+  }
+
+  #[test]
+  fn finishes_at_the_end_of_the_source() {
+    let mut source = Samples(std::vec![1000; 10]);
+    let mut resampler = Resampler::new(Interpolation::Nearest);
+    resampler.set_rates(SOURCE_RATE, TARGET_RATE);
+    resampler.seek(&mut source, 0);
+    let mut played = 0;
+    while !resampler.is_finished(source.len()) {
+      assert_eq!(resampler.next_sample(&mut source), 1000);
+      played += 1;
+    }
+    assert_eq!(played, 40);
+    assert_eq!(resampler.next_sample(&mut source), 0);
+
+    resampler.seek(&mut source, 8);
+    assert_eq!(resampler.position(), 8);
+  }
+}
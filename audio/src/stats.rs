@@ -0,0 +1,126 @@
+// how well the buffers keep up with the pwm. all times are in cpu
+// cycles, as counted by the DWT.
+
+use core::fmt;
+
+// the timing of one refill, measured by the pwm interrupt
+#[derive(Clone, Copy, Debug)]
+pub struct Refill {
+  // how long the buffer plays for
+  pub period: u32,
+  // from the SEQEND of the buffer to the interrupt starting the other
+  // one. the pwm holds the last duty value in the meantime.
+  pub late: u32,
+  // the time spent in the interrupt, filling the buffer included
+  pub isr: u32,
+}
+
+impl Refill {
+  // from the SEQEND to the refill being done
+  pub fn latency(&self) -> u32 {
+    self.late.saturating_add(self.isr)
+  }
+
+  // how much longer the refill could have taken before the buffer
+  // was needed. negative when it was needed before it was ready.
+  pub fn slack(&self) -> i32 {
+    self.period as i32 - self.latency() as i32
+  }
+
+  // restarting more than a sample late is audible
+  pub fn is_underrun(&self, samples: u32) -> bool {
+    self.slack() < 0 || self.late > self.period / samples.max(1)
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PipelineStats {
+  pub buffers: u32,
+  pub underruns: u32,
+  pub last_latency: u32,
+  pub max_latency: u32,
+  pub max_isr: u32,
+  total_isr: u64,
+  pub min_slack: i32,
+  // the duration of the last buffer
+  pub period: u32,
+}
+
+impl PipelineStats {
+  pub const fn new() -> Self {
+    Self {
+      buffers: 0,
+      underruns: 0,
+      last_latency: 0,
+      max_latency: 0,
+      max_isr: 0,
+      total_isr: 0,
+      min_slack: i32::MAX,
+      period: 0,
+    }
+  }
+
+  pub fn reset(&mut self) {
+    *self = Self::new();
+  }
+
+  // returns whether the refill was an underrun
+  pub fn record(&mut self, refill: &Refill, samples: u32) -> bool {
+    let underrun = refill.is_underrun(samples);
+
+    self.buffers += 1;
+    if underrun {
+      self.underruns += 1;
+    }
+    self.last_latency = refill.latency();
+    self.max_latency = self.max_latency.max(refill.latency());
+    self.max_isr = self.max_isr.max(refill.isr);
+    self.total_isr += refill.isr as u64;
+    self.min_slack = self.min_slack.min(refill.slack());
+    self.period = refill.period;
+
+    underrun
+  }
+
+  pub fn avg_isr(&self) -> u32 {
+    if self.buffers == 0 {
+      return 0;
+    }
+    (self.total_isr / self.buffers as u64) as u32
+  }
+
+  // the share of the cpu the interrupt takes, in percent
+  pub fn load(&self) -> u32 {
+    if self.period == 0 {
+      return 0;
+    }
+    (self.avg_isr() as u64 * 100 / self.period as u64) as u32
+  }
+}
+
+impl Default for PipelineStats {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+// one line for rtt or serial
+impl fmt::Display for PipelineStats {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let min_slack = if self.buffers == 0 { 0 } else { self.min_slack };
+    write!(
+      f,
+      "buffers: {}, underruns: {}, latency: {}/{} max, isr: {}/{} max \
+       ({}%), min slack: {} of {}",
+      self.buffers,
+      self.underruns,
+      self.last_latency,
+      self.max_latency,
+      self.avg_isr(),
+      self.max_isr,
+      self.load(),
+      min_slack,
+      self.period,
+    )
+  }
+}
@@ -0,0 +1,724 @@
+// the synthesizer of the midi player. every note gets a voice of its
+// own, with its own phase and envelope, and the voices are added up
+// into one stream of samples. each channel plays the instrument of its
+// program from audio::instrument, except channel 10, which plays the
+// drums.
+
+use fixed::types::{U16F16, U8F8};
+use heapless::Vec;
+
+use super::{
+  calibration::Profile,
+  drums::{Drums, DRUM_CHANNEL},
+  dsp,
+  envelope::{Envelope, VelocityCurve},
+  eq::{Equalizer, Filter},
+  graph::AudioSource,
+  instrument::{self, Instrument},
+  osc::{self, Osc},
+};
+
+pub const CHANNELS: usize = 16;
+
+// the most voices a synth can be set up with
+pub const MAX_VOICES: usize = 16;
+
+// the pitch bend goes this far either way until the song says
+// otherwise, in semitones
+pub const DEFAULT_BEND_RANGE: u8 = 2;
+
+// the vibrato of the modulation wheel all the way up, in cents, and
+// its rate for instruments without a vibrato of their own
+const MODULATION_CENTS: i32 = 50;
+const MODULATION_RATE: U8F8 = U8F8::from_bits(5 << 8);
+
+// the controllers the synth follows
+const CC_MODULATION: u8 = 1;
+const CC_DATA_ENTRY: u8 = 6;
+const CC_VOLUME: u8 = 7;
+const CC_EXPRESSION: u8 = 11;
+const CC_SUSTAIN: u8 = 64;
+const CC_RPN_LSB: u8 = 100;
+const CC_RPN_MSB: u8 = 101;
+const CC_ALL_SOUND_OFF: u8 = 120;
+const CC_RESET_CONTROLLERS: u8 = 121;
+const CC_ALL_NOTES_OFF: u8 = 123;
+// the omni and mono/poly mode messages turn the notes off as well
+const CC_POLY_ON: u8 = 127;
+
+// the registered parameter for the pitch bend range, and none
+const RPN_BEND_RANGE: u16 = 0;
+const NO_RPN: u16 = 0x3fff;
+
+// the drums are added to the notes at this level
+const DRUM_GAIN: U8F8 = U8F8::from_bits(3 << 6);
+
+// how fast the level of the mix follows the number of voices, as a
+// shift. it takes a few ms to settle, so voices coming and going don't
+// click.
+const LEVEL_SMOOTHING: u32 = 6;
+
+// a note being played
+struct Voice {
+  channel: u8,
+  key: u8,
+  instrument: &'static Instrument,
+  osc: Osc,
+  // the pitch of the key, before the vibrato
+  freq: U16F16,
+  envelope: Envelope,
+  // from the velocity
+  velocity: U8F8,
+  // the speaker compensation at the note's frequency
+  gain: U8F8,
+  // all the gains together, with the volume of the channel
+  amp: U8F8,
+  // the note is off, but the sustain pedal of the channel holds it
+  sustained: bool,
+  // how far the pitch is from the key, as last set
+  cents: i32,
+  // the order the notes started in, the oldest is the first to go
+  started: u32,
+  // samples since the note started
+  age: u32,
+  // the phase of the vibrato
+  lfo: u32,
+}
+
+impl Voice {
+  // the pitch and volume for the next samples, from the controllers of
+  // the channel and the vibrato. the vibrato of the instrument starts
+  // from the middle once its delay is over, so the pitch doesn't jump.
+  fn modulate(&mut self, channel: &Channel, sample_rate: u32, samples: u32) {
+    let vibrato = self.instrument.vibrato;
+    let delay = vibrato.delay as u32 * sample_rate / 1000;
+    let mut depth = channel.modulation as i32 * MODULATION_CENTS / 127;
+    if self.age >= delay {
+      depth += vibrato.depth as i32;
+    }
+
+    let mut cents = channel.bend_cents();
+    if depth > 0 {
+      let rate = if vibrato.rate == U8F8::ZERO {
+        MODULATION_RATE
+      } else {
+        vibrato.rate
+      };
+      cents += (osc::sine(self.lfo) as i32 * depth) >> 15;
+      let step = ((rate.to_bits() as u64) << 24) / sample_rate as u64;
+      self.lfo = self.lfo.wrapping_add(step as u32 * samples);
+    }
+    self.age = self.age.saturating_add(samples);
+
+    if cents != self.cents {
+      self.cents = cents;
+      let ratio = dsp::cents_to_ratio(cents);
+      let freq = self.freq.saturating_mul(ratio);
+      self.osc.set_fine_freq(freq);
+    }
+
+    let gain = self.gain.saturating_mul(self.velocity);
+    self.amp = gain.saturating_mul(channel.volume());
+  }
+}
+
+// what a channel plays, and its controllers
+#[derive(Clone, Copy)]
+struct Channel {
+  program: u8,
+  instrument: &'static Instrument,
+  // -8192 to 8191
+  bend: i16,
+  // the bend at either end, in semitones
+  bend_range: u8,
+  sustain: bool,
+  // the modulation wheel, 0 to 127
+  modulation: u8,
+  // 0 to 127 each
+  volume: u8,
+  expression: u8,
+  // the registered parameter the data entry goes to
+  rpn: u16,
+}
+
+impl Channel {
+  fn new() -> Self {
+    Self {
+      program: 0,
+      instrument: instrument::for_program(0),
+      bend: 0,
+      bend_range: DEFAULT_BEND_RANGE,
+      sustain: false,
+      modulation: 0,
+      volume: 100,
+      expression: 127,
+      rpn: NO_RPN,
+    }
+  }
+
+  // what reset all controllers resets, the program and volume stay
+  fn reset_controllers(&mut self) {
+    *self = Self {
+      program: self.program,
+      instrument: self.instrument,
+      volume: self.volume,
+      bend_range: self.bend_range,
+      ..Self::new()
+    };
+  }
+
+  fn bend_cents(&self) -> i32 {
+    self.bend as i32 * self.bend_range as i32 * 100 / 8192
+  }
+
+  fn volume(&self) -> U8F8 {
+    let volume = self.volume as u32 * self.expression as u32;
+    U8F8::from_bits((volume * 256 / (127 * 127)) as u16)
+  }
+}
+
+// plays the notes being held, on any of the 16 channels
+pub struct Synth {
+  pub velocity_curve: VelocityCurve,
+  sample_rate: u32,
+  channels: [Channel; CHANNELS],
+  voices: Vec<Voice, MAX_VOICES>,
+  max_voices: usize,
+  drums: Drums,
+  // counts the notes started
+  notes_started: u32,
+  // semitones added to every note
+  transpose: i8,
+  // the gain of the mix, Q16. follows one over the number of voices.
+  level: u32,
+  equalizer: Equalizer,
+  // speaker compensation from app_speaker_calibration, if any
+  profile: Option<Profile>,
+}
+
+impl Synth {
+  // max_voices is capped at MAX_VOICES
+  pub fn new(
+    sample_rate: u32,
+    max_voices: usize,
+    profile: Option<Profile>,
+  ) -> Self {
+    Self {
+      velocity_curve: VelocityCurve::default(),
+      sample_rate,
+      channels: [Channel::new(); CHANNELS],
+      voices: Vec::new(),
+      max_voices: max_voices.clamp(1, MAX_VOICES),
+      drums: Drums::new(sample_rate),
+      notes_started: 0,
+      transpose: 0,
+      level: 1 << 16,
+      equalizer: Equalizer::new(),
+      profile,
+    }
+  }
+
+  // the filters to run the samples through, e.g. eq::MLT_8530
+  pub fn set_equalizer(&mut self, filters: &[Filter]) {
+    self.equalizer = Equalizer::with_filters(filters, self.sample_rate);
+  }
+
+  // the general midi program of the notes started on the channel from
+  // now on
+  pub fn program_change(&mut self, channel: u8, program: u8) {
+    if let Some(state) = self.channels.get_mut(channel as usize) {
+      state.program = program;
+      state.instrument = instrument::for_program(program);
+    }
+  }
+
+  pub fn program(&self, channel: u8) -> u8 {
+    self.channels[channel as usize % CHANNELS].program
+  }
+
+  pub fn instrument(&self, channel: u8) -> &'static Instrument {
+    self.channels[channel as usize % CHANNELS].instrument
+  }
+
+  // the voices playing, the ones fading out after their note off
+  // included
+  pub fn active_voices(&self) -> usize {
+    self.voices.len()
+  }
+
+  // a key hit again starts over from where its envelope is. when all
+  // the voices are taken, the oldest of the released notes makes room,
+  // or the oldest note if none are released.
+  pub fn note_on(&mut self, channel: u8, key: u8, velocity: u8) {
+    let velocity = self.velocity_curve.gain(velocity);
+    // the drums die out by themselves, keys without a drum are left
+    // out
+    if channel == DRUM_CHANNEL {
+      let volume = self.channels[DRUM_CHANNEL as usize].volume();
+      self.drums.hit(key, velocity.saturating_mul(volume));
+      return;
+    }
+    let same_key = |voice: &Voice| voice.channel == channel && voice.key == key;
+
+    if let Some(voice) = self.voices.iter_mut().find(|v| same_key(v)) {
+      voice.velocity = velocity;
+      voice.started = self.notes_started;
+      voice.sustained = false;
+      voice.envelope.trigger();
+      self.notes_started = self.notes_started.wrapping_add(1);
+      return;
+    }
+
+    if self.voices.len() >= self.max_voices {
+      let now = self.notes_started;
+      let oldest = (0..self.voices.len()).max_by_key(|&i| {
+        let voice = &self.voices[i];
+        (
+          voice.envelope.is_released(),
+          now.wrapping_sub(voice.started),
+        )
+      });
+      if let Some(i) = oldest {
+        self.voices.swap_remove(i);
+      }
+    }
+
+    let instrument = self.instrument(channel);
+    let mut voice = Voice {
+      channel,
+      key,
+      instrument,
+      osc: Osc::new(self.sample_rate),
+      freq: U16F16::ZERO,
+      envelope: Envelope::new(instrument.envelope, self.sample_rate),
+      velocity,
+      gain: U8F8::ONE,
+      amp: U8F8::ZERO,
+      sustained: false,
+      cents: 0,
+      started: self.notes_started,
+      age: 0,
+      lfo: 0,
+    };
+    voice.envelope.trigger();
+    self.tune(&mut voice);
+    self.notes_started = self.notes_started.wrapping_add(1);
+    self.voices.push(voice).ok();
+  }
+
+  // the note fades out with the release of its envelope, or when the
+  // sustain pedal is let go
+  pub fn note_off(&mut self, channel: u8, key: u8) {
+    let sustain = self.channels[channel as usize % CHANNELS].sustain;
+    for voice in self.voices.iter_mut() {
+      if voice.channel == channel && voice.key == key {
+        if sustain {
+          voice.sustained = true;
+        } else {
+          voice.envelope.release();
+        }
+      }
+    }
+  }
+
+  pub fn all_notes_off(&mut self) {
+    for voice in self.voices.iter_mut() {
+      voice.envelope.release();
+    }
+  }
+
+  // -8192 to 8191, the ends are the bend range of the channel
+  pub fn pitch_bend(&mut self, channel: u8, bend: i16) {
+    if let Some(state) = self.channels.get_mut(channel as usize) {
+      state.bend = bend.clamp(-8192, 8191);
+    }
+  }
+
+  // the bend range is also set by the song, with registered parameter
+  // 0
+  pub fn set_bend_range(&mut self, channel: u8, semitones: u8) {
+    if let Some(state) = self.channels.get_mut(channel as usize) {
+      state.bend_range = semitones.min(24);
+    }
+  }
+
+  // controllers the synth doesn't know are left alone
+  pub fn control_change(&mut self, channel: u8, controller: u8, value: u8) {
+    let Some(state) = self.channels.get_mut(channel as usize) else {
+      return;
+    };
+
+    match controller {
+      CC_MODULATION => state.modulation = value,
+      CC_VOLUME => state.volume = value,
+      CC_EXPRESSION => state.expression = value,
+      CC_SUSTAIN => {
+        state.sustain = value >= 64;
+        if !state.sustain {
+          self.release_sustained(channel);
+        }
+      }
+      CC_RPN_MSB => state.rpn = (state.rpn & 0x7f) | (value as u16) << 7,
+      CC_RPN_LSB => state.rpn = (state.rpn & !0x7f) | value as u16,
+      CC_DATA_ENTRY if state.rpn == RPN_BEND_RANGE => {
+        self.set_bend_range(channel, value)
+      }
+      CC_ALL_SOUND_OFF => {
+        self.voices.retain(|voice| voice.channel != channel);
+        if channel == DRUM_CHANNEL {
+          self.drums.clear();
+        }
+      }
+      CC_RESET_CONTROLLERS => {
+        state.reset_controllers();
+        self.release_sustained(channel);
+      }
+      CC_ALL_NOTES_OFF..=CC_POLY_ON => {
+        for voice in self.voices.iter_mut() {
+          if voice.channel == channel {
+            voice.envelope.release();
+          }
+        }
+      }
+      _ => {}
+    }
+  }
+
+  fn release_sustained(&mut self, channel: u8) {
+    for voice in self.voices.iter_mut() {
+      if voice.channel == channel && voice.sustained {
+        voice.sustained = false;
+        voice.envelope.release();
+      }
+    }
+  }
+
+  // silences everything at once, and the channels go back to piano
+  pub fn reset(&mut self) {
+    self.voices.clear();
+    self.drums.clear();
+    self.channels = [Channel::new(); CHANNELS];
+  }
+
+  pub fn transpose(&self) -> i8 {
+    self.transpose
+  }
+
+  // the notes already playing change pitch too
+  pub fn set_transpose(&mut self, transpose: i8) {
+    self.transpose = transpose;
+    let mut voices = core::mem::take(&mut self.voices);
+    voices.iter_mut().for_each(|voice| self.tune(voice));
+    self.voices = voices;
+  }
+
+  fn tune(&self, voice: &mut Voice) {
+    let key = (voice.key as i16 + self.transpose as i16).clamp(0, 127);
+    voice.freq = dsp::key_to_freq(key as u8);
+    voice.osc.set_fine_freq(voice.freq);
+    // the bend and vibrato are put back on by modulate
+    voice.cents = 0;
+    voice.gain = match self.profile.as_ref() {
+      Some(profile) => profile.gain_at(voice.freq.to_num()),
+      None => U8F8::ONE,
+    };
+  }
+}
+
+impl AudioSource for Synth {
+  fn fill(&mut self, samples: &mut [i16]) {
+    // with no voices left the level stays, ready for the next note
+    let target = match self.voices.len() {
+      0 => self.level,
+      n => (1 << 16) / n as u32,
+    };
+
+    for voice in self.voices.iter_mut() {
+      let channel = &self.channels[voice.channel as usize % CHANNELS];
+      voice.modulate(channel, self.sample_rate, samples.len() as u32);
+    }
+
+    for sample in samples.iter_mut() {
+      let mut sum = 0;
+      for voice in self.voices.iter_mut() {
+        let x = voice.instrument.table.sample(voice.osc.advance());
+        let x = dsp::apply_gain(x, voice.amp);
+        sum += voice.envelope.apply(x) as i32;
+      }
+
+      // the level moves a bit towards the target every sample
+      self.level = self.level - (self.level >> LEVEL_SMOOTHING)
+        + (target >> LEVEL_SMOOTHING);
+      let drums = dsp::apply_gain(self.drums.sample(), DRUM_GAIN);
+      let y = ((sum as i64 * self.level as i64) >> 16) + drums as i64;
+      let y = y.clamp(i16::MIN as i64, i16::MAX as i64) as i16;
+      *sample = self.equalizer.process(y);
+    }
+
+    self.voices.retain(|voice| !voice.envelope.is_idle());
+    self.drums.collect();
+  }
+
+  fn is_silent(&self) -> bool {
+    self.voices.is_empty() && self.drums.is_silent()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::vec::Vec;
+
+  use super::*;
+
+  const SAMPLE_RATE: u32 = 16000;
+
+  fn synth(max_voices: usize) -> Synth {
+    Synth::new(SAMPLE_RATE, max_voices, None)
+  }
+
+  // (channel, key) of the voices, in the order they started
+  fn keys(synth: &Synth) -> Vec<(u8, u8)> {
+    let mut voices: Vec<&Voice> = synth.voices.iter().collect();
+    voices.sort_by_key(|v| v.started);
+    voices.iter().map(|v| (v.channel, v.key)).collect()
+  }
+
+  fn voice(synth: &Synth, channel: u8, key: u8) -> &Voice {
+    let found = synth
+      .voices
+      .iter()
+      .find(|v| (v.channel, v.key) == (channel, key));
+    found.unwrap()
+  }
+
+  fn play(synth: &mut Synth, len: usize) {
+    let mut samples = std::vec![0; len];
+    synth.fill(&mut samples);
+  }
+
+  #[test]
+  fn every_note_gets_a_voice() {
+    let mut synth = synth(4);
+    synth.note_on(0, 60, 100);
+    synth.note_on(0, 64, 100);
+    synth.note_on(3, 60, 100);
+    assert_eq!(keys(&synth), [(0, 60), (0, 64), (3, 60)]);
+
+    // the same key again keeps its voice
+    synth.note_on(0, 60, 50);
+    assert_eq!(keys(&synth), [(0, 64), (3, 60), (0, 60)]);
+
+    // the drums play on their own
+    synth.note_on(DRUM_CHANNEL, 36, 100);
+    assert_eq!(synth.active_voices(), 3);
+    assert!(!synth.drums.is_silent());
+  }
+
+  #[test]
+  fn steals_the_oldest_note_when_all_voices_play() {
+    let mut synth = synth(3);
+    for key in [60, 62, 64] {
+      synth.note_on(0, key, 100);
+    }
+    synth.note_on(1, 65, 100);
+    assert_eq!(keys(&synth), [(0, 62), (0, 64), (1, 65)]);
+
+    // a key hit again counts as the newest
+    synth.note_on(0, 62, 100);
+    synth.note_on(1, 67, 100);
+    assert_eq!(keys(&synth), [(1, 65), (0, 62), (1, 67)]);
+  }
+
+  #[test]
+  fn steals_a_released_note_before_a_held_one() {
+    let mut synth = synth(3);
+    for key in [60, 62, 64] {
+      synth.note_on(0, key, 100);
+    }
+    synth.note_off(0, 64);
+    synth.note_off(0, 62);
+    synth.note_on(0, 65, 100);
+    // 62 and 64 are fading out, 62 is the older of them
+    assert_eq!(keys(&synth), [(0, 60), (0, 64), (0, 65)]);
+  }
+
+  #[test]
+  fn max_voices_is_capped() {
+    let mut synth = synth(100);
+    for key in 0..40 {
+      synth.note_on(0, key, 100);
+    }
+    assert_eq!(synth.active_voices(), MAX_VOICES);
+    assert_eq!(keys(&synth)[0], (0, 40 - MAX_VOICES as u8));
+  }
+
+  #[test]
+  fn sustain_holds_notes_until_the_pedal_is_let_go() {
+    let mut synth = synth(8);
+    synth.control_change(0, CC_SUSTAIN, 127);
+    synth.note_on(0, 60, 100);
+    synth.note_on(1, 60, 100);
+    synth.note_off(0, 60);
+    synth.note_off(1, 60);
+
+    let held = voice(&synth, 0, 60);
+    assert!(held.sustained);
+    assert!(!held.envelope.is_released());
+    // the pedal is only down on channel 0
+    assert!(voice(&synth, 1, 60).envelope.is_released());
+
+    // a note hit again while held is held by the key again
+    synth.note_on(0, 60, 100);
+    assert!(!voice(&synth, 0, 60).sustained);
+    synth.note_off(0, 60);
+
+    synth.note_on(0, 64, 100);
+    synth.control_change(0, CC_SUSTAIN, 0);
+    let released = voice(&synth, 0, 60);
+    assert!(!released.sustained);
+    assert!(released.envelope.is_released());
+    // a key still down plays on
+    assert!(!voice(&synth, 0, 64).envelope.is_released());
+  }
+
+  fn set_rpn(synth: &mut Synth, channel: u8, rpn: u16, value: u8) {
+    synth.control_change(channel, CC_RPN_MSB, (rpn >> 7) as u8);
+    synth.control_change(channel, CC_RPN_LSB, (rpn & 0x7f) as u8);
+    synth.control_change(channel, CC_DATA_ENTRY, value);
+  }
+
+  #[test]
+  fn bend_range_is_set_by_rpn_0() {
+    let mut synth = synth(4);
+    synth.note_on(0, 69, 100);
+    synth.pitch_bend(0, 8191);
+    play(&mut synth, 16);
+    // the default of 2 semitones, just short of the end
+    assert_eq!(voice(&synth, 0, 69).cents, 199);
+
+    set_rpn(&mut synth, 0, RPN_BEND_RANGE, 12);
+    assert_eq!(synth.channels[0].bend_range, 12);
+    synth.pitch_bend(0, -8192);
+    play(&mut synth, 16);
+    assert_eq!(voice(&synth, 0, 69).cents, -1200);
+
+    // other parameters, and data entry after the null rpn, are left
+    // alone
+    set_rpn(&mut synth, 0, 1, 5);
+    set_rpn(&mut synth, 0, NO_RPN, 5);
+    assert_eq!(synth.channels[0].bend_range, 12);
+    // so are other channels
+    assert_eq!(synth.channels[1].bend_range, DEFAULT_BEND_RANGE);
+
+    // and it's capped at two octaves
+    set_rpn(&mut synth, 0, RPN_BEND_RANGE, 100);
+    assert_eq!(synth.channels[0].bend_range, 24);
+  }
+
+  #[test]
+  fn modulation_wheel_adds_vibrato() {
+    let mut synth = synth(4);
+    synth.note_on(0, 69, 100);
+    synth.control_change(0, CC_MODULATION, 127);
+
+    let mut cents = Vec::new();
+    for _ in 0..200 {
+      play(&mut synth, 16);
+      cents.push(voice(&synth, 0, 69).cents);
+    }
+    let max = *cents.iter().max().unwrap();
+    let min = *cents.iter().min().unwrap();
+    assert!((45..=MODULATION_CENTS).contains(&max), "{max}");
+    assert!((-MODULATION_CENTS..=-45).contains(&min), "{min}");
+  }
+
+  #[test]
+  fn volume_and_expression_scale_the_voices() {
+    let mut synth = synth(4);
+    synth.velocity_curve = VelocityCurve::Linear;
+    synth.note_on(0, 69, 127);
+    synth.control_change(0, CC_VOLUME, 127);
+    play(&mut synth, 16);
+    let full = voice(&synth, 0, 69).amp.to_bits();
+
+    synth.control_change(0, CC_EXPRESSION, 64);
+    play(&mut synth, 16);
+    let half = voice(&synth, 0, 69).amp.to_bits();
+    assert!(full.abs_diff(2 * half) <= 2, "{full} {half}");
+
+    synth.control_change(0, CC_VOLUME, 0);
+    play(&mut synth, 16);
+    assert_eq!(voice(&synth, 0, 69).amp, U8F8::ZERO);
+  }
+
+  #[test]
+  fn all_notes_off_releases_the_notes_of_the_channel() {
+    let mut synth = synth(8);
+    synth.note_on(0, 60, 100);
+    synth.note_on(1, 60, 100);
+    synth.control_change(0, CC_ALL_NOTES_OFF, 0);
+    assert!(voice(&synth, 0, 60).envelope.is_released());
+    assert!(!voice(&synth, 1, 60).envelope.is_released());
+    // they fade out rather than stop
+    assert_eq!(synth.active_voices(), 2);
+
+    // the mode messages do the same
+    synth.control_change(1, CC_POLY_ON, 0);
+    assert!(voice(&synth, 1, 60).envelope.is_released());
+
+    synth.note_on(2, 60, 100);
+    synth.note_on(3, 60, 100);
+    synth.all_notes_off();
+    assert!(synth.voices.iter().all(|v| v.envelope.is_released()));
+  }
+
+  #[test]
+  fn all_sound_off_stops_the_channel_at_once() {
+    let mut synth = synth(8);
+    synth.note_on(0, 60, 100);
+    synth.note_on(1, 60, 100);
+    synth.control_change(0, CC_ALL_SOUND_OFF, 0);
+    assert_eq!(keys(&synth), [(1, 60)]);
+  }
+
+  #[test]
+  fn reset_all_controllers_keeps_the_program_and_volume() {
+    let mut synth = synth(8);
+    synth.program_change(0, 40);
+    synth.control_change(0, CC_VOLUME, 90);
+    synth.control_change(0, CC_EXPRESSION, 30);
+    synth.control_change(0, CC_MODULATION, 100);
+    synth.control_change(0, CC_SUSTAIN, 127);
+    set_rpn(&mut synth, 0, RPN_BEND_RANGE, 7);
+    synth.pitch_bend(0, 4000);
+    synth.note_on(0, 60, 100);
+    synth.note_off(0, 60);
+    assert!(voice(&synth, 0, 60).sustained);
+
+    synth.control_change(0, CC_RESET_CONTROLLERS, 0);
+    let channel = synth.channels[0];
+    assert_eq!(channel.bend, 0);
+    assert!(!channel.sustain);
+    assert_eq!(channel.modulation, 0);
+    assert_eq!(channel.expression, 127);
+    assert_eq!(channel.rpn, NO_RPN);
+    assert_eq!((channel.program, channel.volume), (40, 90));
+    assert_eq!(channel.bend_range, 7);
+    // the pedal is up, so the held note goes
+    assert!(voice(&synth, 0, 60).envelope.is_released());
+  }
+
+  #[test]
+  fn reset_silences_everything() {
+    let mut synth = synth(8);
+    synth.program_change(2, 40);
+    synth.pitch_bend(2, 1000);
+    synth.note_on(2, 60, 100);
+    synth.note_on(DRUM_CHANNEL, 36, 100);
+    synth.reset();
+
+    assert!(synth.is_silent());
+    assert_eq!(synth.program(2), 0);
+    assert_eq!(synth.channels[2].bend, 0);
+  }
+}
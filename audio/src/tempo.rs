@@ -0,0 +1,213 @@
+// keeps track of the midi tick while the song plays. the clock is
+// stepped at a fixed rate by a timer, and moves on by however many
+// ticks fit in a step at the current tempo.
+//
+// with ticks per quarter note, the length of a tick depends on the
+// tempo, which the song can change at any time with a Set Tempo meta
+// event. with smpte timecode, a tick is a fixed fraction of a second
+// and the tempo doesn't matter.
+
+// how the ticks of a midi file relate to time
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Division {
+  TicksPerQuarter(u16),
+  // smpte frames per second and ticks per frame. 29 is 29.97 drop
+  // frame.
+  Timecode { fps: u8, ticks_per_frame: u8 },
+}
+
+// 120 bpm, until the song says otherwise
+pub const DEFAULT_TEMPO: u32 = 500_000;
+
+const MICROS_PER_SEC: u64 = 1_000_000;
+
+pub struct TempoClock {
+  division: Division,
+  // the rate the clock is stepped at
+  step_rate: u32,
+  // microseconds per quarter note
+  tempo: u32,
+  // ticks per step, Q32
+  step: u64,
+  // the position in ticks, Q32
+  position: u64,
+}
+
+impl TempoClock {
+  pub fn new(division: Division, step_rate: u32) -> Self {
+    let mut clock = Self {
+      division,
+      step_rate,
+      tempo: DEFAULT_TEMPO,
+      step: 0,
+      position: 0,
+    };
+    clock.update_step();
+    clock
+  }
+
+  pub fn division(&self) -> Division {
+    self.division
+  }
+
+  pub fn tempo(&self) -> u32 {
+    self.tempo
+  }
+
+  // from a Set Tempo event, in microseconds per quarter note
+  pub fn set_tempo(&mut self, tempo: u32) {
+    self.tempo = tempo.max(1);
+    self.update_step();
+  }
+
+  pub fn ticks_per_sec(&self) -> u32 {
+    (self.ticks_per_sec_q32() >> 32) as u32
+  }
+
+  // Q32, the timecode rates aren't whole
+  fn ticks_per_sec_q32(&self) -> u64 {
+    match self.division {
+      Division::TicksPerQuarter(tpq) => {
+        let rate =
+          ((tpq as u128 * MICROS_PER_SEC as u128) << 32) / self.tempo as u128;
+        rate.min(u64::MAX as u128) as u64
+      }
+      Division::Timecode {
+        fps: 29,
+        ticks_per_frame,
+      } => ((30_000 * ticks_per_frame as u64) << 32) / 1001,
+      Division::Timecode {
+        fps,
+        ticks_per_frame,
+      } => (fps as u64 * ticks_per_frame as u64) << 32,
+    }
+  }
+
+  fn update_step(&mut self) {
+    self.step = self.ticks_per_sec_q32() / self.step_rate.max(1) as u64;
+  }
+
+  // the tick the song is at
+  pub fn tick(&self) -> u32 {
+    (self.position >> 32) as u32
+  }
+
+  // moves on by one step, returns the new tick
+  pub fn advance(&mut self) -> u32 {
+    self.position += self.step;
+    self.tick()
+  }
+
+  pub fn reset(&mut self) {
+    self.position = 0;
+    self.tempo = DEFAULT_TEMPO;
+    self.update_step();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const RATE: u32 = 1024;
+
+  #[test]
+  fn ticks_per_second() {
+    let clock = TempoClock::new(Division::TicksPerQuarter(480), RATE);
+    assert_eq!(clock.ticks_per_sec(), 960);
+    let clock = TempoClock::new(
+      Division::Timecode {
+        fps: 25,
+        ticks_per_frame: 40,
+      },
+      RATE,
+    );
+    assert_eq!(clock.ticks_per_sec(), 1000);
+    let clock = TempoClock::new(
+      Division::Timecode {
+        fps: 29,
+        ticks_per_frame: 100,
+      },
+      RATE,
+    );
+    // 2997.003
+    assert_eq!(clock.ticks_per_sec(), 2997);
+  }
+
+  #[test]
+  fn timecode_ignores_tempo() {
+    let division = Division::Timecode {
+      fps: 30,
+      ticks_per_frame: 80,
+    };
+    let mut clock = TempoClock::new(division, RATE);
+    clock.set_tempo(DEFAULT_TEMPO / 2);
+    assert_eq!(clock.ticks_per_sec(), 2400);
+  }
+
+  // a tempo that doesn't divide evenly, played for as long as the
+  // fugue, ends up on the tick it should
+  #[test]
+  fn no_drift() {
+    let tempo = 521_739;
+    let mut clock = TempoClock::new(Division::TicksPerQuarter(96), RATE);
+    clock.set_tempo(tempo);
+    let secs = 166;
+    let mut tick = 0;
+    for _ in 0..secs * RATE {
+      tick = clock.advance();
+    }
+    let exact = secs as f64 * 96.0 * 1e6 / tempo as f64;
+    assert_eq!(tick, exact as u32);
+  }
+
+  // the times a song's events come up at, with the tempo slowing down
+  // over a few bars like at the end of the fugue. every event is played
+  // on the step after it's due, so it's late by less than a step, and
+  // the steps the tempo changes in don't add up.
+  #[test]
+  fn events_are_on_time() {
+    let tpq = 480;
+    // a tempo change every beat, from 115 bpm down to half that
+    let changes: std::vec::Vec<(u32, u32)> = (0..40)
+      .map(|beat| (beat * tpq, 60_000_000 / (115 - beat)))
+      .collect();
+    // a note every 7th of a beat, so they fall anywhere in a step
+    let notes = (0..40 * 7).map(|i| i * tpq / 7);
+
+    // the exact time of each note, in seconds
+    let time_of = |tick: u32| {
+      let mut time = 0.0;
+      let mut from = 0;
+      let mut tempo = DEFAULT_TEMPO;
+      for &(at, next) in changes.iter().take_while(|(at, _)| *at <= tick) {
+        time += (at - from) as f64 * tempo as f64 / 1e6 / tpq as f64;
+        from = at;
+        tempo = next;
+      }
+      time + (tick - from) as f64 * tempo as f64 / 1e6 / tpq as f64
+    };
+
+    let mut clock =
+      TempoClock::new(Division::TicksPerQuarter(tpq as u16), RATE);
+    let mut changes_left = changes.iter().peekable();
+    let mut worst: f64 = 0.0;
+    let mut step = 0;
+    for note in notes {
+      while clock.tick() < note {
+        clock.advance();
+        step += 1;
+        while let Some(&&(at, tempo)) = changes_left.peek() {
+          if at > clock.tick() {
+            break;
+          }
+          clock.set_tempo(tempo);
+          changes_left.next();
+        }
+      }
+      let late = step as f64 / RATE as f64 - time_of(note);
+      worst = worst.max(late.abs());
+    }
+    assert!(worst * (RATE as f64) < 1.0, "{}s", worst);
+  }
+}
@@ -0,0 +1,22 @@
+// helpers shared by the tests of the modules
+
+// xorshift, so the data is the same on every run
+pub struct Random(u32);
+
+impl Random {
+  pub fn new(seed: u32) -> Self {
+    Self(seed)
+  }
+
+  pub fn next(&mut self) -> u32 {
+    self.0 ^= self.0 << 13;
+    self.0 ^= self.0 >> 17;
+    self.0 ^= self.0 << 5;
+    self.0
+  }
+
+  // noise in [-amplitude, amplitude]
+  pub fn noise(&mut self, amplitude: i32) -> i32 {
+    (self.next() % (2 * amplitude as u32 + 1)) as i32 - amplitude
+  }
+}
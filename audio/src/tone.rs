@@ -0,0 +1,190 @@
+// the sine of the tone generator. the note holds until it's released,
+// with an envelope around it.
+
+use fixed::types::{U16F16, U8F8};
+
+use super::{
+  calibration::Profile,
+  dsp,
+  envelope::{Adsr, Envelope},
+  eq::{Equalizer, Filter},
+  graph::AudioSource,
+  osc::Osc,
+};
+
+pub struct Tone {
+  // midi key, 60 = middle C
+  note: u8,
+  // out of 127
+  volume: u8,
+  sample_rate: u32,
+  osc: Osc,
+  envelope: Envelope,
+  equalizer: Equalizer,
+  // speaker compensation from app_speaker_calibration, if any
+  profile: Option<Profile>,
+}
+
+impl Tone {
+  pub fn new(sample_rate: u32, profile: Option<Profile>) -> Self {
+    let mut tone = Self {
+      note: 60,
+      volume: 20,
+      sample_rate,
+      osc: Osc::new(sample_rate),
+      envelope: Envelope::new(Adsr::default(), sample_rate),
+      equalizer: Equalizer::new(),
+      profile,
+    };
+    tone.set_note(60);
+    tone
+  }
+
+  pub fn note(&self) -> u8 {
+    self.note
+  }
+
+  pub fn volume(&self) -> u8 {
+    self.volume
+  }
+
+  // starts the note over, from wherever the envelope is
+  pub fn set_note(&mut self, note: u8) {
+    self.note = note;
+    self.osc.set_fine_freq(self.freq());
+    self.envelope.trigger();
+  }
+
+  // fades the note out
+  pub fn release(&mut self) {
+    self.envelope.release();
+  }
+
+  // a note that's playing starts over with the new envelope
+  pub fn set_envelope(&mut self, adsr: Adsr) {
+    let playing = !self.envelope.is_released();
+    self.envelope = Envelope::new(adsr, self.sample_rate);
+    if playing {
+      self.envelope.trigger();
+    }
+  }
+
+  // the filters to run the samples through, e.g. eq::MLT_8530
+  pub fn set_equalizer(&mut self, filters: &[Filter]) {
+    self.equalizer = Equalizer::with_filters(filters, self.sample_rate);
+  }
+
+  pub fn set_volume(&mut self, volume: u8) {
+    self.volume = volume.min(127);
+  }
+
+  pub fn freq(&self) -> U16F16 {
+    dsp::key_to_freq(self.note)
+  }
+
+  // the speaker compensation at the note's frequency
+  fn gain(&self) -> U8F8 {
+    match self.profile.as_ref() {
+      Some(profile) => profile.gain_at(self.freq().to_num()),
+      None => U8F8::ONE,
+    }
+  }
+}
+
+impl AudioSource for Tone {
+  fn fill(&mut self, samples: &mut [i16]) {
+    let vol = self.gain().saturating_mul(U8F8::from_num(self.volume)) / 127;
+
+    for sample in samples.iter_mut() {
+      let x = dsp::apply_gain(self.osc.next_sample(), vol);
+      *sample = self.equalizer.process(self.envelope.apply(x));
+    }
+  }
+
+  fn is_silent(&self) -> bool {
+    self.envelope.is_idle()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::noise_shaping::{NoiseShaper, Order};
+  use std::{hint::black_box, time::Instant, vec};
+
+  const SAMPLE_RATE: u32 = 44000;
+  const COUNTERTOP: u16 = 90;
+  const SAMPLES: usize = 1 << 20;
+
+  // the loop of the tone generator before it moved to fixed point,
+  // 3.14159 and all
+  #[allow(clippy::approx_constant)]
+  fn fill_f32(
+    duty: &mut [u16],
+    offset: usize,
+    note: u8,
+    volume: u8,
+    shaper: &mut NoiseShaper,
+  ) {
+    let freq = 261.62558 * 1.0594631f32.powi(note as i32 - 60);
+    let period = (SAMPLE_RATE as f32 / freq) as usize;
+    let vol = volume as f32 / 127.0;
+    for (i, d) in duty.iter_mut().enumerate() {
+      let phase = ((offset + i) % period) as f32 / period as f32;
+      let x = (2.0 * 3.14159 * phase).sin() * vol * i16::MAX as f32;
+      *d = shaper.duty(x as i16, COUNTERTOP);
+    }
+  }
+
+  fn fill_fixed(duty: &mut [u16], tone: &mut Tone, shaper: &mut NoiseShaper) {
+    let mut samples = [0; 256];
+    for chunk in duty.chunks_mut(samples.len()) {
+      let samples = &mut samples[..chunk.len()];
+      tone.fill(samples);
+      for (d, &x) in chunk.iter_mut().zip(samples.iter()) {
+        *d = shaper.duty(x, COUNTERTOP);
+      }
+    }
+  }
+
+  fn ns_per_sample(mut run: impl FnMut(&mut [u16])) -> f64 {
+    let mut duty = vec![0; 256];
+    let start = Instant::now();
+    for _ in 0..SAMPLES / duty.len() {
+      run(black_box(&mut duty));
+    }
+    start.elapsed().as_nanos() as f64 / SAMPLES as f64
+  }
+
+  #[test]
+  fn volume_and_note() {
+    let mut tone = Tone::new(SAMPLE_RATE, None);
+    tone.set_volume(200);
+    assert_eq!(tone.volume(), 127);
+    tone.set_note(69);
+    assert_eq!(tone.freq(), U16F16::from_num(440));
+    assert!(!tone.is_silent());
+  }
+
+  // the figures in the readme, run with
+  //
+  //   cargo test --release --target x86_64-unknown-linux-gnu \
+  //     -- --ignored --nocapture tone_benchmark
+  #[test]
+  #[ignore]
+  fn tone_benchmark() {
+    let mut shaper = NoiseShaper::new(Order::Second);
+    let mut offset = 0;
+    let old = ns_per_sample(|duty| {
+      fill_f32(duty, offset, 60, 100, &mut shaper);
+      offset += duty.len();
+    });
+
+    let mut shaper = NoiseShaper::new(Order::Second);
+    let mut tone = Tone::new(SAMPLE_RATE, None);
+    tone.set_volume(100);
+    let new = ns_per_sample(|duty| fill_fixed(duty, &mut tone, &mut shaper));
+
+    std::println!("f32: {:.1}ns per sample, fixed: {:.1}ns", old, new);
+  }
+}
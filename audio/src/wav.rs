@@ -0,0 +1,636 @@
+// reading and writing the RIFF/WAVE container. the parser borrows the
+// sample data from the file, nothing is copied.
+//
+// supported encodings are 8-bit unsigned and 16-bit signed pcm, and
+// IMA ADPCM, in mono or stereo. stereo is mixed down to mono when
+// reading.
+
+use super::adpcm;
+
+const FORMAT_PCM: u16 = 0x0001;
+const FORMAT_IMA_ADPCM: u16 = 0x0011;
+const FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+// the longest header write_header produces
+pub const MAX_HEADER_LEN: usize = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+  U8,
+  I16,
+  // the bytes in a block, covering all channels
+  ImaAdpcm { block_align: u16 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Spec {
+  pub format: Format,
+  pub channels: u16,
+  pub sample_rate: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WavError {
+  // doesn't start with a RIFF/WAVE header
+  NotWave,
+  // a chunk runs past the end of the file
+  Truncated,
+  MissingFmt,
+  MissingData,
+  UnsupportedFormat(u16),
+  UnsupportedChannels(u16),
+  UnsupportedBitDepth(u16),
+  BadBlockAlign(u16),
+  BadSampleRate,
+}
+
+pub struct Wav<'a> {
+  pub spec: Spec,
+  pub data: &'a [u8],
+}
+
+impl<'a> Wav<'a> {
+  pub fn parse(bytes: &'a [u8]) -> Result<Self, WavError> {
+    if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+      return Err(WavError::NotWave);
+    }
+
+    let mut spec = None;
+    let mut rest = &bytes[12..];
+
+    // chunks are an id, a length and the body padded to an even length
+    while rest.len() >= 8 {
+      let id = &rest[..4];
+      let len = u32_at(rest, 4) as usize;
+      let body = rest[8..].get(..len).ok_or(WavError::Truncated)?;
+
+      match id {
+        b"fmt " => spec = Some(parse_fmt(body)?),
+        b"data" => {
+          let spec = spec.ok_or(WavError::MissingFmt)?;
+          // drop a partial frame at the end
+          let frame = spec.frame_bytes();
+          let data = &body[..body.len() / frame * frame];
+          return Ok(Self { spec, data });
+        }
+        _ => {}
+      }
+
+      rest = rest.get(8 + len + len % 2..).unwrap_or(&[]);
+    }
+
+    match spec {
+      Some(_) => Err(WavError::MissingData),
+      None => Err(WavError::MissingFmt),
+    }
+  }
+
+  // the number of samples per channel
+  pub fn frames(&self) -> usize {
+    match self.spec.format {
+      Format::ImaAdpcm { block_align } => adpcm::sample_count(
+        self.data.len(),
+        block_align as usize,
+        self.spec.channels as usize,
+      ),
+      _ => self.data.len() / self.spec.frame_bytes(),
+    }
+  }
+
+  pub fn duration_ms(&self) -> u32 {
+    (self.frames() as u64 * 1000 / self.spec.sample_rate as u64) as u32
+  }
+
+  pub fn reader(&self) -> Reader<'a> {
+    let channels = self.spec.channels as usize;
+    match self.spec.format {
+      Format::U8 => Reader::U8 {
+        data: self.data,
+        channels,
+      },
+      Format::I16 => Reader::I16 {
+        data: self.data,
+        channels,
+      },
+      Format::ImaAdpcm { block_align } => Reader::Adpcm(
+        adpcm::Reader::with_layout(self.data, block_align as usize, channels),
+      ),
+    }
+  }
+}
+
+impl Spec {
+  // the unit the data chunk is made of
+  fn frame_bytes(&self) -> usize {
+    match self.format {
+      Format::U8 => self.channels as usize,
+      Format::I16 => self.channels as usize * 2,
+      Format::ImaAdpcm { .. } => 1,
+    }
+  }
+
+  // writes the header for data_len bytes of sample data into out,
+  // which must be MAX_HEADER_LEN long. returns the length of the
+  // header, the data goes right after it.
+  pub fn write_header(&self, data_len: u32, out: &mut [u8]) -> usize {
+    let channels = self.channels;
+    let (tag, bits, block_align, byte_rate) = match self.format {
+      Format::U8 => {
+        (FORMAT_PCM, 8, channels, self.sample_rate * channels as u32)
+      }
+      Format::I16 => (
+        FORMAT_PCM,
+        16,
+        channels * 2,
+        self.sample_rate * channels as u32 * 2,
+      ),
+      Format::ImaAdpcm { block_align } => {
+        let samples =
+          adpcm::block_samples(block_align as usize, channels as usize);
+        let rate =
+          self.sample_rate as u64 * block_align as u64 / samples as u64;
+        (FORMAT_IMA_ADPCM, 4, block_align, rate as u32)
+      }
+    };
+
+    let mut w = Writer { out, pos: 0 };
+    w.bytes(b"RIFF");
+    // filled in at the end
+    w.u32(0);
+    w.bytes(b"WAVE");
+
+    w.bytes(b"fmt ");
+    w.u32(if tag == FORMAT_IMA_ADPCM { 20 } else { 16 });
+    w.u16(tag);
+    w.u16(channels);
+    w.u32(self.sample_rate);
+    w.u32(byte_rate);
+    w.u16(block_align);
+    w.u16(bits);
+
+    if let Format::ImaAdpcm { block_align } = self.format {
+      let samples =
+        adpcm::block_samples(block_align as usize, channels as usize);
+
+      // the extra bytes, holding the samples per block
+      w.u16(2);
+      w.u16(samples as u16);
+
+      // compressed formats need the number of samples per channel
+      let frames = adpcm::sample_count(
+        data_len as usize,
+        block_align as usize,
+        channels as usize,
+      );
+      w.bytes(b"fact");
+      w.u32(4);
+      w.u32(frames as u32);
+    }
+
+    w.bytes(b"data");
+    w.u32(data_len);
+
+    let len = w.pos;
+    let riff_len = (len as u32 - 8) + data_len + data_len % 2;
+    out[4..8].copy_from_slice(&riff_len.to_le_bytes());
+    len
+  }
+}
+
+fn parse_fmt(body: &[u8]) -> Result<Spec, WavError> {
+  if body.len() < 16 {
+    return Err(WavError::Truncated);
+  }
+
+  let mut tag = u16_at(body, 0);
+  let channels = u16_at(body, 2);
+  let sample_rate = u32_at(body, 4);
+  let block_align = u16_at(body, 12);
+  let bits = u16_at(body, 14);
+
+  // the actual format is the first two bytes of the sub format guid
+  if tag == FORMAT_EXTENSIBLE {
+    if body.len() < 26 {
+      return Err(WavError::Truncated);
+    }
+    tag = u16_at(body, 24);
+  }
+
+  if !(1..=adpcm::MAX_CHANNELS as u16).contains(&channels) {
+    return Err(WavError::UnsupportedChannels(channels));
+  }
+  if sample_rate == 0 {
+    return Err(WavError::BadSampleRate);
+  }
+
+  let format = match (tag, bits) {
+    (FORMAT_PCM, 8) => Format::U8,
+    (FORMAT_PCM, 16) => Format::I16,
+    (FORMAT_IMA_ADPCM, 4) => Format::ImaAdpcm { block_align },
+    (FORMAT_PCM | FORMAT_IMA_ADPCM, _) => {
+      return Err(WavError::UnsupportedBitDepth(bits))
+    }
+    _ => return Err(WavError::UnsupportedFormat(tag)),
+  };
+
+  let aligned = match format {
+    Format::U8 => block_align == channels,
+    Format::I16 => block_align == channels * 2,
+    // the headers and at least one word of samples per channel, in
+    // whole words
+    Format::ImaAdpcm { .. } => {
+      let words = block_align / 4;
+      block_align.is_multiple_of(4)
+        && words >= channels * 2
+        && words.is_multiple_of(channels)
+    }
+  };
+  if !aligned {
+    return Err(WavError::BadBlockAlign(block_align));
+  }
+
+  Ok(Spec {
+    format,
+    channels,
+    sample_rate,
+  })
+}
+
+// random access to the samples of a file, mixed down to mono
+pub enum Reader<'a> {
+  U8 { data: &'a [u8], channels: usize },
+  I16 { data: &'a [u8], channels: usize },
+  Adpcm(adpcm::Reader<'a>),
+}
+
+impl Reader<'_> {
+  // the number of samples per channel
+  pub fn len(&self) -> usize {
+    match self {
+      Reader::U8 { data, channels } => data.len() / channels,
+      Reader::I16 { data, channels } => data.len() / (channels * 2),
+      Reader::Adpcm(reader) => reader.len(),
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn sample_at(&mut self, idx: usize) -> i16 {
+    match self {
+      Reader::U8 { data, channels } => {
+        let frame = &data[idx * *channels..][..*channels];
+        let sum: i32 = frame.iter().map(|&x| (x as i32 - 128) << 8).sum();
+        (sum / *channels as i32) as i16
+      }
+      Reader::I16 { data, channels } => {
+        let frame = &data[idx * *channels * 2..][..*channels * 2];
+        let sum: i32 = frame
+          .chunks_exact(2)
+          .map(|x| i16::from_le_bytes([x[0], x[1]]) as i32)
+          .sum();
+        (sum / *channels as i32) as i16
+      }
+      Reader::Adpcm(reader) => reader.sample_at(idx),
+    }
+  }
+}
+
+struct Writer<'a> {
+  out: &'a mut [u8],
+  pos: usize,
+}
+
+impl Writer<'_> {
+  fn bytes(&mut self, bytes: &[u8]) {
+    self.out[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+    self.pos += bytes.len();
+  }
+
+  fn u16(&mut self, x: u16) {
+    self.bytes(&x.to_le_bytes());
+  }
+
+  fn u32(&mut self, x: u32) {
+    self.bytes(&x.to_le_bytes());
+  }
+}
+
+fn u16_at(bytes: &[u8], i: usize) -> u16 {
+  u16::from_le_bytes([bytes[i], bytes[i + 1]])
+}
+
+fn u32_at(bytes: &[u8], i: usize) -> u32 {
+  u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{vec, vec::Vec};
+
+  use super::*;
+
+  // the software tag ffmpeg puts in a LIST chunk
+  const LAVF: &[u8] = b"Lavf60.16.100\0";
+
+  // files written by other programs, see fixtures/wav/README
+  const FFMPEG_S16: &[u8] =
+    include_bytes!("../fixtures/wav/ffmpeg-lavf56-s16-mono.wav");
+  const FFMPEG_S16_CBSIZE: &[u8] =
+    include_bytes!("../fixtures/wav/ffmpeg-lavf54-s16-mono-cbsize.wav");
+  const EXTENSIBLE_S16: &[u8] =
+    include_bytes!("../fixtures/wav/hound-extensible-s16-mono.wav");
+  const EXTENSIBLE_S24: &[u8] =
+    include_bytes!("../fixtures/wav/hound-extensible-s24-mono.wav");
+
+  fn chunk(out: &mut Vec<u8>, id: &[u8], body: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(body);
+    if body.len() % 2 == 1 {
+      out.push(0);
+    }
+  }
+
+  // a file with the chunks in the order ffmpeg's wav muxer writes
+  // them: fmt, a LIST chunk with the encoder, fact for compressed
+  // formats, then data
+  fn wav_file(
+    tag: u16,
+    channels: u16,
+    rate: u32,
+    bits: u16,
+    block_align: u16,
+    data: &[u8],
+  ) -> Vec<u8> {
+    let mut fmt = Vec::new();
+    fmt.extend_from_slice(&tag.to_le_bytes());
+    fmt.extend_from_slice(&channels.to_le_bytes());
+    fmt.extend_from_slice(&rate.to_le_bytes());
+    let byte_rate = rate * block_align as u32;
+    fmt.extend_from_slice(&byte_rate.to_le_bytes());
+    fmt.extend_from_slice(&block_align.to_le_bytes());
+    fmt.extend_from_slice(&bits.to_le_bytes());
+    if tag == FORMAT_IMA_ADPCM {
+      let samples_per_block =
+        adpcm::block_samples(block_align as usize, channels as usize);
+      fmt.extend_from_slice(&2u16.to_le_bytes());
+      fmt.extend_from_slice(&(samples_per_block as u16).to_le_bytes());
+    }
+
+    let mut info = b"INFOISFT".to_vec();
+    info.extend_from_slice(&(LAVF.len() as u32).to_le_bytes());
+    info.extend_from_slice(LAVF);
+
+    let mut body = b"WAVE".to_vec();
+    chunk(&mut body, b"fmt ", &fmt);
+    chunk(&mut body, b"LIST", &info);
+    if tag == FORMAT_IMA_ADPCM {
+      let frames = adpcm::sample_count(
+        data.len(),
+        block_align as usize,
+        channels as usize,
+      );
+      chunk(&mut body, b"fact", &(frames as u32).to_le_bytes());
+    }
+    chunk(&mut body, b"data", data);
+
+    let mut file = Vec::new();
+    chunk(&mut file, b"RIFF", &body);
+    file
+  }
+
+  fn samples(reader: &mut Reader) -> Vec<i16> {
+    (0..reader.len()).map(|i| reader.sample_at(i)).collect()
+  }
+
+  #[test]
+  fn reads_files_written_by_ffmpeg() {
+    for file in [FFMPEG_S16, FFMPEG_S16_CBSIZE] {
+      let wav = Wav::parse(file).unwrap();
+      assert_eq!(
+        wav.spec,
+        Spec {
+          format: Format::I16,
+          channels: 1,
+          sample_rate: 44100
+        }
+      );
+      assert_eq!(samples(&mut wav.reader()), [2, -3, 5, -7]);
+    }
+  }
+
+  #[test]
+  fn reads_the_sub_format_of_extensible_files() {
+    let wav = Wav::parse(EXTENSIBLE_S16).unwrap();
+    assert_eq!(wav.spec.format, Format::I16);
+    assert_eq!(wav.spec.sample_rate, 44100);
+    let pop = samples(&mut wav.reader());
+    assert_eq!(pop.len(), 100);
+    assert_eq!(pop[..4], [0, 2052, 4097, 6126]);
+    assert_eq!(pop[96..], [-8582, -6584, -4560, -2518]);
+
+    let error = Wav::parse(EXTENSIBLE_S24).err();
+    assert_eq!(error, Some(WavError::UnsupportedBitDepth(24)));
+  }
+
+  // newer versions of ffmpeg tag their files with an odd length
+  // string, e.g. Lavf61.7.100, and pad the LIST chunk to an even
+  // length. the ffmpeg file with its tag swapped for one of those.
+  #[test]
+  fn skips_the_pad_byte_of_odd_chunks() {
+    let (head, rest) = FFMPEG_S16.split_at(12 + 8 + 16);
+    assert_eq!(&rest[..4], b"LIST");
+    let list_len = u32_at(rest, 4) as usize;
+    let data = &rest[8 + list_len..];
+    assert_eq!(&data[..4], b"data");
+
+    let tag = b"Lavf61.7.100\0";
+    let mut info = b"INFOISFT".to_vec();
+    info.extend_from_slice(&(tag.len() as u32).to_le_bytes());
+    info.extend_from_slice(tag);
+    assert_eq!(info.len() % 2, 1);
+
+    let mut file = head.to_vec();
+    chunk(&mut file, b"LIST", &info);
+    file.extend_from_slice(data);
+    let riff_len = file.len() as u32 - 8;
+    file[4..8].copy_from_slice(&riff_len.to_le_bytes());
+
+    let wav = Wav::parse(&file).unwrap();
+    assert_eq!(samples(&mut wav.reader()), [2, -3, 5, -7]);
+
+    // without the pad byte the data chunk is out of step
+    let at = file.len() - data.len() - 1;
+    file.remove(at);
+    assert!(Wav::parse(&file).is_err());
+  }
+
+  #[test]
+  fn reads_u8_mono() {
+    let data = [0x80, 0xff, 0x00, 0x90, 0x70];
+    let file = wav_file(FORMAT_PCM, 1, 8000, 8, 1, &data);
+    let wav = Wav::parse(&file).unwrap();
+    assert_eq!(
+      wav.spec,
+      Spec {
+        format: Format::U8,
+        channels: 1,
+        sample_rate: 8000
+      }
+    );
+    assert_eq!(wav.frames(), 5);
+    assert_eq!(
+      samples(&mut wav.reader()),
+      [0, 127 << 8, -128 << 8, 16 << 8, -16 << 8]
+    );
+  }
+
+  #[test]
+  fn mixes_i16_stereo_down() {
+    let frames: [[i16; 2]; 4] =
+      [[1000, 3000], [-32768, -32768], [32767, -32768], [7, 0]];
+    let data: Vec<u8> = frames
+      .iter()
+      .flatten()
+      .flat_map(|x| x.to_le_bytes())
+      .collect();
+    // a partial frame at the end is dropped
+    let mut with_tail = data.clone();
+    with_tail.push(0x12);
+    let file = wav_file(FORMAT_PCM, 2, 44100, 16, 4, &with_tail);
+
+    let wav = Wav::parse(&file).unwrap();
+    assert_eq!(wav.spec.format, Format::I16);
+    assert_eq!(wav.spec.sample_rate, 44100);
+    assert_eq!(wav.data, &data[..]);
+    assert_eq!(samples(&mut wav.reader()), [2000, -32768, 0, 3]);
+    assert_eq!(wav.duration_ms(), 0);
+  }
+
+  // stereo in 1024 byte blocks
+  #[test]
+  fn reads_ima_adpcm_stereo() {
+    let block_align = 1024;
+    let mut data = vec![0u8; 2 * block_align];
+    for (i, byte) in data.iter_mut().enumerate() {
+      *byte = (i * 7 % 251) as u8;
+    }
+    for block in data.chunks_mut(block_align) {
+      // left at 1000, right at -1000, both with step index 20
+      block[..8].copy_from_slice(&[0xe8, 0x03, 20, 0, 0x18, 0xfc, 20, 0]);
+    }
+    let file =
+      wav_file(FORMAT_IMA_ADPCM, 2, 22050, 4, block_align as u16, &data);
+
+    let wav = Wav::parse(&file).unwrap();
+    assert_eq!(wav.spec.format, Format::ImaAdpcm { block_align: 1024 });
+    assert_eq!(wav.frames(), 2 * 1017);
+    let mut reader = wav.reader();
+    assert_eq!(reader.len(), 2 * 1017);
+    // the header samples mix down to 0
+    assert_eq!(reader.sample_at(0), 0);
+    assert_eq!(reader.sample_at(1017), 0);
+
+    let mut expected = adpcm::Reader::with_layout(&data, block_align, 2);
+    for i in 0..reader.len() {
+      assert_eq!(reader.sample_at(i), expected.sample_at(i));
+    }
+  }
+
+  #[test]
+  fn write_header_round_trips() {
+    for format in [
+      Format::U8,
+      Format::I16,
+      Format::ImaAdpcm {
+        block_align: adpcm::BLOCK_BYTES as u16,
+      },
+    ] {
+      for channels in [1, 2] {
+        let spec = Spec {
+          format,
+          channels,
+          sample_rate: 11025,
+        };
+        if matches!(format, Format::ImaAdpcm { .. }) && channels == 2 {
+          continue;
+        }
+        let data = [0x55; 600];
+        let mut file = vec![0; MAX_HEADER_LEN];
+        let len = spec.write_header(data.len() as u32, &mut file);
+        file.truncate(len);
+        file.extend_from_slice(&data);
+
+        let wav = Wav::parse(&file).unwrap();
+        assert_eq!(wav.spec, spec);
+        assert_eq!(
+          wav.data.len(),
+          600 / spec.frame_bytes() * spec.frame_bytes()
+        );
+        assert_eq!(u32_at(&file, 4) as usize, file.len() - 8);
+      }
+    }
+  }
+
+  fn error(file: &[u8]) -> WavError {
+    Wav::parse(file).err().unwrap()
+  }
+
+  #[test]
+  fn malformed_headers_are_errors() {
+    let good = wav_file(FORMAT_PCM, 1, 8000, 16, 2, &[0; 8]);
+    assert!(Wav::parse(&good).is_ok());
+
+    assert_eq!(error(b"RIFX\0\0\0\0WAVE"), WavError::NotWave);
+    assert_eq!(error(&good[..11]), WavError::NotWave);
+    assert_eq!(error(&good[..good.len() - 1]), WavError::Truncated);
+    assert_eq!(error(&good[..30]), WavError::Truncated);
+
+    let mut data_first = b"WAVE".to_vec();
+    chunk(&mut data_first, b"data", &[0; 4]);
+    let mut file = Vec::new();
+    chunk(&mut file, b"RIFF", &data_first);
+    assert_eq!(error(&file), WavError::MissingFmt);
+
+    // the data chunk id mangled
+    let mut no_data = good.clone();
+    let at = no_data.len() - 16;
+    no_data[at..at + 4].copy_from_slice(b"junk");
+    assert_eq!(error(&no_data), WavError::MissingData);
+
+    let float = wav_file(3, 1, 8000, 32, 4, &[0; 8]);
+    assert_eq!(error(&float), WavError::UnsupportedFormat(3));
+    let s24 = wav_file(FORMAT_PCM, 1, 8000, 24, 3, &[0; 9]);
+    assert_eq!(error(&s24), WavError::UnsupportedBitDepth(24));
+    let surround = wav_file(FORMAT_PCM, 6, 8000, 16, 12, &[0; 12]);
+    assert_eq!(error(&surround), WavError::UnsupportedChannels(6));
+    let silent = wav_file(FORMAT_PCM, 1, 0, 16, 2, &[0; 8]);
+    assert_eq!(error(&silent), WavError::BadSampleRate);
+    let misaligned = wav_file(FORMAT_PCM, 2, 8000, 16, 2, &[0; 8]);
+    assert_eq!(error(&misaligned), WavError::BadBlockAlign(2));
+    let adpcm = wav_file(FORMAT_IMA_ADPCM, 2, 8000, 4, 12, &[0; 24]);
+    assert_eq!(error(&adpcm), WavError::BadBlockAlign(12));
+  }
+
+  // none of the prefixes and byte flips of a file may panic
+  #[test]
+  fn survives_broken_files() {
+    let file = wav_file(FORMAT_IMA_ADPCM, 1, 8000, 4, 256, &[0x3c; 700]);
+    for len in 0..file.len() {
+      if let Ok(wav) = Wav::parse(&file[..len]) {
+        samples(&mut wav.reader());
+      }
+    }
+    for i in 0..80 {
+      for bit in 0..8 {
+        let mut broken = file.clone();
+        broken[i] ^= 1 << bit;
+        if let Ok(wav) = Wav::parse(&broken) {
+          samples(&mut wav.reader());
+        }
+      }
+    }
+  }
+}
@@ -1,26 +1,256 @@
 use std::env;
-use std::fs::File;
+use std::fmt::Write as _;
+use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-fn linker_data() -> Option<&'static [u8]> {
+use microbity_audio::{adpcm, wav};
+use midly::{
+  num::u28, Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent,
+  TrackEventKind,
+};
+
+#[path = "src/assets/crc32.rs"]
+mod crc32;
+
+const ASSET_DIR: &str = "assets";
+
+// both layouts keep the last page of the flash free for the settings
+fn linker_data() -> &'static [u8] {
   #[cfg(feature = "softdevice")]
-  return Some(include_bytes!("memory-nrf52833-with-softdevice.x"));
+  return include_bytes!("memory-nrf52833-with-softdevice.x");
 
   #[cfg(not(feature = "softdevice"))]
-  return None;
+  return include_bytes!("memory.x");
+}
+
+// the constant for a file, e.g. BAD_APPLE for bad-apple.wav
+fn const_name(stem: &str, kind: &str) -> String {
+  let name: String = stem
+    .chars()
+    .map(|c| match c {
+      'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+      _ => '_',
+    })
+    .collect();
+
+  if name.starts_with(|c: char| c.is_ascii_digit()) {
+    format!("{}_{}", kind, name)
+  } else {
+    name
+  }
+}
+
+fn include_out(file: &str) -> String {
+  format!("include_bytes!(concat!(env!(\"OUT_DIR\"), \"/{}\"))", file)
+}
+
+// compress a wav file into a mono ima adpcm wav file
+fn encode_adpcm(src: &Path, dst: &Path) -> Vec<u8> {
+  let bytes = fs::read(src).unwrap();
+  let wav = wav::Wav::parse(&bytes)
+    .unwrap_or_else(|e| panic!("{}: bad wav file: {:?}", src.display(), e));
+  let mut reader = wav.reader();
+  let samples: Vec<i16> =
+    (0..reader.len()).map(|i| reader.sample_at(i)).collect();
+
+  let mut encoded = vec![0; adpcm::encoded_len(samples.len())];
+  adpcm::encode(&samples, &mut encoded);
+
+  let spec = wav::Spec {
+    format: wav::Format::ImaAdpcm {
+      block_align: adpcm::BLOCK_BYTES as u16,
+    },
+    channels: 1,
+    sample_rate: wav.spec.sample_rate,
+  };
+  let mut header = [0; wav::MAX_HEADER_LEN];
+  let len = spec.write_header(encoded.len() as u32, &mut header);
+
+  let mut file = header[..len].to_vec();
+  file.extend_from_slice(&encoded);
+  // keep the riff chunk even
+  if encoded.len() % 2 == 1 {
+    file.push(0);
+  }
+  fs::write(dst, &file).unwrap();
+  file
+}
+
+fn audio_asset(src: &Path, out: &Path, stem: &str, name: &str) -> String {
+  let file_name = format!("{}.wav", stem);
+  let file = encode_adpcm(src, &out.join(&file_name));
+  let wav = wav::Wav::parse(&file).unwrap();
+
+  format!(
+    "pub const {}: Audio = Audio {{
+  name: {:?},
+  wav: {},
+  format: Format::{:?},
+  sample_rate: {},
+  frames: {},
+  checksum: {:#010x},
+}};
+",
+    name,
+    stem,
+    include_out(&file_name),
+    wav.spec.format,
+    wav.spec.sample_rate,
+    wav.frames(),
+    crc32::crc32(&file),
+  )
+}
+
+// merge the tracks of a midi file into a single track, keeping only
+// what the player uses. note offs become note ons with velocity 0, so
+// runs of notes on a channel share one status byte.
+fn compile_midi(src: &Path, dst: &Path) -> (Vec<u8>, Timing, usize, u32) {
+  let bytes = fs::read(src).unwrap();
+  let smf = Smf::parse(&bytes)
+    .unwrap_or_else(|e| panic!("{}: bad midi file: {}", src.display(), e));
+  if smf.header.format == Format::Sequential {
+    // the tracks are separate songs, merging them makes no sense
+    panic!("{}: format 2 midi files are not supported", src.display());
+  }
+
+  let mut events = Vec::new();
+  let mut end = 0;
+  for track in smf.tracks.iter() {
+    let mut tick = 0;
+    for event in track {
+      tick += event.delta.as_int();
+      let kind = match event.kind {
+        TrackEventKind::Midi {
+          channel,
+          message: MidiMessage::NoteOff { key, .. },
+        } => TrackEventKind::Midi {
+          channel,
+          message: MidiMessage::NoteOn { key, vel: 0.into() },
+        },
+        kind @ TrackEventKind::Midi { .. } => kind,
+        kind @ TrackEventKind::Meta(MetaMessage::Tempo(_)) => kind,
+        _ => continue,
+      };
+      events.push((tick, kind));
+    }
+    end = end.max(tick);
+  }
+
+  // stable, so events at the same tick stay in track order
+  events.sort_by_key(|(tick, _)| *tick);
+
+  let mut merged = Vec::with_capacity(events.len() + 1);
+  let mut last = 0;
+  for (tick, kind) in events.iter() {
+    merged.push(TrackEvent {
+      delta: u28::new(tick - last),
+      kind: *kind,
+    });
+    last = *tick;
+  }
+  merged.push(TrackEvent {
+    delta: u28::new(end - last),
+    kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+  });
+
+  let compiled = Smf {
+    header: Header::new(Format::SingleTrack, smf.header.timing),
+    tracks: vec![merged],
+  };
+  let mut file = Vec::new();
+  compiled.write_std(&mut file).unwrap();
+  fs::write(dst, &file).unwrap();
+
+  (file, smf.header.timing, events.len(), end)
+}
+
+fn midi_asset(src: &Path, out: &Path, stem: &str, name: &str) -> String {
+  let file_name = format!("{}.mid", stem);
+  let (file, timing, events, ticks) = compile_midi(src, &out.join(&file_name));
+
+  let division = match timing {
+    Timing::Metrical(n) => format!("Division::TicksPerQuarter({})", n),
+    Timing::Timecode(fps, n) => format!(
+      "Division::Timecode {{ fps: {}, ticks_per_frame: {} }}",
+      fps.as_int(),
+      n
+    ),
+  };
+
+  format!(
+    "pub const {}: Midi = Midi {{
+  name: {:?},
+  smf: {},
+  division: {},
+  events: {},
+  ticks: {},
+  checksum: {:#010x},
+}};
+",
+    name,
+    stem,
+    include_out(&file_name),
+    division,
+    events,
+    ticks,
+    crc32::crc32(&file),
+  )
+}
+
+// turn every file in assets/ into a constant in the assets module
+fn build_assets(out: &Path) {
+  let mut paths: Vec<PathBuf> = fs::read_dir(ASSET_DIR)
+    .unwrap()
+    .map(|entry| entry.unwrap().path())
+    .collect();
+  paths.sort();
+
+  let mut code =
+    String::from("// generated by build.rs from the files in assets/\n\n");
+  let mut names = Vec::new();
+
+  for path in paths.iter() {
+    let stem = path.file_stem().unwrap().to_str().unwrap();
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    let kind = match ext.to_ascii_lowercase().as_str() {
+      "wav" => "AUDIO",
+      "mid" | "midi" => "MIDI",
+      // e.g. the readme
+      _ => continue,
+    };
+
+    let name = const_name(stem, kind);
+    if names.contains(&name) {
+      panic!("{}: another asset is also called {}", path.display(), name);
+    }
+
+    let asset = match kind {
+      "AUDIO" => audio_asset(path, out, stem, &name),
+      _ => midi_asset(path, out, stem, &name),
+    };
+    names.push(name);
+
+    writeln!(code, "{}", asset).unwrap();
+  }
+
+  fs::write(out.join("assets.rs"), code).unwrap();
+  println!("cargo:rerun-if-changed={}", ASSET_DIR);
 }
 
 fn main() {
   // Put `memory.x` in our output directory and ensure it's
   // on the linker search path.
   let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
-  if let Some(data) = linker_data() {
-    File::create(out.join("memory.x"))
-      .unwrap()
-      .write_all(data)
-      .unwrap();
-    println!("cargo:rustc-link-search={}", out.display());
-    println!("cargo:rerun-if-changed=memory.x");
-  }
+  File::create(out.join("memory.x"))
+    .unwrap()
+    .write_all(linker_data())
+    .unwrap();
+  println!("cargo:rustc-link-search={}", out.display());
+  println!("cargo:rerun-if-changed=memory.x");
+  println!("cargo:rerun-if-changed=memory-nrf52833-with-softdevice.x");
+
+  build_assets(out);
+  println!("cargo:rerun-if-changed=src/assets/crc32.rs");
+  println!("cargo:rerun-if-changed=build.rs");
 }
@@ -9,6 +9,7 @@ MEMORY
 {
   /* https://infocenter.nordicsemi.com/topic/sds_s113/SDS/s1xx/mem_usage/mem_resource_map_usage.html */
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x00000000 + 112K, LENGTH = 512K - 112K
+  /* the last page of the flash holds the settings, see src/raw/flash.rs */
+  FLASH : ORIGIN = 0x00000000 + 112K, LENGTH = 512K - 112K - 4K
   RAM : ORIGIN = 0x20000000 + 8K, LENGTH = 128K - 8K
 }
@@ -0,0 +1,7 @@
+MEMORY
+{
+  /* NOTE 1 K = 1 KiBi = 1024 bytes */
+  /* the last page of the flash holds the settings, see src/raw/flash.rs */
+  FLASH : ORIGIN = 0x00000000, LENGTH = 512K - 4K
+  RAM : ORIGIN = 0x20000000, LENGTH = 128K
+}
@@ -0,0 +1,210 @@
+use core::{cell::RefCell, fmt::Write};
+
+use cortex_m::{
+  interrupt::{free, CriticalSection, Mutex},
+  peripheral::NVIC,
+};
+use heapless::String;
+use microbit::{
+  hal::{
+    gpio::Level,
+    prelude::{_embedded_hal_timer_CountDown, InputPin},
+    Timer,
+  },
+  pac::{interrupt, pwm0::prescaler::PRESCALER_A, UARTE0},
+  Board,
+};
+use rtt_target::rprintln;
+use static_cell::StaticCell;
+
+use crate::{
+  audio::{
+    agc::{AgcConfig, MicChain},
+    graph::PwmSink,
+    modem::{Arq, ArqEvent, Demodulator, Frame, Modulator},
+    noise_shaping::{NoiseShaper, Order},
+  },
+  raw::{sequencer::PwmSequencer, speaker::SpeakerDrive, Microphone, Serial},
+};
+
+const PWM_PRESCALER: PRESCALER_A = PRESCALER_A::DIV_4;
+const PWM_CLOCK_FREQ: u32 = 1 << (24 - (PWM_PRESCALER as u8));
+const PWM_COUNTERTOP: u16 = (PWM_CLOCK_FREQ / 44000) as u16;
+// the actual rate after rounding the countertop
+const SAMPLE_RATE: u32 = PWM_CLOCK_FREQ / PWM_COUNTERTOP as u32;
+const BUF_LEN: usize = 64;
+
+// the built-in speaker
+const DRIVE: SpeakerDrive = SpeakerDrive::Single;
+const SAMPLES_PER_BUF: usize = BUF_LEN / DRIVE.values_per_sample();
+
+// keeps the rounding noise of the short countertop away from the 2 and
+// 2.4 kHz tones the demodulator listens for
+static SINK: Mutex<RefCell<PwmSink>> = Mutex::new(RefCell::new(PwmSink::new(
+  PWM_COUNTERTOP,
+  DRIVE.silence(PWM_COUNTERTOP),
+  NoiseShaper::new(Order::Second),
+)));
+
+const MIC_SAMPLE_RATE: u32 = 8000;
+const MIC_BLOCK_LEN: usize = 64;
+const MIC_BLOCK_MS: u32 = MIC_BLOCK_LEN as u32 * 1000 / MIC_SAMPLE_RATE;
+
+// how long to wait for an ack after our frame is sent. an ack takes
+// about 1.5 seconds at 50 baud.
+const ACK_TIMEOUT_MS: u32 = 3000;
+
+static BUFFERS: StaticCell<[[u16; BUF_LEN]; 2]> = StaticCell::new();
+
+type Sequencer = PwmSequencer<BUF_LEN>;
+static SEQUENCER: Mutex<RefCell<Option<Sequencer>>> =
+  Mutex::new(RefCell::new(None));
+
+static MODULATOR: Mutex<RefCell<Option<Modulator>>> =
+  Mutex::new(RefCell::new(None));
+
+pub fn run() -> ! {
+  let mut board = Board::take().unwrap();
+  let mut serial = Serial::setup(board.UARTE0, board.uart);
+
+  let speaker_pin = board
+    .speaker_pin
+    .into_push_pull_output(Level::Low)
+    .degrade();
+  let button_a = board.buttons.button_a.into_floating_input().degrade();
+
+  let mut microphone = Microphone::setup(board.SAADC, board.microphone_pins);
+  microphone.set_chain(MicChain::new(None, Some(AgcConfig::default())));
+  let mut ticker = Timer::periodic(board.TIMER0);
+  ticker.start(1_000_000 / MIC_SAMPLE_RATE);
+
+  free(|cs| {
+    MODULATOR
+      .borrow(cs)
+      .replace(Some(Modulator::new(SAMPLE_RATE)))
+  });
+
+  let buffers = BUFFERS.init([[0; BUF_LEN]; 2]);
+  let mut sequencer = PwmSequencer::new(board.PWM0, buffers);
+  setup_pwm(&sequencer, speaker_pin.psel_bits());
+  free(|cs| {
+    sequencer.start(|buffer| fill_buffer(buffer, cs));
+    SEQUENCER.borrow(cs).replace(Some(sequencer));
+  });
+
+  unsafe {
+    board.NVIC.set_priority(interrupt::PWM0, 10);
+    NVIC::unmask(interrupt::PWM0);
+  }
+
+  let mut demodulator = Demodulator::new(MIC_SAMPLE_RATE);
+  let mut arq = Arq::new(ACK_TIMEOUT_MS);
+  let mut block = [0i16; MIC_BLOCK_LEN];
+  let mut a_was_pressed = false;
+  let mut message_no = 0u32;
+
+  loop {
+    microphone.read_block(&mut ticker, &mut block);
+
+    // the mic hears our own transmission, don't listen while sending
+    // and hold the ack timeout until the frame is out
+    if transmitting() {
+      demodulator.reset();
+      continue;
+    }
+
+    match demodulator.process(&block) {
+      Some(Ok(frame)) => {
+        for event in arq.on_frame(frame) {
+          handle_event(event, &mut serial);
+        }
+      }
+      Some(Err(e)) => rprintln!("bad frame: {:?}", e),
+      None => {}
+    }
+
+    if let Some(event) = arq.tick(MIC_BLOCK_MS) {
+      handle_event(event, &mut serial);
+    }
+
+    let a_pressed = button_a.is_low().unwrap();
+    if a_pressed && !a_was_pressed {
+      let mut message: String<16> = String::new();
+      write!(&mut message, "hello {}", message_no).unwrap();
+
+      if let Some(event) = arq.send(message.as_bytes()) {
+        message_no += 1;
+        handle_event(event, &mut serial);
+      }
+    }
+    a_was_pressed = a_pressed;
+  }
+}
+
+fn handle_event(event: ArqEvent, serial: &mut Serial<UARTE0>) {
+  match event {
+    ArqEvent::Transmit(frame) => transmit(&frame),
+    ArqEvent::Received(payload) => {
+      let text = core::str::from_utf8(&payload).unwrap_or("<binary>");
+      rprintln!("received: {}", text);
+      serial.send_str(text);
+      serial.send_str("\r\n");
+    }
+    ArqEvent::Delivered => rprintln!("delivered"),
+    ArqEvent::Failed => rprintln!("no ack, giving up"),
+  }
+}
+
+fn transmit(frame: &Frame) {
+  rprintln!("sending {:?}", frame);
+  free(|cs| {
+    if let Some(modulator) = MODULATOR.borrow(cs).borrow_mut().as_mut() {
+      modulator.send(frame);
+    }
+  });
+}
+
+fn transmitting() -> bool {
+  free(|cs| {
+    MODULATOR
+      .borrow(cs)
+      .borrow()
+      .as_ref()
+      .is_some_and(|m| m.is_busy())
+  })
+}
+
+fn setup_pwm(sequencer: &Sequencer, speaker_pin: u32) {
+  let pwm = sequencer.pwm();
+  DRIVE.setup(pwm, speaker_pin, None);
+  pwm.mode.write(|w| w.updown().up());
+  pwm
+    .prescaler
+    .write(|w| w.prescaler().variant(PWM_PRESCALER));
+  pwm
+    .countertop
+    .write(|w| unsafe { w.countertop().bits(PWM_COUNTERTOP) });
+  // each sample is played once
+  sequencer.set_refresh(0);
+  pwm.enable.write(|w| w.enable().enabled());
+}
+
+#[interrupt]
+fn PWM0() {
+  free(|cs| {
+    let mut sequencer = SEQUENCER.borrow(cs).borrow_mut();
+    if let Some(sequencer) = sequencer.as_mut() {
+      sequencer.handle_seqend(|buffer| fill_buffer(buffer, cs));
+    }
+  });
+}
+
+fn fill_buffer(buffer: &mut [u16], cs: &CriticalSection) {
+  let mut sink = SINK.borrow(cs).borrow_mut();
+  let samples = &mut buffer[..SAMPLES_PER_BUF];
+  match MODULATOR.borrow(cs).borrow_mut().as_mut() {
+    Some(modulator) => sink.fill(modulator, samples),
+    None => samples.fill(DRIVE.silence(PWM_COUNTERTOP)),
+  }
+  DRIVE.spread(buffer);
+}
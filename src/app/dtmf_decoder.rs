@@ -0,0 +1,111 @@
+use core::{cell::RefCell, fmt::Write};
+
+use cortex_m::{
+  interrupt::{free, Mutex},
+  peripheral::NVIC,
+};
+use heapless::String;
+use microbit::{
+  display::nonblocking::{BitImage, Display},
+  hal::{prelude::_embedded_hal_timer_CountDown, Timer},
+  pac::{interrupt, TIMER1},
+  Board,
+};
+use rtt_target::rprintln;
+
+use crate::{
+  audio::{
+    agc::{AgcConfig, GateConfig, MicChain},
+    dtmf::{Decoder, BLOCK_LEN},
+  },
+  raw::{Microphone, Serial},
+};
+
+const SAMPLE_RATE: u32 = 8000;
+// the amplitude each of the two tones needs, relative to the agc
+// target level of 4096
+const THRESHOLD: u32 = 600;
+
+static DISPLAY: Mutex<RefCell<Option<Display<TIMER1>>>> =
+  Mutex::new(RefCell::new(None));
+
+#[rustfmt::skip]
+const GLYPHS: [(u8, [[u8; 5]; 5]); 16] = [
+  (b'0', [[0, 1, 1, 1, 0], [0, 1, 0, 1, 0], [0, 1, 0, 1, 0], [0, 1, 0, 1, 0], [0, 1, 1, 1, 0]]),
+  (b'1', [[0, 0, 1, 0, 0], [0, 1, 1, 0, 0], [0, 0, 1, 0, 0], [0, 0, 1, 0, 0], [0, 1, 1, 1, 0]]),
+  (b'2', [[0, 1, 1, 1, 0], [0, 0, 0, 1, 0], [0, 1, 1, 1, 0], [0, 1, 0, 0, 0], [0, 1, 1, 1, 0]]),
+  (b'3', [[0, 1, 1, 1, 0], [0, 0, 0, 1, 0], [0, 1, 1, 1, 0], [0, 0, 0, 1, 0], [0, 1, 1, 1, 0]]),
+  (b'4', [[0, 1, 0, 1, 0], [0, 1, 0, 1, 0], [0, 1, 1, 1, 0], [0, 0, 0, 1, 0], [0, 0, 0, 1, 0]]),
+  (b'5', [[0, 1, 1, 1, 0], [0, 1, 0, 0, 0], [0, 1, 1, 1, 0], [0, 0, 0, 1, 0], [0, 1, 1, 1, 0]]),
+  (b'6', [[0, 1, 1, 1, 0], [0, 1, 0, 0, 0], [0, 1, 1, 1, 0], [0, 1, 0, 1, 0], [0, 1, 1, 1, 0]]),
+  (b'7', [[0, 1, 1, 1, 0], [0, 0, 0, 1, 0], [0, 0, 1, 0, 0], [0, 0, 1, 0, 0], [0, 0, 1, 0, 0]]),
+  (b'8', [[0, 1, 1, 1, 0], [0, 1, 0, 1, 0], [0, 1, 1, 1, 0], [0, 1, 0, 1, 0], [0, 1, 1, 1, 0]]),
+  (b'9', [[0, 1, 1, 1, 0], [0, 1, 0, 1, 0], [0, 1, 1, 1, 0], [0, 0, 0, 1, 0], [0, 1, 1, 1, 0]]),
+  (b'A', [[0, 1, 1, 1, 0], [0, 1, 0, 1, 0], [0, 1, 1, 1, 0], [0, 1, 0, 1, 0], [0, 1, 0, 1, 0]]),
+  (b'B', [[0, 1, 1, 0, 0], [0, 1, 0, 1, 0], [0, 1, 1, 0, 0], [0, 1, 0, 1, 0], [0, 1, 1, 0, 0]]),
+  (b'C', [[0, 1, 1, 1, 0], [0, 1, 0, 0, 0], [0, 1, 0, 0, 0], [0, 1, 0, 0, 0], [0, 1, 1, 1, 0]]),
+  (b'D', [[0, 1, 1, 0, 0], [0, 1, 0, 1, 0], [0, 1, 0, 1, 0], [0, 1, 0, 1, 0], [0, 1, 1, 0, 0]]),
+  (b'*', [[1, 0, 1, 0, 1], [0, 1, 1, 1, 0], [1, 1, 1, 1, 1], [0, 1, 1, 1, 0], [1, 0, 1, 0, 1]]),
+  (b'#', [[0, 1, 0, 1, 0], [1, 1, 1, 1, 1], [0, 1, 0, 1, 0], [1, 1, 1, 1, 1], [0, 1, 0, 1, 0]]),
+];
+
+pub fn run() -> ! {
+  let mut board = Board::take().unwrap();
+  let mut serial = Serial::setup(board.UARTE0, board.uart);
+  let mut str_buf: String<16> = String::new();
+
+  let display = Display::new(board.TIMER1, board.display_pins);
+  free(|cs| DISPLAY.borrow(cs).replace(Some(display)));
+  unsafe {
+    board.NVIC.set_priority(interrupt::TIMER1, 48);
+    NVIC::unmask(interrupt::TIMER1);
+  }
+
+  let mut microphone = Microphone::setup(board.SAADC, board.microphone_pins);
+  microphone.set_chain(MicChain::new(
+    Some(GateConfig::default()),
+    Some(AgcConfig::default()),
+  ));
+  let mut ticker = Timer::periodic(board.TIMER0);
+  ticker.start(1_000_000 / SAMPLE_RATE);
+
+  let mut decoder = Decoder::new(SAMPLE_RATE, THRESHOLD);
+  let mut block = [0i16; BLOCK_LEN];
+
+  loop {
+    microphone.read_block(&mut ticker, &mut block);
+
+    let Some(key) = decoder.process(&block) else {
+      continue;
+    };
+
+    rprintln!("dtmf: {}", key as char);
+    write!(&mut str_buf, "{}", key as char).unwrap();
+    serial.send_str(&str_buf);
+    str_buf.clear();
+
+    show_key(key);
+  }
+}
+
+fn show_key(key: u8) {
+  let Some((_, glyph)) = GLYPHS.iter().find(|(k, _)| *k == key) else {
+    return;
+  };
+
+  let image = BitImage::new(glyph);
+  free(|cs| {
+    if let Some(display) = DISPLAY.borrow(cs).borrow_mut().as_mut() {
+      display.show(&image);
+    }
+  });
+}
+
+#[interrupt]
+fn TIMER1() {
+  free(|cs| {
+    if let Some(display) = DISPLAY.borrow(cs).borrow_mut().as_mut() {
+      display.handle_display_event();
+    }
+  });
+}
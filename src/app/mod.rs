@@ -1,5 +1,9 @@
+#[cfg(feature = "app_acoustic_modem")]
+pub mod acoustic_modem;
 #[cfg(feature = "app_ble_temp")]
 pub mod ble_temp;
+#[cfg(feature = "app_dtmf_decoder")]
+pub mod dtmf_decoder;
 #[cfg(feature = "app_i2c_display")]
 pub mod i2c_display;
 #[cfg(feature = "app_midi_player")]
@@ -8,6 +12,10 @@ pub mod midi_player;
 pub mod pcm_player;
 #[cfg(feature = "app_playground")]
 pub mod playground;
+#[cfg(feature = "app_recorder")]
+pub mod recorder;
+#[cfg(feature = "app_speaker_calibration")]
+pub mod speaker_calibration;
 #[cfg(feature = "app_temp")]
 pub mod temp;
 #[cfg(feature = "app_tone_generator")]
@@ -1,31 +1,54 @@
 use core::{
-  cell::{Cell, OnceCell, RefCell},
-  sync::atomic::{AtomicU16, AtomicU32, AtomicUsize, Ordering},
+  cell::{Cell, RefCell},
+  fmt::Write,
+  sync::atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering},
   u16,
 };
 
 use cortex_m::{
-  asm::{self, delay},
+  asm::delay,
   interrupt::{free, CriticalSection, Mutex},
   peripheral::NVIC,
 };
+use fixed::types::U8F8;
+use heapless::String;
 use microbit::{
-  hal::{gpio::Level, prelude::OutputPin},
-  pac::{interrupt, pwm0::prescaler::PRESCALER_A, GPIOTE, PWM0},
+  display::nonblocking::{BitImage, Display},
+  hal::{
+    gpio::Level,
+    prelude::{_embedded_hal_timer_CountDown, InputPin, OutputPin},
+    Timer,
+  },
+  pac::{interrupt, pwm0::prescaler::PRESCALER_A, TIMER1, UARTE0},
   Board,
 };
 use rtt_target::rprintln;
+use static_cell::StaticCell;
+
+use crate::{
+  assets,
+  audio::{
+    eq::{Equalizer, Filter},
+    graph::PwmSink,
+    noise_shaping::{NoiseShaper, Order},
+    player::{Clip, Player, PlayerEvent},
+    resample::Interpolation,
+    wav::WavError,
+  },
+  raw::{
+    scroll::{Frame, ScrollText},
+    sequencer::PwmSequencer,
+    speaker::SpeakerDrive,
+    Serial,
+  },
+};
 
-// generated using ffmpeg -i bad-apple.webm -ac 1 -ar 2700 -f u8 -t 60 bad-apple.raw
-// -ac 1: mono channel
-// -ar 2700: sample rate
-// -f u8: 8-bit unsigned pcm
-// -t 60: 60 seconds (make sure the file is not too large to fit in flash)
-const AUDIO_DATA: &[u8] = include_bytes!("../../assets/bad-apple.raw");
-// the sample rate of the audio data
-const DATA_SAMPLE_RATE: u32 = 7812;
+// build.rs compresses the wav files in assets/ to 4-bit IMA ADPCM,
+// half the size in flash
+const CLIPS: &[Clip] = &[assets::BAD_APPLE.clip(), assets::CHIME.clip()];
 // <del>the speaker's resonance frequency</del>
-static TARGET_SAMPLE_RATE: AtomicU32 = AtomicU32::new(31250);
+const START_SAMPLE_RATE: u32 = 31250;
+static TARGET_SAMPLE_RATE: AtomicU32 = AtomicU32::new(START_SAMPLE_RATE);
 
 // the prescaler sets the PWM clock frequency.
 const PWM_PRESCALER: PRESCALER_A = PRESCALER_A::DIV_1;
@@ -41,47 +64,158 @@ static PWM_REFRESH: AtomicU32 = AtomicU32::new(2);
 // Therefore, PWM_COUNTERTOP = PWM_CLOCK_FREQ / (TARGET_SAMPLE_RATE * (1 + REFRESH))
 static PWM_COUNTERTOP: AtomicU16 = AtomicU16::new(1); // initialize to an arbitrary value
 
-const GAIN: f32 = 1.0;
+const GAIN: U8F8 = U8F8::ONE;
 
-static CURSOR: AtomicUsize = AtomicUsize::new(0);
+// eq::MLT_8530 for the built-in speaker
+const EQUALIZER: &[Filter] = &[];
 
-const BUF_LEN: usize = 512;
-static BUFFER0: Mutex<RefCell<[u16; BUF_LEN]>> =
-  Mutex::new(RefCell::new([0; BUF_LEN]));
-static BUFFER1: Mutex<RefCell<[u16; BUF_LEN]>> =
-  Mutex::new(RefCell::new([0; BUF_LEN]));
+// nearest and linear are cheaper, but the images of the source rate
+// become audible as the target rate changes
+const INTERPOLATION: Interpolation = Interpolation::Sinc;
+static PLAYER: Mutex<RefCell<Player>> = Mutex::new(RefCell::new(
+  Player::new(CLIPS, START_SAMPLE_RATE, INTERPOLATION),
+));
+// set from the pwm interrupt, the main loop moves on to the next track
+static CLIP_ENDED: AtomicBool = AtomicBool::new(false);
+
+// the noise is shaped at the rate the buffer is filled at. with a
+// lower PWM_REFRESH and a higher TARGET_SAMPLE_RATE more of it ends up
+// above the audible band.
+const NOISE_SHAPING: Order = Order::Second;
+// the countertop is set before each buffer
+static SINK: Mutex<RefCell<PwmSink>> = Mutex::new(RefCell::new(
+  PwmSink::new(1, 0, NoiseShaper::new(NOISE_SHAPING)),
+));
 
-type Pwm = PWM0;
-static PWM: Mutex<OnceCell<Pwm>> = Mutex::new(OnceCell::new());
-static GPIOTE: Mutex<OnceCell<GPIOTE>> = Mutex::new(OnceCell::new());
+// bridged needs a speaker across edge pins 0 and 1
+const DRIVE: SpeakerDrive = SpeakerDrive::Single;
+
+const BUF_LEN: usize = 512;
+// a bridged speaker takes two values per sample
+const SAMPLES_PER_BUF: usize = BUF_LEN / DRIVE.values_per_sample();
+// if the playback goes faster than the cpu can fill in the buffer, the
+// pwm will generate a sequence from garbage. but generally it's much
+// faster to generate the buffer than consuming it. the sequencer counts
+// the times it isn't, and reports them with the rest of its stats.
+static BUFFERS: StaticCell<[[u16; BUF_LEN]; 2]> = StaticCell::new();
+
+type Sequencer = PwmSequencer<BUF_LEN>;
+static SEQUENCER: Mutex<RefCell<Option<Sequencer>>> =
+  Mutex::new(RefCell::new(None));
+
+static DISPLAY: Mutex<RefCell<Option<Display<TIMER1>>>> =
+  Mutex::new(RefCell::new(None));
+
+// the buttons are polled this often
+const TICK_RATE: u32 = 100;
+// the track number moves a column every SCROLL_TICKS ticks
+const SCROLL_TICKS: u32 = 12;
+// the buffer stats go out over rtt and serial every 5 seconds
+const STATS_TICKS: u32 = 5 * TICK_RATE;
+
+#[rustfmt::skip]
+const PAUSED: Frame = [
+  [0, 1, 0, 1, 0],
+  [0, 1, 0, 1, 0],
+  [0, 1, 0, 1, 0],
+  [0, 1, 0, 1, 0],
+  [0, 1, 0, 1, 0],
+];
 
 #[derive(Clone, Copy)]
 #[allow(unused)]
 enum ButtonFunction {
+  // a plays the previous clip, b the next one
+  Track,
+  // a jumps back SEEK_STEP_MS, b forward
+  Seek,
   PwmRefresh,
   TargetSampleRate,
 }
 
+const SEEK_STEP_MS: i32 = 5000;
+
 static BUTTON_FUNCTION: Mutex<Cell<ButtonFunction>> =
-  Mutex::new(Cell::new(ButtonFunction::TargetSampleRate));
+  Mutex::new(Cell::new(ButtonFunction::Track));
 
 impl ButtonFunction {
-  fn up(&self) {
+  fn button_a(&self, cs: &CriticalSection) {
+    let mut player = PLAYER.borrow(cs).borrow_mut();
     match self {
-      ButtonFunction::PwmRefresh => PWM_REFRESH.fetch_add(1, Ordering::Relaxed),
+      ButtonFunction::Track => {
+        let result = player.prev_track();
+        report(&player, result);
+      }
+      ButtonFunction::Seek => player.skip_ms(-SEEK_STEP_MS),
+      ButtonFunction::PwmRefresh => {
+        PWM_REFRESH.fetch_add(1, Ordering::Relaxed);
+        configure_pwm(SEQUENCER.borrow(cs).borrow_mut().as_mut().unwrap());
+      }
       ButtonFunction::TargetSampleRate => {
-        TARGET_SAMPLE_RATE.fetch_add(100, Ordering::Relaxed)
+        TARGET_SAMPLE_RATE.fetch_add(100, Ordering::Relaxed);
+        configure_pwm(SEQUENCER.borrow(cs).borrow_mut().as_mut().unwrap());
       }
-    };
+    }
   }
 
-  fn down(&self) {
+  fn button_b(&self, cs: &CriticalSection) {
+    let mut player = PLAYER.borrow(cs).borrow_mut();
     match self {
-      ButtonFunction::PwmRefresh => PWM_REFRESH.fetch_sub(1, Ordering::Relaxed),
+      ButtonFunction::Track => {
+        let result = player.next_track();
+        report(&player, result);
+      }
+      ButtonFunction::Seek => player.skip_ms(SEEK_STEP_MS),
+      ButtonFunction::PwmRefresh => {
+        PWM_REFRESH.fetch_sub(1, Ordering::Relaxed);
+        configure_pwm(SEQUENCER.borrow(cs).borrow_mut().as_mut().unwrap());
+      }
       ButtonFunction::TargetSampleRate => {
-        TARGET_SAMPLE_RATE.fetch_sub(100, Ordering::Relaxed)
+        TARGET_SAMPLE_RATE.fetch_sub(100, Ordering::Relaxed);
+        configure_pwm(SEQUENCER.borrow(cs).borrow_mut().as_mut().unwrap());
       }
+    }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Press {
+  A,
+  B,
+  Both,
+}
+
+// buttons act when they're released, so pressing both doesn't also
+// count as a press of the one that went down first
+struct ButtonState {
+  held: Option<Press>,
+}
+
+impl ButtonState {
+  fn update(&mut self, a: bool, b: bool) -> Option<Press> {
+    let now = match (a, b) {
+      (false, false) => None,
+      (true, false) => Some(Press::A),
+      (false, true) => Some(Press::B),
+      (true, true) => Some(Press::Both),
     };
+
+    match (self.held, now) {
+      (held, None) => {
+        self.held = None;
+        held
+      }
+      (None, Some(now)) => {
+        self.held = Some(now);
+        None
+      }
+      (Some(held), Some(now)) => {
+        if held != now {
+          self.held = Some(Press::Both);
+        }
+        None
+      }
+    }
   }
 }
 
@@ -92,56 +226,186 @@ pub fn play() -> ! {
 fn play_sound_data() -> ! {
   let mut board = Board::take().unwrap();
 
+  for asset in [&assets::BAD_APPLE, &assets::CHIME] {
+    if !asset.verify() {
+      rprintln!("{}: checksum mismatch, flash again", asset.name);
+    }
+  }
+
+  let mut equalizer_rate = TARGET_SAMPLE_RATE.load(Ordering::Relaxed);
+  let equalizer = Equalizer::with_filters(EQUALIZER, equalizer_rate);
+  free(|cs| {
+    let mut player = PLAYER.borrow(cs).borrow_mut();
+    player.set_gain(GAIN);
+    player.set_equalizer(equalizer);
+    let result = player.play(0);
+    report(&player, result);
+  });
+
   let speaker_pin = board.edge.e00.into_push_pull_output(Level::Low).degrade();
+  let speaker_neg_pin = match DRIVE {
+    SpeakerDrive::Single => None,
+    SpeakerDrive::Bridged => {
+      Some(board.edge.e01.into_push_pull_output(Level::Low).degrade())
+    }
+  };
 
   // let speaker_pin = board
   //   .speaker_pin
   //   .into_push_pull_output(Level::Low)
   //   .degrade();
 
-  let pwm = board.PWM0;
+  let button_a = board.buttons.button_a.into_floating_input().degrade();
+  let button_b = board.buttons.button_b.into_floating_input().degrade();
 
-  setup_pwm(&pwm, speaker_pin.psel_bits());
-  setup_buttons(&board.GPIOTE, board.buttons);
+  let display = Display::new(board.TIMER1, board.display_pins);
+  free(|cs| DISPLAY.borrow(cs).replace(Some(display)));
 
-  unsafe { setup_interrupt(&mut board.NVIC) };
+  let mut serial = Serial::setup(board.UARTE0, board.uart);
+
+  let buffers = BUFFERS.init([[0; BUF_LEN]; 2]);
+  let mut sequencer = PwmSequencer::new(board.PWM0, buffers);
+  sequencer.enable_stats(&mut board.DCB, &mut board.DWT);
+
+  setup_pwm(
+    &mut sequencer,
+    speaker_pin.psel_bits(),
+    speaker_neg_pin.as_ref().map(|p| p.psel_bits()),
+  );
 
   // setup for initial playback
-  free(|cs| {
-    fill_next_buffer(0, cs);
-    fill_next_buffer(1, cs);
-  });
+  free(|cs| sequencer.start(|buffer| fill_buffer(buffer, cs)));
 
-  // save pwm for interrupt
-  play_seq(0, &pwm);
+  // save the sequencer for use in interrupt
+  free(|cs| SEQUENCER.borrow(cs).replace(Some(sequencer)));
 
-  // save the peripherals for use in interrupt
-  free(|cs| {
-    PWM.borrow(cs).set(pwm).unwrap();
-    GPIOTE.borrow(cs).set(board.GPIOTE).unwrap();
-  });
+  unsafe { setup_interrupt(&mut board.NVIC) };
+
+  let mut ticker = Timer::periodic(board.TIMER0);
+  ticker.start(1_000_000 / TICK_RATE);
+
+  let mut buttons = ButtonState { held: None };
+  let mut scroll = ScrollText::new();
+  let mut shown_track = None;
+  let mut tick = 0;
 
   loop {
-    asm::wfi();
+    while ticker.wait().is_err() {}
+    tick += 1;
+
+    if CLIP_ENDED.swap(false, Ordering::Relaxed) {
+      free(|cs| {
+        let mut player = PLAYER.borrow(cs).borrow_mut();
+        let result = player.next_track();
+        report(&player, result);
+      });
+    }
+
+    let press =
+      buttons.update(button_a.is_low().unwrap(), button_b.is_low().unwrap());
+    free(|cs| {
+      let button_function = BUTTON_FUNCTION.borrow(cs).get();
+      match press {
+        Some(Press::A) => button_function.button_a(cs),
+        Some(Press::B) => button_function.button_b(cs),
+        Some(Press::Both) => PLAYER.borrow(cs).borrow_mut().toggle_pause(),
+        None => {}
+      }
+    });
+
+    // the filters are redesigned here rather than in the interrupt,
+    // the player swaps them in with the next buffer
+    let target_sample_rate = TARGET_SAMPLE_RATE.load(Ordering::Relaxed);
+    if equalizer_rate != target_sample_rate {
+      equalizer_rate = target_sample_rate;
+      let equalizer = Equalizer::with_filters(EQUALIZER, equalizer_rate);
+      free(|cs| {
+        let mut player = PLAYER.borrow(cs).borrow_mut();
+        player.set_target_sample_rate(target_sample_rate);
+        player.set_equalizer(equalizer);
+      });
+    }
+
+    let (track, paused) = free(|cs| {
+      let player = PLAYER.borrow(cs).borrow();
+      (player.track(), player.is_paused())
+    });
+
+    if shown_track != Some(track) {
+      shown_track = Some(track);
+      let mut text: String<8> = String::new();
+      write!(&mut text, "{}/{}", track + 1, CLIPS.len()).ok();
+      scroll.set_text(&text);
+    }
+
+    if tick % SCROLL_TICKS == 0 {
+      let frame = if paused { PAUSED } else { scroll.next_frame() };
+      show(&frame);
+    }
+
+    if tick % STATS_TICKS == 0 {
+      report_stats(&mut serial);
+    }
+  }
+}
+
+fn report_stats(serial: &mut Serial<UARTE0>) {
+  let stats = free(|cs| {
+    let sequencer = SEQUENCER.borrow(cs).borrow();
+    sequencer.as_ref().and_then(|s| s.stats().copied())
+  });
+  let Some(stats) = stats else {
+    return;
+  };
+
+  let mut line: String<160> = String::new();
+  write!(&mut line, "{}\r\n", stats).ok();
+  rprintln!("{}", line.trim_end());
+  serial.send_str(&line);
+}
+
+// print the clip the player switched to, or why it can't be played
+fn report(player: &Player, result: Result<(), WavError>) {
+  let clip = &player.clips()[player.track()];
+  match result {
+    Ok(()) => rprintln!(
+      "track {}: {}, {} ms",
+      player.track() + 1,
+      clip.name,
+      player.duration_ms()
+    ),
+    Err(e) => rprintln!("track {}: {}: {:?}", player.track() + 1, clip.name, e),
   }
 }
 
+fn show(frame: &Frame) {
+  let image = BitImage::new(frame);
+  free(|cs| {
+    if let Some(display) = DISPLAY.borrow(cs).borrow_mut().as_mut() {
+      display.show(&image);
+    }
+  });
+}
+
 // update the pwm countertop if the refresh rate is changed
-fn configure_pwm(pwm: &Pwm) {
+fn configure_pwm(sequencer: &mut Sequencer) {
   let refresh = PWM_REFRESH.load(Ordering::Relaxed);
   let target_sample_rate = TARGET_SAMPLE_RATE.load(Ordering::Relaxed);
   let countertop =
     (PWM_CLOCK_FREQ / (target_sample_rate * (refresh + 1))) as u16;
   PWM_COUNTERTOP.store(countertop, Ordering::Relaxed);
 
-  unsafe {
-    // pwm period
-    pwm.countertop.write(|w| w.countertop().bits(countertop));
+  // pwm period
+  let pwm = sequencer.pwm();
+  pwm
+    .countertop
+    .write(|w| unsafe { w.countertop().bits(countertop) });
+
+  // each period is repeated REFRESH+1 times
+  sequencer.set_refresh(refresh);
 
-    // each period is repeated REFRESH+1 times
-    pwm.seq0.refresh.write(|w| w.bits(refresh));
-    pwm.seq1.refresh.write(|w| w.bits(refresh));
-  }
+  // the old stats are of the old buffer duration
+  sequencer.reset_stats();
 
   rprintln!(
     "sample rate: {}, refresh {}, counter top: {}",
@@ -151,35 +415,6 @@ fn configure_pwm(pwm: &Pwm) {
   );
 }
 
-fn setup_buttons(gpiote: &GPIOTE, buttons: microbit::board::Buttons) {
-  // enable gpio event for button a
-  gpiote.config[0].write(|w| unsafe {
-    w.mode()
-      .event()
-      .psel()
-      .bits(buttons.button_a.degrade().pin())
-      .polarity()
-      .hi_to_lo()
-      .outinit()
-      .low()
-  });
-
-  // enable gpio event for button b
-  gpiote.config[1].write(|w| unsafe {
-    w.mode()
-      .event()
-      .psel()
-      .bits(buttons.button_b.degrade().pin())
-      .polarity()
-      .hi_to_lo()
-      .outinit()
-      .low()
-  });
-
-  // enable interrupt
-  gpiote.intenset.write(|w| w.in0().set().in1().set());
-}
-
 #[allow(unused)]
 fn dump_mem(start: u32, end: u32) {
   for i in start..end {
@@ -194,13 +429,19 @@ unsafe fn setup_interrupt(nvic: &mut NVIC) {
   nvic.set_priority(interrupt::PWM0, 10);
   NVIC::unmask(interrupt::PWM0);
 
-  nvic.set_priority(interrupt::GPIOTE, 9);
-  NVIC::unmask(interrupt::GPIOTE);
+  nvic.set_priority(interrupt::TIMER1, 48);
+  NVIC::unmask(interrupt::TIMER1);
 }
 
-fn setup_pwm(pwm: &Pwm, speaker_pin: u32) {
-  // set pin
-  pwm.psel.out[0].write(|w| unsafe { w.bits(speaker_pin) });
+fn setup_pwm(
+  sequencer: &mut Sequencer,
+  speaker_pin: u32,
+  speaker_neg_pin: Option<u32>,
+) {
+  let pwm = sequencer.pwm();
+
+  // set pins and decode mode, one sample at a time
+  DRIVE.setup(pwm, speaker_pin, speaker_neg_pin);
 
   // enable
   pwm.enable.write(|w| w.enable().enabled());
@@ -213,107 +454,38 @@ fn setup_pwm(pwm: &Pwm, speaker_pin: u32) {
     .prescaler
     .write(|w| w.prescaler().bits(PWM_PRESCALER as u8));
 
-  configure_pwm(pwm);
-
-  // set seq pointer to buffer
-  free(|cs| {
-    // if the playback goes faster than the cpu can fill in the
-    // buffer, the pwm will generate a sequence from garbage. so
-    // strictly speaking, the pointer assignments is unsafe. but
-    // generally it's much faster to generate the buffer than
-    // consuming it. so i'll just keep it this way.
-    let buf_0_ptr = BUFFER0.borrow(cs).as_ptr() as u32;
-    let buf_1_ptr = BUFFER1.borrow(cs).as_ptr() as u32;
-    pwm.seq0.ptr.write(|w| unsafe { w.bits(buf_0_ptr) });
-    pwm.seq0.cnt.write(|w| unsafe { w.bits(BUF_LEN as u32) });
-    pwm.seq1.ptr.write(|w| unsafe { w.bits(buf_1_ptr) });
-    pwm.seq1.cnt.write(|w| unsafe { w.bits(BUF_LEN as u32) });
-  });
-
-  // set decode mode to one sample at a time
-  pwm
-    .decoder
-    .write(|w| w.load().common().mode().refresh_count());
-
-  // enable interrupts for end of sequence event
-  pwm.intenset.write(|w| w.seqend0().set().seqend1().set());
+  configure_pwm(sequencer);
 }
 
 #[interrupt]
 fn PWM0() {
   free(|cs| {
-    let pwm = PWM.borrow(cs).get().unwrap();
-    if pwm.events_seqend[0].read().bits() != 0 {
-      play_seq(1, pwm);
-      pwm.events_seqend[0].write(|w| w.events_seqend().clear_bit());
-      fill_next_buffer(0, cs);
-    }
-
-    if pwm.events_seqend[1].read().bits() != 0 {
-      play_seq(0, pwm);
-      pwm.events_seqend[1].write(|w| w.events_seqend().clear_bit());
-      fill_next_buffer(1, cs);
+    let mut sequencer = SEQUENCER.borrow(cs).borrow_mut();
+    if let Some(sequencer) = sequencer.as_mut() {
+      sequencer.handle_seqend(|buffer| fill_buffer(buffer, cs));
     }
   });
 }
 
 #[interrupt]
-fn GPIOTE() {
+fn TIMER1() {
   free(|cs| {
-    let gpiote = GPIOTE.borrow(cs).get().unwrap();
-    let button_function = BUTTON_FUNCTION.borrow(cs).get();
-
-    // button a pressed
-    if gpiote.events_in[0].read().bits() != 0 {
-      gpiote.events_in[0].write(|w| w.events_in().clear_bit());
-      button_function.up();
-      configure_pwm(PWM.borrow(cs).get().unwrap());
-    }
-
-    // button b pressed
-    if gpiote.events_in[1].read().bits() != 0 {
-      gpiote.events_in[1].write(|w| w.events_in().clear_bit());
-      button_function.down();
-      configure_pwm(PWM.borrow(cs).get().unwrap());
+    if let Some(display) = DISPLAY.borrow(cs).borrow_mut().as_mut() {
+      display.handle_display_event();
     }
   });
 }
 
-fn fill_next_buffer(id: u8, cs: &CriticalSection) {
-  let cursor = CURSOR.load(Ordering::Relaxed);
-  let buffer = match id {
-    0 => BUFFER0.borrow(cs),
-    1 => BUFFER1.borrow(cs),
-    _ => panic!("invalid id"),
-  };
-
-  let mut buffer = buffer.borrow_mut();
-  let new_cursor = fill_samples(buffer.as_mut_slice(), AUDIO_DATA, cursor);
-  CURSOR.store(new_cursor, Ordering::Relaxed);
-}
-
-fn fill_samples(buffer: &mut [u16], data: &[u8], cursor: usize) -> usize {
-  // in case the data sample rate is different than the target sample
-  // rate, we read the every SAMPLE_STRIDE sample in the data file to
-  // get the same sample rate
-  let target_sample_rate = TARGET_SAMPLE_RATE.load(Ordering::Relaxed);
-  let stride = DATA_SAMPLE_RATE as f32 / target_sample_rate as f32;
-  let countertop = PWM_COUNTERTOP.load(Ordering::Relaxed) as usize;
-  let pos = |i| (cursor + (stride * i as f32) as usize) % data.len();
-
-  for (i, cell) in buffer.iter_mut().enumerate() {
-    let sample = data[pos(i)] as f32 / 255.0;
-    let sample = (sample - 0.5) * GAIN + 0.5;
-    let sample = (sample * countertop as f32) as u16;
-    *cell = sample;
+fn fill_buffer(buffer: &mut [u16], cs: &CriticalSection) {
+  let countertop = PWM_COUNTERTOP.load(Ordering::Relaxed);
+  let mut player = PLAYER.borrow(cs).borrow_mut();
+  let mut sink = SINK.borrow(cs).borrow_mut();
+  sink.set_countertop(countertop, DRIVE.silence(countertop));
+  sink.fill(&mut *player, &mut buffer[..SAMPLES_PER_BUF]);
+  if let Some(PlayerEvent::EndOfClip(_)) = player.take_event() {
+    CLIP_ENDED.store(true, Ordering::Relaxed);
   }
-
-  // return next cursor
-  pos(buffer.len())
-}
-
-fn play_seq(id: u8, pwm: &Pwm) {
-  pwm.tasks_seqstart[id as usize].write(|w| w.tasks_seqstart().trigger());
+  DRIVE.spread(buffer);
 }
 
 #[allow(unused)]
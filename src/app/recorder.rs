@@ -0,0 +1,276 @@
+use core::{
+  cell::RefCell,
+  sync::atomic::{AtomicBool, Ordering},
+};
+
+use cortex_m::{
+  interrupt::{free, CriticalSection, Mutex},
+  peripheral::NVIC,
+};
+use fixed::types::U8F8;
+use microbit::{
+  hal::{
+    gpio::{Floating, Input, Level, Pin},
+    prelude::{_embedded_hal_timer_CountDown, InputPin},
+    timer::Periodic,
+    Timer,
+  },
+  pac::{interrupt, pwm0::prescaler::PRESCALER_A, TIMER0},
+  Board,
+};
+use rtt_target::rprintln;
+use static_cell::StaticCell;
+
+use crate::{
+  audio::{
+    agc::{AgcConfig, GateConfig, MicChain},
+    eq::Equalizer,
+    noise_shaping::{NoiseShaper, Order},
+    pcm::{self, PcmParams},
+    resample::{Interpolation, Resampler},
+  },
+  raw::{sequencer::PwmSequencer, speaker::SpeakerDrive, Microphone},
+};
+
+// the rate the microphone is sampled at
+const SAMPLE_RATE: u32 = 8000;
+// 4 seconds of audio at 8 kHz
+const RECORDING_LEN: usize = 32 * 1024;
+// number of samples read from the microphone between button checks
+const BLOCK_LEN: usize = 64;
+
+// each recorded sample is played 4 times, see pcm_player for why.
+const TARGET_SAMPLE_RATE: u32 = SAMPLE_RATE * 4;
+const PWM_PRESCALER: PRESCALER_A = PRESCALER_A::DIV_1;
+const PWM_CLOCK_FREQ: u32 = 1 << (24 - (PWM_PRESCALER as u8));
+const PWM_REFRESH: u32 = 2;
+const PWM_COUNTERTOP: u16 =
+  (PWM_CLOCK_FREQ / (TARGET_SAMPLE_RATE * (PWM_REFRESH + 1))) as u16;
+
+// the built-in speaker
+const DRIVE: SpeakerDrive = SpeakerDrive::Single;
+
+const BUF_LEN: usize = 512;
+static BUFFERS: StaticCell<[[u16; BUF_LEN]; 2]> = StaticCell::new();
+
+type Sequencer = PwmSequencer<BUF_LEN>;
+static SEQUENCER: Mutex<RefCell<Option<Sequencer>>> =
+  Mutex::new(RefCell::new(None));
+
+static RECORDING: Mutex<RefCell<Recording>> =
+  Mutex::new(RefCell::new(Recording::new()));
+static RESAMPLER: Mutex<RefCell<Resampler>> =
+  Mutex::new(RefCell::new(Resampler::new(Interpolation::Sinc)));
+static SHAPER: Mutex<RefCell<NoiseShaper>> =
+  Mutex::new(RefCell::new(NoiseShaper::new(Order::Second)));
+// cleared once the memo is played out, the sequencer then finishes the
+// buffer that holds the end of it
+static PLAYING: AtomicBool = AtomicBool::new(false);
+
+// a ring buffer of u8 samples. when the recording is longer than the
+// buffer, the oldest samples are overwritten.
+struct Recording {
+  data: [u8; RECORDING_LEN],
+  // where the next sample is written
+  head: usize,
+  len: usize,
+}
+
+impl Recording {
+  const fn new() -> Self {
+    Self {
+      data: [128; RECORDING_LEN],
+      head: 0,
+      len: 0,
+    }
+  }
+
+  fn clear(&mut self) {
+    self.head = 0;
+    self.len = 0;
+  }
+
+  fn push(&mut self, sample: u8) {
+    self.data[self.head] = sample;
+    self.head = (self.head + 1) % RECORDING_LEN;
+    self.len = (self.len + 1).min(RECORDING_LEN);
+  }
+
+  // rotate the ring so that the oldest sample sits at index 0. this
+  // lets the playback read the recording as a plain slice.
+  fn linearize(&mut self) {
+    let start = (self.head + RECORDING_LEN - self.len) % RECORDING_LEN;
+    self.data.rotate_left(start);
+    self.head = self.len % RECORDING_LEN;
+  }
+
+  fn samples(&self) -> &[u8] {
+    &self.data[..self.len]
+  }
+}
+
+pub fn run() -> ! {
+  let mut board = Board::take().unwrap();
+
+  let speaker_pin = board
+    .speaker_pin
+    .into_push_pull_output(Level::Low)
+    .degrade();
+  let button_a = board.buttons.button_a.into_floating_input().degrade();
+  let button_b = board.buttons.button_b.into_floating_input().degrade();
+
+  let mut microphone = Microphone::setup(board.SAADC, board.microphone_pins);
+  microphone.set_chain(MicChain::new(
+    Some(GateConfig::default()),
+    Some(AgcConfig::default()),
+  ));
+  let mut ticker = Timer::periodic(board.TIMER0);
+  ticker.start(1_000_000 / SAMPLE_RATE);
+
+  let buffers = BUFFERS.init([[0; BUF_LEN]; 2]);
+  let sequencer = PwmSequencer::new(board.PWM0, buffers);
+  setup_pwm(&sequencer, speaker_pin.psel_bits());
+  free(|cs| SEQUENCER.borrow(cs).replace(Some(sequencer)));
+
+  unsafe { setup_interrupt(&mut board.NVIC) };
+
+  let mut b_was_pressed = false;
+
+  loop {
+    if button_a.is_low().unwrap() {
+      stop_playback();
+      record(&mut microphone, &mut ticker, &button_a);
+    }
+
+    let b_pressed = button_b.is_low().unwrap();
+    if b_pressed && !b_was_pressed {
+      start_playback();
+    }
+    b_was_pressed = b_pressed;
+
+    // poll the buttons at the sample rate
+    while ticker.wait().is_err() {}
+  }
+}
+
+// record until button a is released
+fn record(
+  microphone: &mut Microphone,
+  ticker: &mut Timer<TIMER0, Periodic>,
+  button: &Pin<Input<Floating>>,
+) {
+  free(|cs| RECORDING.borrow(cs).borrow_mut().clear());
+  rprintln!("recording");
+
+  let mut block = [0i16; BLOCK_LEN];
+
+  while button.is_low().unwrap() {
+    microphone.read_block(ticker, &mut block);
+
+    free(|cs| {
+      let mut recording = RECORDING.borrow(cs).borrow_mut();
+      for &sample in block.iter() {
+        recording.push(((sample >> 8) + 128) as u8);
+      }
+    });
+  }
+
+  let len = free(|cs| {
+    let mut recording = RECORDING.borrow(cs).borrow_mut();
+    recording.linearize();
+    recording.len
+  });
+  rprintln!("recorded {} samples", len);
+}
+
+fn start_playback() {
+  let started = free(|cs| {
+    let recording = RECORDING.borrow(cs).borrow();
+    if recording.len == 0 {
+      return false;
+    }
+
+    let mut resampler = RESAMPLER.borrow(cs).borrow_mut();
+    resampler.seek(&mut recording.samples(), 0);
+    drop(resampler);
+    drop(recording);
+
+    PLAYING.store(true, Ordering::Relaxed);
+    let mut sequencer = SEQUENCER.borrow(cs).borrow_mut();
+    let sequencer = sequencer.as_mut().unwrap();
+    sequencer.start(|buffer| fill_buffer(buffer, cs));
+    true
+  });
+  if started {
+    rprintln!("playing");
+  }
+}
+
+fn stop_playback() {
+  PLAYING.store(false, Ordering::Relaxed);
+
+  free(|cs| {
+    let mut sequencer = SEQUENCER.borrow(cs).borrow_mut();
+    sequencer.as_mut().unwrap().stop();
+  });
+}
+
+unsafe fn setup_interrupt(nvic: &mut NVIC) {
+  nvic.set_priority(interrupt::PWM0, 10);
+  NVIC::unmask(interrupt::PWM0);
+}
+
+fn setup_pwm(sequencer: &Sequencer, speaker_pin: u32) {
+  let pwm = sequencer.pwm();
+  DRIVE.setup(pwm, speaker_pin, None);
+  pwm.enable.write(|w| w.enable().enabled());
+  pwm.mode.write(|w| w.updown().up());
+  pwm
+    .prescaler
+    .write(|w| w.prescaler().variant(PWM_PRESCALER));
+  pwm
+    .countertop
+    .write(|w| unsafe { w.countertop().bits(PWM_COUNTERTOP) });
+  sequencer.set_refresh(PWM_REFRESH);
+}
+
+#[interrupt]
+fn PWM0() {
+  free(|cs| {
+    let mut sequencer = SEQUENCER.borrow(cs).borrow_mut();
+    if let Some(sequencer) = sequencer.as_mut() {
+      sequencer.handle_seqend(|buffer| fill_buffer(buffer, cs));
+      if !PLAYING.load(Ordering::Relaxed) {
+        sequencer.finish();
+      }
+    }
+  });
+}
+
+fn fill_buffer(buffer: &mut [u16], cs: &CriticalSection) {
+  let params = PcmParams {
+    data_sample_rate: SAMPLE_RATE,
+    target_sample_rate: TARGET_SAMPLE_RATE,
+    countertop: PWM_COUNTERTOP,
+    gain: U8F8::ONE,
+  };
+
+  let recording = RECORDING.borrow(cs).borrow();
+  let mut samples = recording.samples();
+  let mut resampler = RESAMPLER.borrow(cs).borrow_mut();
+  let mut shaper = SHAPER.borrow(cs).borrow_mut();
+  // the buffer before this one holds the end of the memo, this one is
+  // left silent
+  if resampler.is_finished(samples.len()) {
+    PLAYING.store(false, Ordering::Relaxed);
+  }
+  pcm::fill_samples(
+    buffer,
+    &mut samples,
+    &mut resampler,
+    // the memo is played back as recorded
+    &mut Equalizer::new(),
+    &mut shaper,
+    &params,
+  );
+}
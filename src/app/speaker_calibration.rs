@@ -0,0 +1,180 @@
+use core::{cell::RefCell, fmt::Write};
+
+use cortex_m::{
+  asm::wfi,
+  interrupt::{free, CriticalSection, Mutex},
+  peripheral::NVIC,
+};
+use heapless::String;
+use microbit::{
+  hal::{gpio::Level, prelude::_embedded_hal_timer_CountDown, Timer},
+  pac::{interrupt, pwm0::prescaler::PRESCALER_A},
+  Board,
+};
+use rtt_target::rprintln;
+use static_cell::StaticCell;
+
+use crate::{
+  audio::{
+    calibration::{sweep, Profile, Response, SweepTone},
+    goertzel::Goertzel,
+    graph::PwmSink,
+    noise_shaping::{NoiseShaper, Order},
+  },
+  raw::{
+    flash::{Flash, SETTINGS_PAGE},
+    sequencer::PwmSequencer,
+    speaker::SpeakerDrive,
+    Microphone, Serial,
+  },
+};
+
+const PWM_PRESCALER: PRESCALER_A = PRESCALER_A::DIV_4;
+const PWM_CLOCK_FREQ: u32 = 1 << (24 - (PWM_PRESCALER as u8));
+const PWM_COUNTERTOP: u16 = (PWM_CLOCK_FREQ / 44000) as u16;
+// the actual rate after rounding the countertop
+const SAMPLE_RATE: u32 = PWM_CLOCK_FREQ / PWM_COUNTERTOP as u32;
+const BUF_LEN: usize = 64;
+
+// the on-board speaker
+const DRIVE: SpeakerDrive = SpeakerDrive::Single;
+const SAMPLES_PER_BUF: usize = BUF_LEN / DRIVE.values_per_sample();
+
+// the same sink the players go through, so the profile measures the
+// path they play on
+static SINK: Mutex<RefCell<PwmSink>> = Mutex::new(RefCell::new(PwmSink::new(
+  PWM_COUNTERTOP,
+  DRIVE.silence(PWM_COUNTERTOP),
+  NoiseShaper::new(Order::Second),
+)));
+
+// the mic is sampled every 62 us (~16 kHz), fast enough to hear the
+// sweep up to 6 kHz
+const MIC_TICK_US: u32 = 62;
+const MIC_SAMPLE_RATE: u32 = 1_000_000 / MIC_TICK_US;
+const MIC_BLOCK_LEN: usize = 1024;
+
+const SWEEP_MIN_FREQ: u32 = 250;
+const SWEEP_MAX_FREQ: u32 = 6000;
+
+static BUFFERS: StaticCell<[[u16; BUF_LEN]; 2]> = StaticCell::new();
+
+type Sequencer = PwmSequencer<BUF_LEN>;
+static SEQUENCER: Mutex<RefCell<Option<Sequencer>>> =
+  Mutex::new(RefCell::new(None));
+
+static TONE: Mutex<RefCell<SweepTone>> =
+  Mutex::new(RefCell::new(SweepTone::new(SAMPLE_RATE)));
+
+pub fn run() -> ! {
+  let mut board = Board::take().unwrap();
+  let mut serial = Serial::setup(board.UARTE0, board.uart);
+  let mut str_buf: String<64> = String::new();
+  let mut flash = Flash::new(board.NVMC);
+
+  // calibrate the on-board speaker, it's right next to the mic
+  let speaker_pin = board
+    .speaker_pin
+    .into_push_pull_output(Level::Low)
+    .degrade();
+
+  let mut microphone = Microphone::setup(board.SAADC, board.microphone_pins);
+  let mut ticker = Timer::periodic(board.TIMER0);
+  ticker.start(MIC_TICK_US);
+
+  let buffers = BUFFERS.init([[0; BUF_LEN]; 2]);
+  let mut sequencer = PwmSequencer::new(board.PWM0, buffers);
+  setup_pwm(&sequencer, speaker_pin.psel_bits());
+  free(|cs| {
+    sequencer.start(|buffer| fill_buffer(buffer, cs));
+    SEQUENCER.borrow(cs).replace(Some(sequencer));
+  });
+
+  unsafe {
+    board.NVIC.set_priority(interrupt::PWM0, 10);
+    NVIC::unmask(interrupt::PWM0);
+  }
+
+  let mut block = [0i16; MIC_BLOCK_LEN];
+  let mut response = Response::default();
+
+  serial.send_str("\r\nfreq (Hz), magnitude\r\n");
+
+  for freq in sweep(SWEEP_MIN_FREQ, SWEEP_MAX_FREQ) {
+    set_tone(Some(freq));
+
+    // let the speaker settle before listening
+    microphone.read_block(&mut ticker, &mut block);
+    microphone.read_block(&mut ticker, &mut block);
+
+    let mut goertzel = Goertzel::new(freq, MIC_SAMPLE_RATE);
+    goertzel.process(&block);
+    let magnitude = goertzel.amplitude();
+    response.push(freq, magnitude);
+
+    write!(&mut str_buf, "{freq}, {magnitude}\r\n").unwrap();
+    serial.send_str(&str_buf);
+    str_buf.clear();
+  }
+
+  set_tone(None);
+
+  if let Some(peak) = response.peak() {
+    write!(&mut str_buf, "resonance peak: {} Hz\r\n", peak.freq).unwrap();
+    serial.send_str(&str_buf);
+    str_buf.clear();
+  }
+
+  let profile = Profile::from_response(&response);
+  flash.erase_page(SETTINGS_PAGE);
+  flash.write_words(SETTINGS_PAGE, &profile.to_words());
+
+  serial.send_str("compensation profile saved\r\nfreq (Hz), gain\r\n");
+  for (freq, gain) in profile.points() {
+    write!(&mut str_buf, "{freq}, {gain}\r\n").unwrap();
+    serial.send_str(&str_buf);
+    str_buf.clear();
+  }
+
+  rprintln!("calibration done");
+
+  loop {
+    wfi();
+  }
+}
+
+fn set_tone(freq: Option<u32>) {
+  free(|cs| TONE.borrow(cs).borrow_mut().set_freq(freq));
+}
+
+fn setup_pwm(sequencer: &Sequencer, speaker_pin: u32) {
+  let pwm = sequencer.pwm();
+  DRIVE.setup(pwm, speaker_pin, None);
+  pwm.mode.write(|w| w.updown().up());
+  pwm
+    .prescaler
+    .write(|w| w.prescaler().variant(PWM_PRESCALER));
+  pwm
+    .countertop
+    .write(|w| unsafe { w.countertop().bits(PWM_COUNTERTOP) });
+  // each sample is played once
+  sequencer.set_refresh(0);
+  pwm.enable.write(|w| w.enable().enabled());
+}
+
+#[interrupt]
+fn PWM0() {
+  free(|cs| {
+    let mut sequencer = SEQUENCER.borrow(cs).borrow_mut();
+    if let Some(sequencer) = sequencer.as_mut() {
+      sequencer.handle_seqend(|buffer| fill_buffer(buffer, cs));
+    }
+  });
+}
+
+fn fill_buffer(buffer: &mut [u16], cs: &CriticalSection) {
+  let mut tone = TONE.borrow(cs).borrow_mut();
+  let mut sink = SINK.borrow(cs).borrow_mut();
+  sink.fill(&mut *tone, &mut buffer[..SAMPLES_PER_BUF]);
+  DRIVE.spread(buffer);
+}
@@ -5,14 +5,32 @@ use cortex_m::{
   interrupt::{free, Mutex},
   peripheral::NVIC,
 };
+use fixed::types::U8F8;
 use microbit::{
   hal::gpio::{Input, Level, Output, Pin, PullUp, PushPull},
-  pac::{interrupt, pwm0::prescaler::PRESCALER_A, GPIOTE, PWM0},
+  pac::{interrupt, pwm0::prescaler::PRESCALER_A, GPIOTE},
   Board,
 };
 use rtt_target::rprintln;
-
-use micromath::F32Ext;
+use static_cell::StaticCell;
+
+use crate::{
+  audio::{
+    calibration::{Profile, PROFILE_WORDS},
+    dtmf,
+    envelope::Adsr,
+    eq::Filter,
+    graph::{ClipSource, Mixer, PwmSink},
+    noise_shaping::{NoiseShaper, Order},
+    resample::Interpolation,
+    tone::Tone,
+  },
+  raw::{
+    flash::{self, SETTINGS_PAGE},
+    sequencer::PwmSequencer,
+    speaker::SpeakerDrive,
+  },
+};
 
 // the prescaler sets the PWM clock frequency.
 const PWM_PRESCALER: PRESCALER_A = PRESCALER_A::DIV_4;
@@ -20,21 +38,63 @@ const PWM_CLOCK_FREQ: u32 = 1 << (24 - (PWM_PRESCALER as u8));
 const PWM_COUNTER_TOP: u16 = (PWM_CLOCK_FREQ / SAMPLE_RATE) as u16;
 
 const SAMPLE_RATE: u32 = 44000;
+// the rate the samples are actually played at, after rounding the
+// countertop
+const PLAYBACK_RATE: u32 = PWM_CLOCK_FREQ / PWM_COUNTER_TOP as u32;
 const BUFFER_SIZE: usize = 64;
 
+// bridged needs a speaker across edge pins 0 and 1
+const DRIVE: SpeakerDrive = SpeakerDrive::Single;
+const SAMPLES_PER_BUFFER: usize = BUFFER_SIZE / DRIVE.values_per_sample();
+static BUFFERS: StaticCell<[[u16; BUFFER_SIZE]; 2]> = StaticCell::new();
+
+// the countertop of 90 leaves less than 7 bits per sample. shaping the
+// rounding noise moves most of it above the audible band.
+const NOISE_SHAPING: Order = Order::Second;
+
+// eq::MLT_8530 for the built-in speaker
+const EQUALIZER: &[Filter] = &[];
+
+// every note starts with the attack of the envelope. with
+// Adsr::PLUCK the buttons pluck the notes instead of holding them.
+const ENVELOPE: Adsr = Adsr::ORGAN;
+
+// a clip to play the note over, over and over, e.g.
+// Some(assets::CHIME.wav)
+const BACKGROUND: Option<&[u8]> = None;
+// the note and the clip are mixed at half volume each
+const MIX_GAIN: U8F8 = U8F8::from_bits(1 << 7);
+
+// what the buttons do
+#[derive(Clone, Copy, PartialEq)]
+#[allow(unused)]
+enum Mode {
+  // button a/b moves the note up/down
+  Note,
+  // button a sends DTMF_CODE, button b sends the next key of it. the
+  // tones can be decoded by app_dtmf_decoder on another board.
+  Dtmf,
+}
+
+const MODE: Mode = Mode::Note;
+const DTMF_CODE: &[u8] = b"0123456789*#ABCD";
+
 static APP: Mutex<RefCell<Option<App>>> = Mutex::new(RefCell::new(None));
 
 struct Peripherals {
-  pwm: PWM0,
+  sequencer: PwmSequencer<BUFFER_SIZE>,
   nvic: NVIC,
   speaker_pin: Pin<Output<PushPull>>,
+  // the other side of a bridged speaker
+  speaker_neg_pin: Option<Pin<Output<PushPull>>>,
   buttons: [Pin<Input<PullUp>>; 2],
   gpiote: GPIOTE,
 }
 
 impl Peripherals {
   fn take(board: Board) -> Self {
-    let pwm = board.PWM0;
+    let buffers = BUFFERS.init([[0; BUFFER_SIZE]; 2]);
+    let sequencer = PwmSequencer::new(board.PWM0, buffers);
     let nvic = board.NVIC;
 
     // the built-in speaker
@@ -46,6 +106,12 @@ impl Peripherals {
     // the speaker on io:bit extension board
     let speaker_pin =
       board.edge.e00.into_push_pull_output(Level::Low).degrade();
+    let speaker_neg_pin = match DRIVE {
+      SpeakerDrive::Single => None,
+      SpeakerDrive::Bridged => {
+        Some(board.edge.e01.into_push_pull_output(Level::Low).degrade())
+      }
+    };
     let buttons = [
       board.buttons.button_a.into_pullup_input().degrade(),
       board.buttons.button_b.into_pullup_input().degrade(),
@@ -53,9 +119,10 @@ impl Peripherals {
     let gpiote = board.GPIOTE;
 
     Self {
-      pwm,
+      sequencer,
       nvic,
       speaker_pin,
+      speaker_neg_pin,
       buttons,
       gpiote,
     }
@@ -63,66 +130,100 @@ impl Peripherals {
 }
 
 struct NoteGen {
-  note: u8,
-  volume: u8,
-  offset: usize,
-  buffers: [[u16; BUFFER_SIZE]; 2],
+  tone: Tone,
+  background: Option<ClipSource<'static>>,
+  dtmf: dtmf::Sender,
+  // the key of DTMF_CODE sent by button b
+  dtmf_key: usize,
+  sink: PwmSink,
 }
 
-const BASE_FREQ: f32 = 261.62558;
-// EXP2_ONE_TWELFTH = 2^(1/12)
-const EXP2_ONE_TWELFTH: f32 = 1.0594631;
-
 impl NoteGen {
   fn new() -> Self {
+    let profile =
+      Profile::from_words(flash::read_words(SETTINGS_PAGE, PROFILE_WORDS));
+    let mut tone = Tone::new(PLAYBACK_RATE, profile);
+    tone.set_equalizer(EQUALIZER);
+    tone.set_envelope(ENVELOPE);
+
+    let background = BACKGROUND.and_then(|wav| {
+      ClipSource::new(wav, PLAYBACK_RATE, Interpolation::Linear)
+        .map_err(|e| rprintln!("can't play the background: {:?}", e))
+        .ok()
+    });
+
     Self {
-      note: 60,
-      volume: 20,
-      offset: 0,
-      buffers: [[0; BUFFER_SIZE]; 2],
+      tone,
+      background,
+      // the dtmf tones need the exact rate
+      dtmf: dtmf::Sender::new(PLAYBACK_RATE),
+      dtmf_key: 0,
+      sink: PwmSink::new(
+        PWM_COUNTER_TOP,
+        DRIVE.silence(PWM_COUNTER_TOP),
+        NoiseShaper::new(NOISE_SHAPING),
+      ),
     }
   }
 
-  fn freq(&self) -> f32 {
-    let x: i32 = self.note as i32 - 60;
-    BASE_FREQ * EXP2_ONE_TWELFTH.powi(x)
-  }
+  fn fill_buffer(&mut self, buffer: &mut [u16]) {
+    let samples = &mut buffer[..SAMPLES_PER_BUFFER];
+
+    match self.background.as_mut() {
+      Some(clip) => {
+        if clip.is_finished() {
+          clip.rewind();
+        }
+        let mut mixer = Mixer::<2>::new()
+          .input(&mut self.tone, MIX_GAIN)
+          .input(clip, MIX_GAIN);
+        self.sink.fill(&mut mixer, samples);
+      }
+      None => self.sink.fill(&mut self.tone, samples),
+    }
 
-  // in units of samples
-  fn period(&self) -> usize {
-    (SAMPLE_RATE as f32 / self.freq()) as usize
+    DRIVE.spread(buffer);
   }
 
-  fn fill_buffer(&mut self, buffer_idx: usize) {
-    let period = self.period().max(1);
-    let vol = self.volume as f32 / 127.0;
-    let buffer = &mut self.buffers[buffer_idx];
+  fn fill_dtmf_buffer(&mut self, buffer: &mut [u16]) {
+    // played at full volume so the other board can hear it
+    self
+      .sink
+      .fill(&mut self.dtmf, &mut buffer[..SAMPLES_PER_BUFFER]);
+    DRIVE.spread(buffer);
+  }
 
-    #[allow(clippy::needless_range_loop)]
-    for i in 0..BUFFER_SIZE {
-      let phase = ((self.offset + i) % period) as f32 / period as f32;
+  fn send_dtmf(&mut self, code: &[u8]) {
+    rprintln!("dtmf: {}", core::str::from_utf8(code).unwrap_or("?"));
+    self.dtmf.send(code);
+  }
 
-      let sample = sine_waveform(phase) * vol;
-      let sample = (sample + 1.0) / 2.0 * (PWM_COUNTER_TOP as f32);
+  fn send_next_dtmf_key(&mut self) {
+    let i = self.dtmf_key;
+    self.dtmf_key = (i + 1) % DTMF_CODE.len();
+    self.send_dtmf(&DTMF_CODE[i..=i]);
+  }
 
-      buffer[i] = sample as u16;
-      // rprintln!("{} ({}): sin({}) -> {} ({})", i, phase, x, y, buffer[i]);
+  fn fill(&mut self, buffer: &mut [u16]) {
+    match MODE {
+      Mode::Note => self.fill_buffer(buffer),
+      Mode::Dtmf => self.fill_dtmf_buffer(buffer),
     }
+  }
 
-    self.offset = (self.offset + BUFFER_SIZE) % period;
+  fn note(&self) -> u8 {
+    self.tone.note()
   }
 
   fn set_note(&mut self, note: u8) {
-    self.note = note;
-    self.offset = 0;
+    self.tone.set_note(note);
 
     rprintln!(
-      "note: {}, freq: {}, top: {}, period: {}, vol: {}",
-      self.note,
-      self.freq(),
+      "note: {}, freq: {}, top: {}, vol: {}/127",
+      self.tone.note(),
+      self.tone.freq(),
       PWM_COUNTER_TOP,
-      self.period(),
-      self.volume as f32 / 127.0
+      self.tone.volume()
     );
   }
 }
@@ -157,9 +258,15 @@ impl App {
   }
 
   fn setup_pwm(&mut self) {
-    let pwm = &self.peripherals.pwm;
+    let sequencer = &self.peripherals.sequencer;
+    let pwm = sequencer.pwm();
     let speaker_pin = self.peripherals.speaker_pin.psel_bits();
-    pwm.psel.out[0].write(|w| unsafe { w.bits(speaker_pin) });
+    let speaker_neg_pin = self
+      .peripherals
+      .speaker_neg_pin
+      .as_ref()
+      .map(|p| p.psel_bits());
+    DRIVE.setup(pwm, speaker_pin, speaker_neg_pin);
 
     pwm.mode.write(|w| w.updown().up());
     pwm
@@ -168,28 +275,10 @@ impl App {
     pwm
       .countertop
       .write(|w| unsafe { w.countertop().bits(PWM_COUNTER_TOP) });
-
-    let buf_len = BUFFER_SIZE as u16;
-
-    let buf_ptr = self.note_gen.buffers[0].as_ptr() as u32;
-    pwm.seq0.ptr.write(|w| unsafe { w.bits(buf_ptr) });
-    pwm.seq0.cnt.write(|w| unsafe { w.cnt().bits(buf_len) });
-    pwm.seq0.refresh.write(|w| w.cnt().continuous());
-    pwm.seq0.enddelay.write(|w| unsafe { w.bits(0) });
-
-    let buf_ptr = self.note_gen.buffers[1].as_ptr() as u32;
-    pwm.seq1.ptr.write(|w| unsafe { w.bits(buf_ptr) });
-    pwm.seq1.cnt.write(|w| unsafe { w.cnt().bits(buf_len) });
-    pwm.seq1.refresh.write(|w| w.cnt().continuous());
-    pwm.seq1.enddelay.write(|w| unsafe { w.bits(0) });
-
-    pwm
-      .decoder
-      .write(|w| w.load().common().mode().refresh_count());
+    // each sample is played once
+    sequencer.set_refresh(0);
 
     pwm.enable.write(|w| w.enable().enabled());
-
-    pwm.intenset.write(|w| w.seqend0().set().seqend1().set());
   }
 
   fn setup_buttons(&mut self) {
@@ -241,33 +330,19 @@ impl App {
   }
 
   fn start_sequence(&mut self) {
-    self.note_gen.fill_buffer(0);
-    self.note_gen.fill_buffer(1);
-
-    self.peripherals.pwm.tasks_seqstart[0]
-      .write(|w| w.tasks_seqstart().trigger());
+    let note_gen = &mut self.note_gen;
+    self
+      .peripherals
+      .sequencer
+      .start(|buffer| note_gen.fill(buffer));
   }
 
   fn handle_pwm_seqend(&mut self) {
-    let pwm = &self.peripherals.pwm;
-
-    if pwm.events_seqend[0].read().bits() != 0 {
-      // rprintln!("seqend0");
-      pwm.events_seqend[0].write(|w| w.events_seqend().clear_bit());
-      pwm.tasks_seqstart[1].write(|w| w.tasks_seqstart().trigger());
-      self.note_gen.fill_buffer(0);
-      return;
-    }
-
-    if pwm.events_seqend[1].read().bits() != 0 {
-      // rprintln!("seqend1");
-      pwm.events_seqend[1].write(|w| w.events_seqend().clear_bit());
-      pwm.tasks_seqstart[0].write(|w| w.tasks_seqstart().trigger());
-      self.note_gen.fill_buffer(1);
-      return;
+    let note_gen = &mut self.note_gen;
+    let sequencer = &mut self.peripherals.sequencer;
+    if !sequencer.handle_seqend(|buffer| note_gen.fill(buffer)) {
+      rprintln!("Unhandled PWM event");
     }
-
-    rprintln!("Unhandled PWM event");
   }
 
   fn handle_button_input(&mut self) {
@@ -276,14 +351,24 @@ impl App {
     if gpiote.events_in[0].read().bits() != 0 {
       gpiote.events_in[0].write(|w| w.events_in().clear_bit());
 
-      self.note_gen.set_note(self.note_gen.note.saturating_add(1));
+      match MODE {
+        Mode::Note => self
+          .note_gen
+          .set_note(self.note_gen.note().saturating_add(1)),
+        Mode::Dtmf => self.note_gen.send_dtmf(DTMF_CODE),
+      }
       return;
     }
 
     if gpiote.events_in[1].read().bits() != 0 {
       gpiote.events_in[1].write(|w| w.events_in().clear_bit());
 
-      self.note_gen.set_note(self.note_gen.note.saturating_sub(1));
+      match MODE {
+        Mode::Note => self
+          .note_gen
+          .set_note(self.note_gen.note().saturating_sub(1)),
+        Mode::Dtmf => self.note_gen.send_next_dtmf_key(),
+      }
       return;
     }
 
@@ -327,20 +412,3 @@ fn PWM0() {
     app.handle_pwm_seqend();
   });
 }
-
-// input: [0, 1], output: [-1, 1]
-// allowed because f32::consts doesn't exist in no_std
-#[allow(unused)]
-#[allow(clippy::approx_constant)]
-fn sine_waveform(phase: f32) -> f32 {
-  (2.0 * 3.14159 * phase).sin()
-}
-
-#[allow(unused)]
-fn square_waveform(phase: f32) -> f32 {
-  if phase < 0.5 {
-    -1.0
-  } else {
-    1.0
-  }
-}
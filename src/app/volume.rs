@@ -1,13 +1,21 @@
+use fixed::types::U8F8;
 use heapless::String;
-use microbit::{hal::Timer, Board};
+use microbit::{
+  hal::{prelude::_embedded_hal_timer_CountDown, Timer},
+  Board,
+};
 
 use core::fmt::Write;
 
 use crate::{
+  audio::agc::{self, AgcConfig, GateConfig, MicChain},
   raw::LedMatrix,
   raw::{Microphone, Serial},
 };
 
+const SAMPLE_RATE: u32 = 8000;
+const BLOCK_LEN: usize = 256;
+
 pub fn show_volumne() -> ! {
   let board = Board::take().unwrap();
   let mut serial = Serial::setup(board.UARTE0, board.uart);
@@ -17,24 +25,35 @@ pub fn show_volumne() -> ! {
   let mut led = LedMatrix::setup(board.display_pins, timer);
   let mut microphone = Microphone::setup(board.SAADC, board.microphone_pins);
 
+  // keep the max gain low, otherwise the agc levels everything out and
+  // the meter always shows the same volume
+  let agc_config = AgcConfig {
+    max_gain: U8F8::const_from_int(8),
+    ..Default::default()
+  };
+  let target = agc_config.target_level;
+  microphone
+    .set_chain(MicChain::new(Some(GateConfig::default()), Some(agc_config)));
+
+  let mut ticker = Timer::periodic(board.TIMER0);
+  ticker.start(1_000_000 / SAMPLE_RATE);
+  let mut block = [0i16; BLOCK_LEN];
+
   write!(&mut str_buf, "\r\n\r\n\r\n\r\n\n").unwrap();
   serial.send_str(&str_buf);
   str_buf.clear();
 
   loop {
-    let mic_value = { microphone.sample(10) };
+    microphone.read_block(&mut ticker, &mut block);
+    let mic_value = agc::level(&block);
 
     write!(&mut str_buf, "{mic_value}\r\n").unwrap();
     serial.send_str(&str_buf);
     str_buf.clear();
 
-    let image = [
-      [if mic_value > 100 { 1 } else { 0 }; 5],
-      [if mic_value > 80 { 1 } else { 0 }; 5],
-      [if mic_value > 60 { 1 } else { 0 }; 5],
-      [if mic_value > 40 { 1 } else { 0 }; 5],
-      [if mic_value > 20 { 1 } else { 0 }; 5],
-    ];
+    // each row lights up at another eighth of the agc target level
+    let row = |n: u16| [(mic_value > target / 8 * n) as u8; 5];
+    let image = [row(5), row(4), row(3), row(2), row(1)];
     led.set_matrix(image);
     led.show(100);
   }
@@ -0,0 +1,35 @@
+// the crc-32 of zip and png, to check the assets in flash against the
+// build. this file is also compiled into build.rs.
+
+const POLY: u32 = 0xedb8_8320;
+
+// a table per nibble is a sixteenth of the usual table per byte, and
+// still only takes two lookups per byte
+const TABLE: [u32; 16] = {
+  let mut table = [0; 16];
+  let mut i = 0;
+  while i < 16 {
+    let mut crc = i as u32;
+    let mut bit = 0;
+    while bit < 4 {
+      crc = if crc & 1 == 1 {
+        (crc >> 1) ^ POLY
+      } else {
+        crc >> 1
+      };
+      bit += 1;
+    }
+    table[i] = crc;
+    i += 1;
+  }
+  table
+};
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+  let mut crc = !0u32;
+  for &byte in bytes {
+    crc = TABLE[((crc ^ byte as u32) & 0xf) as usize] ^ (crc >> 4);
+    crc = TABLE[((crc ^ (byte >> 4) as u32) & 0xf) as usize] ^ (crc >> 4);
+  }
+  !crc
+}
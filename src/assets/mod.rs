@@ -0,0 +1,58 @@
+// the files in assets/, prepared by build.rs. every file gets a
+// constant named after it, e.g. assets/bad-apple.wav is BAD_APPLE.
+// names starting with a digit get the kind in front, like
+// MIDI_1080_C01.
+
+pub mod crc32;
+
+pub use crate::audio::tempo::Division;
+use crate::audio::{player::Clip, wav::Format};
+
+pub struct Audio {
+  pub name: &'static str,
+  // a mono IMA ADPCM wav file, whatever the source was
+  pub wav: &'static [u8],
+  pub format: Format,
+  pub sample_rate: u32,
+  // the number of samples
+  pub frames: usize,
+  pub checksum: u32,
+}
+
+pub struct Midi {
+  pub name: &'static str,
+  // a format 0 midi file with the tracks merged into one, holding the
+  // channel messages and tempo changes
+  pub smf: &'static [u8],
+  pub division: Division,
+  pub events: usize,
+  // the length of the song
+  pub ticks: u32,
+  pub checksum: u32,
+}
+
+impl Audio {
+  pub const fn clip(&self) -> Clip {
+    Clip {
+      name: self.name,
+      wav: self.wav,
+    }
+  }
+
+  pub fn duration_ms(&self) -> u32 {
+    (self.frames as u64 * 1000 / self.sample_rate as u64) as u32
+  }
+
+  // whether the file in flash is the one that was built
+  pub fn verify(&self) -> bool {
+    crc32::crc32(self.wav) == self.checksum
+  }
+}
+
+impl Midi {
+  pub fn verify(&self) -> bool {
+    crc32::crc32(self.smf) == self.checksum
+  }
+}
+
+include!(concat!(env!("OUT_DIR"), "/assets.rs"));
@@ -12,8 +12,14 @@ use panic_probe as _;
 use nrf52833_hal as _;
 
 mod app;
+// the players each use some of the assets
+#[allow(dead_code)]
+mod assets;
 mod raw;
 
+// the audio code is a crate of its own so it can be tested on the host
+use microbity_audio as audio;
+
 #[entry]
 fn main() -> ! {
   #[cfg(feature = "app_playground")]
@@ -30,6 +36,14 @@ fn main() -> ! {
   app::midi_player::play();
   #[cfg(feature = "app_tone_generator")]
   app::tone_generator::play();
+  #[cfg(feature = "app_recorder")]
+  app::recorder::run();
+  #[cfg(feature = "app_speaker_calibration")]
+  app::speaker_calibration::run();
+  #[cfg(feature = "app_dtmf_decoder")]
+  app::dtmf_decoder::run();
+  #[cfg(feature = "app_acoustic_modem")]
+  app::acoustic_modem::run();
   #[cfg(feature = "app_ble_temp")]
   app::ble_temp::run();
 }
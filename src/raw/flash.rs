@@ -0,0 +1,47 @@
+use microbit::pac::NVMC;
+
+pub const PAGE_SIZE: u32 = 4096;
+
+// the last page of the flash is reserved for settings that survive a
+// reset. the program has to stay below it.
+pub const SETTINGS_PAGE: u32 = 0x0008_0000 - PAGE_SIZE;
+
+pub struct Flash {
+  nvmc: NVMC,
+}
+
+impl Flash {
+  pub fn new(nvmc: NVMC) -> Self {
+    Self { nvmc }
+  }
+
+  pub fn erase_page(&mut self, addr: u32) {
+    self.nvmc.config.write(|w| w.wen().een());
+    self
+      .nvmc
+      .erasepage()
+      .write(|w| unsafe { w.erasepage().bits(addr) });
+    self.wait_ready();
+    self.nvmc.config.write(|w| w.wen().ren());
+  }
+
+  // the words must have been erased before, flash can only clear bits
+  pub fn write_words(&mut self, addr: u32, words: &[u32]) {
+    self.nvmc.config.write(|w| w.wen().wen());
+    for (i, word) in words.iter().enumerate() {
+      let ptr = (addr as usize + i * 4) as *mut u32;
+      unsafe { core::ptr::write_volatile(ptr, *word) };
+      self.wait_ready();
+    }
+    self.nvmc.config.write(|w| w.wen().ren());
+  }
+
+  fn wait_ready(&self) {
+    while self.nvmc.ready.read().ready().is_busy() {}
+  }
+}
+
+// flash is memory mapped, so reading doesn't need the NVMC
+pub fn read_words(addr: u32, len: usize) -> &'static [u32] {
+  unsafe { core::slice::from_raw_parts(addr as *const u32, len) }
+}
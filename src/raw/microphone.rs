@@ -5,15 +5,20 @@ use microbit::{
   gpio::MicrophonePins,
   hal::{
     gpio::{p0::P0_05, Floating, Input},
+    prelude::_embedded_hal_timer_CountDown,
     saadc::SaadcConfig,
-    Saadc,
+    timer::{Instance, Periodic},
+    Saadc, Timer,
   },
   pac::SAADC,
 };
 
+use crate::audio::agc::MicChain;
+
 pub struct Microphone {
   mic_in: P0_05<Input<Floating>>,
   saadc: Saadc,
+  chain: MicChain,
 }
 
 impl Microphone {
@@ -28,7 +33,11 @@ impl Microphone {
 
     let mic_in = microphone_pins.mic_in.into_floating_input();
 
-    Self { mic_in, saadc }
+    Self {
+      mic_in,
+      saadc,
+      chain: MicChain::default(),
+    }
   }
 
   pub fn read(&mut self) -> u16 {
@@ -39,6 +48,37 @@ impl Microphone {
       .unsigned_abs()
   }
 
+  // the raw reading, biased around half of the reference voltage
+  pub fn read_raw(&mut self) -> i16 {
+    self.saadc.read(&mut self.mic_in).unwrap_or_default()
+  }
+
+  // replace the processing applied by read_block
+  pub fn set_chain(&mut self, chain: MicChain) {
+    self.chain = chain;
+  }
+
+  pub fn chain(&self) -> &MicChain {
+    &self.chain
+  }
+
+  // fill the buffer with samples, one reading per tick of the
+  // periodic timer. the timer decides the sample rate. the samples
+  // are passed through the processing chain, so they are centered
+  // around zero.
+  pub fn read_block<T: Instance>(
+    &mut self,
+    ticker: &mut Timer<T, Periodic>,
+    buf: &mut [i16],
+  ) {
+    for sample in buf.iter_mut() {
+      while ticker.wait().is_err() {}
+      *sample = self.read_raw();
+    }
+
+    self.chain.process(buf);
+  }
+
   pub fn sample(&mut self, n: usize) -> u16 {
     let mut avg = 0u16;
     let mut div = 0u16;
@@ -5,6 +5,16 @@ pub mod led;
 pub mod microphone;
 pub mod serial;
 
+// only one app is built at a time, and each uses a part of these
+#[allow(dead_code)]
+pub mod flash;
+#[allow(dead_code)]
+pub mod scroll;
+#[allow(dead_code)]
+pub mod sequencer;
+#[allow(dead_code)]
+pub mod speaker;
+
 pub use led::LedMatrix;
 pub use microphone::Microphone;
 pub use serial::Serial;
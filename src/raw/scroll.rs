@@ -0,0 +1,100 @@
+// scrolls a short text across the led matrix, one column per frame.
+// the frames can be shown with any display driver.
+
+use heapless::Vec;
+
+// each row is 3 pixels wide, the highest bit is the left one
+#[rustfmt::skip]
+const FONT: [(char, [u8; 5]); 17] = [
+  ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+  ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+  ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+  ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+  ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+  ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+  ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+  ('7', [0b111, 0b001, 0b010, 0b010, 0b010]),
+  ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+  ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+  (' ', [0b000, 0b000, 0b000, 0b000, 0b000]),
+  ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+  ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+  (':', [0b000, 0b010, 0b000, 0b010, 0b000]),
+  ('/', [0b001, 0b001, 0b010, 0b100, 0b100]),
+  ('E', [0b111, 0b100, 0b111, 0b100, 0b111]),
+  ('r', [0b000, 0b101, 0b110, 0b100, 0b100]),
+];
+
+const GLYPH_WIDTH: usize = 3;
+pub const MAX_COLUMNS: usize = 80;
+
+pub type Frame = [[u8; 5]; 5];
+
+pub struct ScrollText {
+  // one bit per row, the top row in the lowest bit
+  columns: Vec<u8, MAX_COLUMNS>,
+  offset: usize,
+}
+
+impl Default for ScrollText {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl ScrollText {
+  pub const fn new() -> Self {
+    Self {
+      columns: Vec::new(),
+      offset: 0,
+    }
+  }
+
+  // characters without a glyph are skipped, text that doesn't fit is
+  // cut off
+  pub fn set_text(&mut self, text: &str) {
+    self.columns.clear();
+    self.offset = 0;
+
+    for c in text.chars() {
+      let Some((_, rows)) = FONT.iter().find(|(g, _)| *g == c) else {
+        continue;
+      };
+
+      for x in 0..GLYPH_WIDTH {
+        let shift = GLYPH_WIDTH - 1 - x;
+        let column = rows
+          .iter()
+          .enumerate()
+          .fold(0, |col, (y, row)| col | ((row >> shift) & 1) << y);
+        self.columns.push(column).ok();
+      }
+      // a column of space between the characters
+      self.columns.push(0).ok();
+    }
+
+    // a blank screen before the text comes around again
+    for _ in 0..4 {
+      self.columns.push(0).ok();
+    }
+  }
+
+  // the frame to show next, the text moves left by a column each time
+  pub fn next_frame(&mut self) -> Frame {
+    let mut frame = [[0; 5]; 5];
+    let len = self.columns.len();
+    if len == 0 {
+      return frame;
+    }
+
+    for x in 0..5 {
+      let column = self.columns[(self.offset + x) % len];
+      for (y, row) in frame.iter_mut().enumerate() {
+        row[x] = (column >> y) & 1;
+      }
+    }
+
+    self.offset = (self.offset + 1) % len;
+    frame
+  }
+}
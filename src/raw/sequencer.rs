@@ -0,0 +1,181 @@
+// double buffered playback of pwm sequences. the pwm plays one buffer
+// while the other one is filled, and they swap on every SEQEND.
+//
+// the pwm reads the buffers by dma from the addresses in SEQ[n].PTR,
+// so they must not move once playback starts. the sequencer takes the
+// buffers as &'static mut, which a buffer on the stack or in a struct
+// can't be borrowed as, and keeps the only reference to them. the app
+// only ever sees the buffer that isn't playing, through the fill
+// callback.
+//
+// with the stats enabled, every refill is timed with the DWT cycle
+// counter. the SEQEND of a buffer is expected a buffer's duration after
+// it was started, so the interrupt can tell how late it is.
+
+use cortex_m::peripheral::{DCB, DWT};
+use microbit::pac::PWM0;
+
+use crate::audio::stats::{PipelineStats, Refill};
+
+// the cpu runs at 64 MHz, 4 cycles per tick of the undivided pwm clock
+const CYCLES_PER_PWM_TICK: u32 = 4;
+
+pub struct PwmSequencer<const N: usize> {
+  pwm: PWM0,
+  buffers: &'static mut [[u16; N]; 2],
+  stats: Option<PipelineStats>,
+  // the cycle count when the playing buffer was started
+  started: u32,
+  // between start and stop
+  running: bool,
+}
+
+impl<const N: usize> PwmSequencer<N> {
+  // points the sequences at the buffers and enables the SEQEND
+  // interrupts. the rest of the setup is up to the app, through pwm().
+  pub fn new(pwm: PWM0, buffers: &'static mut [[u16; N]; 2]) -> Self {
+    let ptr0 = buffers[0].as_ptr() as u32;
+    pwm.seq0.ptr.write(|w| unsafe { w.bits(ptr0) });
+    pwm.seq0.cnt.write(|w| unsafe { w.bits(N as u32) });
+    pwm.seq0.enddelay.write(|w| unsafe { w.bits(0) });
+
+    let ptr1 = buffers[1].as_ptr() as u32;
+    pwm.seq1.ptr.write(|w| unsafe { w.bits(ptr1) });
+    pwm.seq1.cnt.write(|w| unsafe { w.bits(N as u32) });
+    pwm.seq1.enddelay.write(|w| unsafe { w.bits(0) });
+
+    pwm.intenset.write(|w| w.seqend0().set().seqend1().set());
+
+    Self {
+      pwm,
+      buffers,
+      stats: None,
+      started: 0,
+      running: false,
+    }
+  }
+
+  // time the refills from now on
+  pub fn enable_stats(&mut self, dcb: &mut DCB, dwt: &mut DWT) {
+    dcb.enable_trace();
+    dwt.enable_cycle_counter();
+    self.stats = Some(PipelineStats::new());
+  }
+
+  pub fn stats(&self) -> Option<&PipelineStats> {
+    self.stats.as_ref()
+  }
+
+  pub fn reset_stats(&mut self) {
+    if let Some(stats) = self.stats.as_mut() {
+      stats.reset();
+    }
+  }
+
+  // the duty values loaded at once, one per sample
+  fn values_per_load(&self) -> u32 {
+    let load = self.pwm.decoder.read().load();
+    if load.is_common() {
+      1
+    } else if load.is_grouped() {
+      2
+    } else {
+      4
+    }
+  }
+
+  fn samples(&self) -> u32 {
+    N as u32 / self.values_per_load()
+  }
+
+  // the cycles a buffer plays for, from the registers as they are now
+  fn buffer_cycles(&self) -> u32 {
+    let pwm = &self.pwm;
+    let prescaler = pwm.prescaler.read().prescaler().bits() as u32;
+    let mut pwm_period = pwm.countertop.read().countertop().bits() as u32;
+    if pwm.mode.read().updown().is_up_and_down() {
+      pwm_period *= 2;
+    }
+    let refresh = pwm.seq0.refresh.read().bits();
+
+    self.samples()
+      * (refresh + 1)
+      * pwm_period
+      * (CYCLES_PER_PWM_TICK << prescaler)
+  }
+
+  pub fn pwm(&self) -> &PWM0 {
+    &self.pwm
+  }
+
+  // each value is played refresh + 1 times
+  pub fn set_refresh(&self, refresh: u32) {
+    self.pwm.seq0.refresh.write(|w| unsafe { w.bits(refresh) });
+    self.pwm.seq1.refresh.write(|w| unsafe { w.bits(refresh) });
+  }
+
+  // fill both buffers and play the first one
+  pub fn start(&mut self, mut fill: impl FnMut(&mut [u16])) {
+    fill(&mut self.buffers[0]);
+    fill(&mut self.buffers[1]);
+    self.pwm.tasks_seqstart[0].write(|w| w.tasks_seqstart().trigger());
+    self.started = DWT::cycle_count();
+    self.running = true;
+  }
+
+  // a SEQEND that comes in after this doesn't start the other buffer
+  pub fn stop(&mut self) {
+    self.running = false;
+    self.pwm.tasks_stop.write(|w| w.tasks_stop().trigger());
+  }
+
+  // plays out the buffer that was started last, then stops. the pwm
+  // holds the last duty value of it.
+  pub fn finish(&mut self) {
+    self.running = false;
+  }
+
+  // call from the PWM0 interrupt. starts the other buffer and refills
+  // the one that was played out, unless the sequencer was stopped.
+  // returns false if there was no SEQEND.
+  pub fn handle_seqend(&mut self, mut fill: impl FnMut(&mut [u16])) -> bool {
+    let mut handled = false;
+
+    for seq in 0..2 {
+      if self.pwm.events_seqend[seq].read().bits() == 0 {
+        continue;
+      }
+
+      let entry = DWT::cycle_count();
+      self.pwm.events_seqend[seq].write(|w| w.events_seqend().clear_bit());
+      handled = true;
+      // the last buffer before a stop
+      if !self.running {
+        continue;
+      }
+
+      let period = match self.stats {
+        Some(_) => self.buffer_cycles(),
+        None => 0,
+      };
+      let late = entry.wrapping_sub(self.started).saturating_sub(period);
+      let samples = self.samples();
+
+      self.pwm.tasks_seqstart[1 - seq].write(|w| w.tasks_seqstart().trigger());
+      self.started = DWT::cycle_count();
+
+      // a late interrupt is only counted. the other buffer was filled
+      // in time and is playing, and this one still has a buffer's
+      // duration to be refilled, so dropping it would lose samples
+      // that can still be played.
+      fill(&mut self.buffers[seq]);
+
+      if let Some(stats) = self.stats.as_mut() {
+        let isr = DWT::cycle_count().wrapping_sub(entry);
+        stats.record(&Refill { period, late, isr }, samples);
+      }
+    }
+
+    handled
+  }
+}
@@ -0,0 +1,78 @@
+use microbit::pac::PWM0;
+
+// bit 15 of a duty value flips the polarity of the pwm output
+const INVERTED: u16 = 1 << 15;
+
+// how the speaker is wired to the pwm
+#[derive(Clone, Copy, PartialEq)]
+pub enum SpeakerDrive {
+  // the speaker between a pin and ground. the pin only swings between
+  // 0 and 3.3V, so the speaker gets half of the swing of a bridge.
+  Single,
+  // the speaker across two pins driven with opposite waveforms, e.g.
+  // an external speaker on edge pins 0 and 1. the voltage across it
+  // swings from -3.3V to 3.3V.
+  Bridged,
+}
+
+impl SpeakerDrive {
+  // the values in the pwm sequence for each sample
+  pub const fn values_per_sample(self) -> usize {
+    match self {
+      SpeakerDrive::Single => 1,
+      SpeakerDrive::Bridged => 2,
+    }
+  }
+
+  // the duty value for silence, the same as a sample of 0. anything
+  // else jumps when the sound stops or starts, which clicks, and a
+  // bridge at 0 would hold one pin low and the other high, leaving a
+  // constant voltage across the speaker.
+  pub const fn silence(self, countertop: u16) -> u16 {
+    match self {
+      SpeakerDrive::Single | SpeakerDrive::Bridged => countertop / 2,
+    }
+  }
+
+  // connect the pwm channels to the pins and set up the decoder. the
+  // negative pin is only used when bridged.
+  pub fn setup(self, pwm: &PWM0, positive_pin: u32, negative_pin: Option<u32>) {
+    pwm.psel.out[0].write(|w| unsafe { w.bits(positive_pin) });
+
+    match (self, negative_pin) {
+      (SpeakerDrive::Single, _) => {
+        // one value drives all channels
+        pwm
+          .decoder
+          .write(|w| w.load().common().mode().refresh_count());
+      }
+      (SpeakerDrive::Bridged, Some(negative_pin)) => {
+        // the first value of each pair drives channel 0 (and 1), the
+        // second drives channel 2 (and 3)
+        pwm.psel.out[2].write(|w| unsafe { w.bits(negative_pin) });
+        pwm
+          .decoder
+          .write(|w| w.load().grouped().mode().refresh_count());
+      }
+      (SpeakerDrive::Bridged, None) => {
+        panic!("bridged drive needs two pins")
+      }
+    }
+  }
+
+  // the first buffer.len() / values_per_sample() values of the buffer
+  // hold the duty values. spread them out into the layout the decoder
+  // expects.
+  pub fn spread(self, buffer: &mut [u16]) {
+    if self == SpeakerDrive::Single {
+      return;
+    }
+
+    // back to front, so no duty value is overwritten before it's moved
+    for i in (0..buffer.len() / 2).rev() {
+      let duty = buffer[i];
+      buffer[2 * i] = duty;
+      buffer[2 * i + 1] = duty | INVERTED;
+    }
+  }
+}